@@ -0,0 +1,230 @@
+use std::io::{Read, Write};
+
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use md5::{Digest, Md5};
+use num_bigint::BigUint;
+
+use super::{ClientAuthenticator, CredentialsVerifier, ServerAuthenticator, Verdict};
+use crate::error::Error;
+use crate::io::*;
+use crate::messages::{SecurityType, Version};
+
+const SECURITY_TYPE: SecurityType = SecurityType::AppleInc(30);
+const CREDENTIALS_LENGTH: usize = 128;
+
+/// 1024-bit MODP group from RFC 2409, used by [`ArdVerifier`].
+const PRIME: [u8; 128] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x0f, 0xda, 0xa2, 0x21, 0x68, 0xc2, 0x34,
+    0xc4, 0xc6, 0x62, 0x8b, 0x80, 0xdc, 0x1c, 0xd1, 0x29, 0x02, 0x4e, 0x08, 0x8a, 0x67, 0xcc, 0x74,
+    0x02, 0x0b, 0xbe, 0xa6, 0x3b, 0x13, 0x9b, 0x22, 0x51, 0x4a, 0x08, 0x79, 0x8e, 0x34, 0x04, 0xdd,
+    0xef, 0x95, 0x19, 0xb3, 0xcd, 0x3a, 0x43, 0x1b, 0x30, 0x2b, 0x0a, 0x6d, 0xf2, 0x5f, 0x14, 0x37,
+    0x4f, 0xe1, 0x35, 0x6d, 0x6d, 0x51, 0xc2, 0x45, 0xe4, 0x85, 0xb5, 0x76, 0x62, 0x5e, 0x7e, 0xc6,
+    0xf4, 0x4c, 0x42, 0xe9, 0xa6, 0x37, 0xed, 0x6b, 0x0b, 0xff, 0x5c, 0xb6, 0xf4, 0x06, 0xb7, 0xed,
+    0xee, 0x38, 0x6b, 0xfb, 0x5a, 0x89, 0x9f, 0xa5, 0xae, 0x9f, 0x24, 0x11, 0x7c, 0x4b, 0x1f, 0xe6,
+    0x49, 0x28, 0x66, 0x51, 0xec, 0xe6, 0x53, 0x81, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+
+/// Big-endian bytes of `n`, left-padded to `len`.
+fn to_bytes(n: &BigUint, len: usize) -> Vec<u8> {
+    let bytes = n.to_bytes_be();
+    let mut padded = vec![0; len.saturating_sub(bytes.len())];
+    padded.extend(bytes);
+    padded
+}
+
+fn random_below(prime: &BigUint, len: usize) -> BigUint {
+    let mut bytes = vec![0; len];
+    rand::Rng::fill(&mut rand::thread_rng(), &mut bytes[..]);
+    BigUint::from_bytes_be(&bytes) % prime
+}
+
+fn cipher(prime: &BigUint, public_key: &BigUint, private_key: &BigUint, len: usize) -> Aes128 {
+    let secret = public_key.modpow(private_key, prime);
+    let key = Md5::digest(to_bytes(&secret, len));
+    Aes128::new(&key)
+}
+
+/// Username and password as NUL-terminated strings in two 64-byte halves, the
+/// remaining bytes are random.
+fn credentials(username: &str, password: &str) -> Result<[u8; CREDENTIALS_LENGTH], Error> {
+    let mut buf = [0; CREDENTIALS_LENGTH];
+    rand::Rng::fill(&mut rand::thread_rng(), &mut buf[..]);
+    for (half, value) in buf.chunks_exact_mut(64).zip([username, password]) {
+        if value.len() >= 64 {
+            return Err(Error::LengthTooBig);
+        }
+        half[..value.len()].copy_from_slice(value.as_bytes());
+        half[value.len()] = 0;
+    }
+    Ok(buf)
+}
+
+fn parse_credentials(buf: &[u8; CREDENTIALS_LENGTH]) -> Result<(String, String), Error> {
+    let mut values = buf.chunks_exact(64).map(|half| {
+        let end = half.iter().position(|&b| b == 0).unwrap_or(half.len());
+        String::from_utf8(half[..end].to_vec())
+    });
+    Ok((values.next().unwrap()?, values.next().unwrap()?))
+}
+
+/// Apple Remote Desktop authentication (security type 30).
+pub struct ArdAuthenticator {
+    username: String,
+    password: String,
+}
+
+impl ArdAuthenticator {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+impl ClientAuthenticator for ArdAuthenticator {
+    fn security_type(&self) -> SecurityType {
+        SECURITY_TYPE
+    }
+
+    fn authenticate(
+        &mut self,
+        mut stream: Box<dyn Stream>,
+        _version: &Version,
+    ) -> Result<Box<dyn Stream>, Error> {
+        let generator = BigUint::from(u16::decode_from(&mut stream)?);
+        let len = u16::decode_from(&mut stream)? as usize;
+        if len == 0 {
+            return Err(Error::BadResponse);
+        }
+        let mut buf = vec![0; len * 2];
+        stream.read_exact(&mut buf)?;
+        let prime = BigUint::from_bytes_be(&buf[..len]);
+        let server_key = BigUint::from_bytes_be(&buf[len..]);
+        // Keys are computed modulo the prime, which must leave some room.
+        if prime <= BigUint::from(1u8) {
+            return Err(Error::BadResponse);
+        }
+
+        let private_key = random_below(&prime, len);
+        let public_key = generator.modpow(&private_key, &prime);
+        let cipher = cipher(&prime, &server_key, &private_key, len);
+
+        let mut credentials = credentials(&self.username, &self.password)?;
+        for block in credentials.chunks_exact_mut(16) {
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        }
+        stream.write_all(&credentials)?;
+        stream.write_all(&to_bytes(&public_key, len))?;
+        stream.flush()?;
+        Ok(stream)
+    }
+}
+
+/// Server side of Apple Remote Desktop authentication.
+pub struct ArdVerifier {
+    generator: u16,
+    prime: Vec<u8>,
+    verifier: CredentialsVerifier,
+}
+
+impl ArdVerifier {
+    pub fn new(verifier: impl FnMut(&str, &str) -> bool + Send + 'static) -> Self {
+        Self {
+            generator: 2,
+            prime: PRIME.to_vec(),
+            verifier: Box::new(verifier),
+        }
+    }
+}
+
+impl ServerAuthenticator for ArdVerifier {
+    fn security_type(&self) -> SecurityType {
+        SECURITY_TYPE
+    }
+
+    fn authenticate(
+        &mut self,
+        mut stream: Box<dyn Stream>,
+        _version: &Version,
+    ) -> Result<(Box<dyn Stream>, Verdict), Error> {
+        let len = self.prime.len();
+        let prime = BigUint::from_bytes_be(&self.prime);
+        let private_key = random_below(&prime, len);
+        let public_key = BigUint::from(self.generator).modpow(&private_key, &prime);
+
+        self.generator.encode_to(&mut stream)?;
+        u16::try_from(len)?.encode_to(&mut stream)?;
+        stream.write_all(&self.prime)?;
+        stream.write_all(&to_bytes(&public_key, len))?;
+        stream.flush()?;
+
+        let mut credentials = [0; CREDENTIALS_LENGTH];
+        stream.read_exact(&mut credentials)?;
+        let mut client_key = vec![0; len];
+        stream.read_exact(&mut client_key)?;
+        let client_key = BigUint::from_bytes_be(&client_key);
+
+        let cipher = cipher(&prime, &client_key, &private_key, len);
+        for block in credentials.chunks_exact_mut(16) {
+            cipher.decrypt_block(GenericArray::from_mut_slice(block));
+        }
+        let verdict = match parse_credentials(&credentials) {
+            Ok((username, password)) if (self.verifier)(&username, &password) => Verdict::Accepted,
+            _ => Verdict::Rejected("invalid credentials".to_string()),
+        };
+        Ok((stream, verdict))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::tests::connect;
+
+    #[test]
+    fn credentials_layout() {
+        let buf = credentials("user", "secret").unwrap();
+        assert_eq!(&buf[..5], b"user\0");
+        assert_eq!(&buf[64..71], b"secret\0");
+        assert_eq!(
+            parse_credentials(&buf).unwrap(),
+            ("user".to_string(), "secret".to_string())
+        );
+        assert!(credentials(&"x".repeat(64), "").is_err());
+    }
+
+    #[test]
+    fn padding() {
+        assert_eq!(to_bytes(&BigUint::from(0x0102u16), 4), [0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn invalid_keys() {
+        // Keys of no length, then a prime of 1.
+        for data in [vec![0, 2, 0, 0], vec![0, 2, 0, 1, 1, 5]] {
+            let stream: Box<dyn Stream> = Box::new(std::io::Cursor::new(data));
+            let mut authenticator = ArdAuthenticator::new("user", "secret");
+            assert!(matches!(
+                authenticator.authenticate(stream, &Version::Rfb38),
+                Err(Error::BadResponse)
+            ));
+        }
+    }
+
+    #[test]
+    fn diffie_hellman() {
+        for (password, accepted) in [("secret", true), ("wrong", false)] {
+            let (client, server) = connect(
+                Version::Rfb38,
+                vec![Box::new(ArdAuthenticator::new("user", password))],
+                vec![Box::new(ArdVerifier::new(|username, password| {
+                    username == "user" && password == "secret"
+                }))],
+            );
+            assert_eq!(client.is_ok(), accepted);
+            assert_eq!(server.is_ok(), accepted);
+        }
+    }
+}
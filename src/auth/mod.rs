@@ -0,0 +1,434 @@
+pub mod ard;
+pub mod rsa_aes;
+pub mod tight;
+pub mod tls;
+pub mod vencrypt;
+pub mod vnc;
+pub use ard::*;
+pub use rsa_aes::*;
+pub use tight::*;
+pub use tls::*;
+pub use vencrypt::*;
+pub use vnc::*;
+
+use crate::error::Error;
+use crate::io::*;
+use crate::messages::{SecurityResult, SecurityType, Version};
+
+/// Client half of a security type. Authenticators may wrap the stream they are
+/// given (e.g. in TLS), the returned stream is used for the rest of the session.
+pub trait ClientAuthenticator: Send {
+    fn security_type(&self) -> SecurityType;
+    fn authenticate(
+        &mut self,
+        stream: Box<dyn Stream>,
+        version: &Version,
+    ) -> Result<Box<dyn Stream>, Error>;
+    /// Whether the authenticator runs a nested security negotiation, which
+    /// then carries the SecurityResult.
+    fn nested(&self) -> bool {
+        false
+    }
+}
+
+/// Outcome of a server-side authentication, a rejection carries the reason
+/// sent to RFB 3.8 clients.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Verdict {
+    Accepted,
+    Rejected(String),
+}
+
+/// Checks a username and password on the server.
+pub type CredentialsVerifier = Box<dyn FnMut(&str, &str) -> bool + Send>;
+
+/// Server half of a security type.
+pub trait ServerAuthenticator: Send {
+    fn security_type(&self) -> SecurityType;
+    fn authenticate(
+        &mut self,
+        stream: Box<dyn Stream>,
+        version: &Version,
+    ) -> Result<(Box<dyn Stream>, Verdict), Error>;
+    /// Whether the authenticator runs a nested security negotiation, which
+    /// then carries the SecurityResult.
+    fn nested(&self) -> bool {
+        false
+    }
+}
+
+pub struct NoAuthentication;
+
+impl ClientAuthenticator for NoAuthentication {
+    fn security_type(&self) -> SecurityType {
+        SecurityType::None
+    }
+    fn authenticate(
+        &mut self,
+        stream: Box<dyn Stream>,
+        _version: &Version,
+    ) -> Result<Box<dyn Stream>, Error> {
+        Ok(stream)
+    }
+}
+
+impl ServerAuthenticator for NoAuthentication {
+    fn security_type(&self) -> SecurityType {
+        SecurityType::None
+    }
+    fn authenticate(
+        &mut self,
+        stream: Box<dyn Stream>,
+        _version: &Version,
+    ) -> Result<(Box<dyn Stream>, Verdict), Error> {
+        Ok((stream, Verdict::Accepted))
+    }
+}
+
+/// Runs the client side of the security handshake: picks the first of
+/// `authenticators` offered by the server, authenticates and reads the
+/// SecurityResult. Returns the stream to use and the negotiated type.
+pub fn negotiate_client(
+    mut stream: Box<dyn Stream>,
+    version: &Version,
+    authenticators: &mut [Box<dyn ClientAuthenticator>],
+) -> Result<(Box<dyn Stream>, SecurityType), Error> {
+    let authenticator = match version {
+        Version::Rfb33 => {
+            let security_type = u32::decode_from(&mut stream)?;
+            if security_type == 0 {
+                return Err(Error::ConnectionFailed(String::decode_from(&mut stream)?));
+            }
+            let security_type = u8::try_from(security_type)
+                .map(SecurityType::from)
+                .map_err(|_| Error::BadResponse)?;
+            authenticators
+                .iter_mut()
+                .find(|a| a.security_type() == security_type)
+                .ok_or(Error::IncompatibleSecurity)?
+        }
+        _ => {
+            let server_security_types = Vec::<SecurityType>::decode_from(&mut stream)?;
+            if server_security_types.is_empty() {
+                return Err(Error::ConnectionFailed(String::decode_from(&mut stream)?));
+            }
+            let authenticator = authenticators
+                .iter_mut()
+                .find(|a| server_security_types.contains(&a.security_type()))
+                .ok_or(Error::IncompatibleSecurity)?;
+            authenticator.security_type().encode_to(&mut stream)?;
+            stream.flush()?;
+            authenticator
+        }
+    };
+
+    let security_type = authenticator.security_type();
+    let stream = authenticator.authenticate(stream, version)?;
+    if authenticator.nested() {
+        return Ok((stream, security_type));
+    }
+    Ok((
+        read_security_result(stream, version, security_type)?,
+        security_type,
+    ))
+}
+
+/// Reads the SecurityResult ending an authentication with `security_type`,
+/// which RFB 3.3 and 3.7 servers omit for [`SecurityType::None`].
+pub fn read_security_result(
+    mut stream: Box<dyn Stream>,
+    version: &Version,
+    security_type: SecurityType,
+) -> Result<Box<dyn Stream>, Error> {
+    if security_type == SecurityType::None && *version != Version::Rfb38 {
+        return Ok(stream);
+    }
+    let reason = |stream: &mut Box<dyn Stream>| match version {
+        Version::Rfb38 => String::decode_from(stream).map(Some),
+        _ => Ok(None),
+    };
+    match SecurityResult::decode_from(&mut stream)? {
+        SecurityResult::Ok => Ok(stream),
+        SecurityResult::Failed => Err(Error::AuthenticationFailed(reason(&mut stream)?)),
+        SecurityResult::TooManyAttempts => Err(Error::TooManyAttempts(reason(&mut stream)?)),
+    }
+}
+
+/// Runs the server side of the security handshake: offers the types of
+/// `authenticators`, authenticates the client with the chosen one and sends
+/// the SecurityResult. Returns the stream to use and the negotiated type.
+pub fn negotiate_server(
+    mut stream: Box<dyn Stream>,
+    version: &Version,
+    authenticators: &mut [Box<dyn ServerAuthenticator>],
+) -> Result<(Box<dyn Stream>, SecurityType), Error> {
+    let authenticator = match version {
+        Version::Rfb33 => {
+            let Some(authenticator) = authenticators.first_mut() else {
+                0u32.encode_to(&mut stream)?;
+                return refuse(stream);
+            };
+            u32::from(u8::try_from(authenticator.security_type())?).encode_to(&mut stream)?;
+            stream.flush()?;
+            authenticator
+        }
+        _ => {
+            let security_types: Vec<SecurityType> =
+                authenticators.iter().map(|a| a.security_type()).collect();
+            if security_types.is_empty() {
+                0u8.encode_to(&mut stream)?;
+                return refuse(stream);
+            }
+            security_types.encode_to(&mut stream)?;
+            stream.flush()?;
+            let security_type = SecurityType::decode_from(&mut stream)?;
+            match authenticators
+                .iter_mut()
+                .find(|a| a.security_type() == security_type)
+            {
+                Some(authenticator) => authenticator,
+                None => {
+                    reject(stream, version, "unsupported security type")?;
+                    return Err(Error::IncompatibleSecurity);
+                }
+            }
+        }
+    };
+
+    let security_type = authenticator.security_type();
+    let (stream, verdict) = authenticator.authenticate(stream, version)?;
+    if authenticator.nested() {
+        return Ok((stream, security_type));
+    }
+    let stream = write_security_result(stream, version, security_type, verdict)?;
+    Ok((stream, security_type))
+}
+
+/// Sends the SecurityResult for `verdict`, see [`read_security_result`].
+pub fn write_security_result(
+    mut stream: Box<dyn Stream>,
+    version: &Version,
+    security_type: SecurityType,
+    verdict: Verdict,
+) -> Result<Box<dyn Stream>, Error> {
+    match verdict {
+        Verdict::Accepted => {
+            if security_type != SecurityType::None || *version == Version::Rfb38 {
+                SecurityResult::Ok.encode_to(&mut stream)?;
+                stream.flush()?;
+            }
+            Ok(stream)
+        }
+        Verdict::Rejected(reason) => {
+            reject(stream, version, &reason)?;
+            Err(Error::AuthenticationFailed(Some(reason)))
+        }
+    }
+}
+
+fn refuse<T>(mut stream: Box<dyn Stream>) -> Result<T, Error> {
+    let reason = "no security type available".to_string();
+    reason.clone().encode_to(&mut stream)?;
+    stream.flush()?;
+    Err(Error::ConnectionFailed(reason))
+}
+
+fn reject(mut stream: Box<dyn Stream>, version: &Version, reason: &str) -> Result<(), Error> {
+    SecurityResult::Failed.encode_to(&mut stream)?;
+    if *version == Version::Rfb38 {
+        reason.to_string().encode_to(&mut stream)?;
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::messages::*;
+    use crate::server::{Server, Sessions};
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509Name, X509};
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    pub(crate) fn certificate() -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "localhost")
+            .unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    pub(crate) fn server_init() -> ServerInit {
+        ServerInit {
+            framebuffer_width: 1,
+            framebuffer_height: 1,
+            pixel_format: PixelFormat {
+                bits_per_pixel: 32,
+                depth: 24,
+                big_endian_flag: false,
+                true_colour_flag: true,
+                red_max: 255,
+                green_max: 255,
+                blue_max: 255,
+                red_shift: 16,
+                green_shift: 8,
+                blue_shift: 0,
+            },
+            name: "rfb".to_string(),
+            interaction_capabilities: None,
+        }
+    }
+
+    /// Runs a minimal server on `stream` that answers one update request.
+    fn serve(
+        stream: UnixStream,
+        version: Version,
+        authenticators: Vec<Box<dyn ServerAuthenticator>>,
+    ) -> Result<(), Error> {
+        let mut server = Server::handshake(
+            stream,
+            version,
+            authenticators,
+            server_init(),
+            &Sessions::new(),
+        )?;
+        server.read_update_request()?;
+        server.send_update(FramebufferUpdate {
+            rectangles: vec![Rectangle {
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1,
+                encoding_type: EncodingType::Raw,
+                payload: Payload::Pixels(vec![1, 2, 3, 4]),
+            }],
+        })
+    }
+
+    /// Connects a client to [`serve`] and returns the pixels of the update it
+    /// received along with the server outcome.
+    pub(crate) fn connect(
+        version: Version,
+        client: Vec<Box<dyn ClientAuthenticator>>,
+        server: Vec<Box<dyn ServerAuthenticator>>,
+    ) -> (Result<Vec<u8>, Error>, Result<(), Error>) {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || serve(server_stream, version, server));
+        let client = Client::handshake(client_stream, client).and_then(|mut c| {
+            c.request_update(false, 0, 0, 1, 1)?;
+            match c.read_update()?.rectangles.remove(0).payload {
+                Payload::Pixels(pixels) => Ok(pixels),
+                _ => Err(Error::BadResponse),
+            }
+        });
+        (client, handle.join().unwrap())
+    }
+
+    fn run(
+        version: Version,
+        server_script: &[u8],
+        authenticators: &mut [Box<dyn ClientAuthenticator>],
+    ) -> (Result<(), Error>, Vec<u8>) {
+        let (mut server, client) = UnixStream::pair().unwrap();
+        server.write_all(server_script).unwrap();
+        let result = negotiate_client(Box::new(client), &version, authenticators).map(drop);
+        server.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut sent = Vec::new();
+        server.read_to_end(&mut sent).unwrap();
+        (result, sent)
+    }
+
+    #[test]
+    fn picks_preferred_type() {
+        let mut authenticators: Vec<Box<dyn ClientAuthenticator>> = vec![
+            Box::new(VncAuthenticator::new("secret")),
+            Box::new(NoAuthentication),
+        ];
+        let mut script = vec![2, 1, 2];
+        script.extend_from_slice(&[0; 16]);
+        script.extend_from_slice(&[0, 0, 0, 0]);
+        let (result, sent) = run(Version::Rfb38, &script, &mut authenticators);
+        assert!(result.is_ok());
+        assert_eq!(sent[0], 2);
+        assert_eq!(&sent[1..], encrypt_challenge("secret", [0; 16]));
+
+        let mut authenticators: Vec<Box<dyn ClientAuthenticator>> =
+            vec![Box::new(NoAuthentication)];
+        let (result, sent) = run(Version::Rfb37, &[2, 2, 1], &mut authenticators);
+        assert!(result.is_ok());
+        assert_eq!(sent, [1]);
+    }
+
+    #[test]
+    fn incompatible_security() {
+        let mut authenticators: Vec<Box<dyn ClientAuthenticator>> =
+            vec![Box::new(NoAuthentication)];
+        let (result, _) = run(Version::Rfb38, &[1, 2], &mut authenticators);
+        assert!(matches!(result, Err(Error::IncompatibleSecurity)));
+    }
+
+    #[test]
+    fn connection_failed_reasons() {
+        let mut authenticators: Vec<Box<dyn ClientAuthenticator>> =
+            vec![Box::new(NoAuthentication)];
+        let (result, _) = run(
+            Version::Rfb33,
+            b"\0\0\0\0\0\0\0\x04busy",
+            &mut authenticators,
+        );
+        assert!(matches!(result, Err(Error::ConnectionFailed(reason)) if reason == "busy"));
+
+        let (result, _) = run(Version::Rfb38, b"\0\0\0\0\x04busy", &mut authenticators);
+        assert!(matches!(result, Err(Error::ConnectionFailed(reason)) if reason == "busy"));
+    }
+
+    #[test]
+    fn security_result_reasons() {
+        let mut authenticators: Vec<Box<dyn ClientAuthenticator>> =
+            vec![Box::new(NoAuthentication)];
+        let (result, _) = run(
+            Version::Rfb38,
+            b"\x01\x01\0\0\0\x01\0\0\0\x06denied",
+            &mut authenticators,
+        );
+        assert!(
+            matches!(result, Err(Error::AuthenticationFailed(Some(reason))) if reason == "denied")
+        );
+
+        let mut authenticators: Vec<Box<dyn ClientAuthenticator>> =
+            vec![Box::new(VncAuthenticator::new("secret"))];
+        let mut script = vec![0, 0, 0, 2];
+        script.extend_from_slice(&[0; 16]);
+        script.extend_from_slice(&[0, 0, 0, 2]);
+        let (result, sent) = run(Version::Rfb33, &script, &mut authenticators);
+        assert!(matches!(result, Err(Error::TooManyAttempts(None))));
+        assert_eq!(sent, encrypt_challenge("secret", [0; 16]));
+    }
+}
@@ -0,0 +1,613 @@
+use std::io::{self, Read, Write};
+
+use aes::{Aes128, Aes256};
+use eax::aead::{generic_array::GenericArray, AeadInPlace, KeyInit};
+use eax::Eax;
+use openssl::bn::BigNum;
+use openssl::pkey::{Private, Public};
+use openssl::rsa::{Padding, Rsa};
+use openssl::sha::{Sha1, Sha256};
+
+use super::{ClientAuthenticator, CredentialsVerifier, ServerAuthenticator, Verdict};
+use crate::error::Error;
+use crate::io::*;
+use crate::messages::{SecurityType, Version};
+
+const MAX_MESSAGE_LENGTH: usize = 8192;
+const TAG_LENGTH: usize = 16;
+const KEY_BITS: u32 = 2048;
+
+const SUBTYPE_USER_PASSWORD: u8 = 1;
+const SUBTYPE_PASSWORD: u8 = 2;
+
+/// The RSA-AES security types, "ne" variants only encrypt the handshake.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RsaAesMode {
+    Ra2,
+    Ra2ne,
+    Ra256,
+    RaNe256,
+}
+
+impl RsaAesMode {
+    pub fn security_type(self) -> SecurityType {
+        match self {
+            Self::Ra2 => SecurityType::Ra2,
+            Self::Ra2ne => SecurityType::Ra2ne,
+            Self::Ra256 => SecurityType::Ra256,
+            Self::RaNe256 => SecurityType::RaNe256,
+        }
+    }
+
+    fn encrypts_session(self) -> bool {
+        matches!(self, Self::Ra2 | Self::Ra256)
+    }
+
+    fn random_length(self) -> usize {
+        match self {
+            Self::Ra2 | Self::Ra2ne => 16,
+            Self::Ra256 | Self::RaNe256 => 32,
+        }
+    }
+
+    fn hash(self, parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            Self::Ra2 | Self::Ra2ne => {
+                let mut hasher = Sha1::new();
+                parts.iter().for_each(|part| hasher.update(part));
+                hasher.finish().to_vec()
+            }
+            Self::Ra256 | Self::RaNe256 => {
+                let mut hasher = Sha256::new();
+                parts.iter().for_each(|part| hasher.update(part));
+                hasher.finish().to_vec()
+            }
+        }
+    }
+
+    fn cipher(self, first: &[u8], second: &[u8]) -> Cipher {
+        let key = self.hash(&[first, second]);
+        match self {
+            Self::Ra2 | Self::Ra2ne => {
+                Cipher::Aes128(Eax::new(GenericArray::from_slice(&key[..16])))
+            }
+            Self::Ra256 | Self::RaNe256 => Cipher::Aes256(Eax::new(GenericArray::from_slice(&key))),
+        }
+    }
+}
+
+enum Cipher {
+    Aes128(Eax<Aes128>),
+    Aes256(Eax<Aes256>),
+}
+
+impl Cipher {
+    fn encrypt(&self, nonce: &[u8; 16], header: &[u8], data: &mut [u8]) -> Vec<u8> {
+        let nonce = GenericArray::from_slice(nonce);
+        let tag = match self {
+            Self::Aes128(eax) => eax.encrypt_in_place_detached(nonce, header, data),
+            Self::Aes256(eax) => eax.encrypt_in_place_detached(nonce, header, data),
+        };
+        tag.expect("EAX messages are bounded").to_vec()
+    }
+
+    fn decrypt(&self, nonce: &[u8; 16], header: &[u8], data: &mut [u8], tag: &[u8]) -> bool {
+        let nonce = GenericArray::from_slice(nonce);
+        let tag = GenericArray::from_slice(tag);
+        match self {
+            Self::Aes128(eax) => eax.decrypt_in_place_detached(nonce, header, data, tag),
+            Self::Aes256(eax) => eax.decrypt_in_place_detached(nonce, header, data, tag),
+        }
+        .is_ok()
+    }
+}
+
+/// Increments the nonce as a 128-bit little-endian counter.
+fn increment(nonce: &mut [u8; 16]) {
+    for byte in nonce.iter_mut() {
+        *byte = byte.wrapping_add(1);
+        if *byte != 0 {
+            break;
+        }
+    }
+}
+
+/// Stream of AES-EAX messages: a 16-bit length (authenticated), the encrypted
+/// data and a 16-byte tag, each direction counting its own nonce.
+pub struct AesEaxStream<S> {
+    inner: S,
+    encryptor: Cipher,
+    decryptor: Cipher,
+    write_nonce: [u8; 16],
+    read_nonce: [u8; 16],
+    write_buffer: Vec<u8>,
+    read_buffer: Vec<u8>,
+    read_position: usize,
+}
+
+impl<S: Read + Write> AesEaxStream<S> {
+    fn new(inner: S, encryptor: Cipher, decryptor: Cipher) -> Self {
+        Self {
+            inner,
+            encryptor,
+            decryptor,
+            write_nonce: [0; 16],
+            read_nonce: [0; 16],
+            write_buffer: Vec::new(),
+            read_buffer: Vec::new(),
+            read_position: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn write_message(&mut self, len: usize) -> io::Result<()> {
+        let mut data: Vec<u8> = self.write_buffer.drain(..len).collect();
+        let header = (len as u16).to_be_bytes();
+        let tag = self
+            .encryptor
+            .encrypt(&self.write_nonce, &header, &mut data);
+        increment(&mut self.write_nonce);
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&data)?;
+        self.inner.write_all(&tag)
+    }
+
+    fn read_message(&mut self) -> io::Result<()> {
+        let mut header = [0; 2];
+        self.inner.read_exact(&mut header)?;
+        let len = u16::from_be_bytes(header) as usize;
+        let mut data = vec![0; len];
+        self.inner.read_exact(&mut data)?;
+        let mut tag = [0; TAG_LENGTH];
+        self.inner.read_exact(&mut tag)?;
+        if !self
+            .decryptor
+            .decrypt(&self.read_nonce, &header, &mut data, &tag)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "AES-EAX message authentication failed",
+            ));
+        }
+        increment(&mut self.read_nonce);
+        self.read_buffer = data;
+        self.read_position = 0;
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Read for AesEaxStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read_position == self.read_buffer.len() {
+            self.read_message()?;
+        }
+        let available = &self.read_buffer[self.read_position..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.read_position += len;
+        Ok(len)
+    }
+}
+
+impl<S: Read + Write> Write for AesEaxStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_buffer.extend_from_slice(buf);
+        while self.write_buffer.len() >= MAX_MESSAGE_LENGTH {
+            self.write_message(MAX_MESSAGE_LENGTH)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.write_buffer.is_empty() {
+            self.write_message(self.write_buffer.len())?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// An RSA public key as sent on the wire: its length in bits, then the
+/// modulus and the exponent padded to the key length in bytes.
+struct PublicKey {
+    bits: u32,
+    modulus: Vec<u8>,
+    exponent: Vec<u8>,
+}
+
+impl PublicKey {
+    fn from_rsa(key: &Rsa<Private>) -> Result<Self, Error> {
+        let len = key.size() as i32;
+        Ok(Self {
+            bits: key.n().num_bits() as u32,
+            modulus: key.n().to_vec_padded(len)?,
+            exponent: key.e().to_vec_padded(len)?,
+        })
+    }
+
+    fn to_rsa(&self) -> Result<Rsa<Public>, Error> {
+        Ok(Rsa::from_public_components(
+            BigNum::from_slice(&self.modulus)?,
+            BigNum::from_slice(&self.exponent)?,
+        )?)
+    }
+
+    fn hash_parts(&self) -> [Vec<u8>; 3] {
+        [
+            self.bits.to_be_bytes().to_vec(),
+            self.modulus.clone(),
+            self.exponent.clone(),
+        ]
+    }
+}
+
+impl<R: Read> DecodeFrom<R> for PublicKey {
+    type Error = Error;
+    fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
+        let bits = u32::decode_from(reader)?;
+        if !(1024..=8192).contains(&bits) {
+            return Err(Error::BadResponse);
+        }
+        let len = bits.div_ceil(8) as usize;
+        let mut modulus = vec![0; len];
+        reader.read_exact(&mut modulus)?;
+        let mut exponent = vec![0; len];
+        reader.read_exact(&mut exponent)?;
+        Ok(Self {
+            bits,
+            modulus,
+            exponent,
+        })
+    }
+}
+
+impl<W: Write> EncodeTo<W> for &PublicKey {
+    type Error = Error;
+    fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
+        self.bits.encode_to(writer)?;
+        writer.write_all(&self.modulus)?;
+        writer.write_all(&self.exponent)?;
+        Ok(4 + self.modulus.len() + self.exponent.len())
+    }
+}
+
+/// Sends a fresh random encrypted with the peer key.
+fn write_random(
+    stream: &mut Box<dyn Stream>,
+    mode: RsaAesMode,
+    peer: &PublicKey,
+) -> Result<Vec<u8>, Error> {
+    let mut random = vec![0; mode.random_length()];
+    rand::Rng::fill(&mut rand::thread_rng(), &mut random[..]);
+    let key = peer.to_rsa()?;
+    let mut encrypted = vec![0; key.size() as usize];
+    let len = key.public_encrypt(&random, &mut encrypted, Padding::PKCS1)?;
+    u16::try_from(len)?.encode_to(stream)?;
+    stream.write_all(&encrypted[..len])?;
+    stream.flush()?;
+    Ok(random)
+}
+
+fn read_random(
+    stream: &mut Box<dyn Stream>,
+    mode: RsaAesMode,
+    key: &Rsa<Private>,
+) -> Result<Vec<u8>, Error> {
+    let len = u16::decode_from(stream)? as usize;
+    if len != key.size() as usize {
+        return Err(Error::BadResponse);
+    }
+    let mut encrypted = vec![0; len];
+    stream.read_exact(&mut encrypted)?;
+    let mut random = vec![0; len];
+    let len = key.private_decrypt(&encrypted, &mut random, Padding::PKCS1)?;
+    if len != mode.random_length() {
+        return Err(Error::BadResponse);
+    }
+    random.truncate(len);
+    Ok(random)
+}
+
+fn key_hash(mode: RsaAesMode, first: &PublicKey, second: &PublicKey) -> Vec<u8> {
+    let [a, b, c] = first.hash_parts();
+    let [d, e, f] = second.hash_parts();
+    mode.hash(&[&a, &b, &c, &d, &e, &f])
+}
+
+/// Finishes the handshake on the encrypted stream: exchanges and checks the
+/// hashes of both public keys.
+fn exchange_hashes(
+    stream: &mut AesEaxStream<Box<dyn Stream>>,
+    mode: RsaAesMode,
+    own: &PublicKey,
+    peer: &PublicKey,
+) -> Result<(), Error> {
+    stream.write_all(&key_hash(mode, own, peer))?;
+    stream.flush()?;
+    let expected = key_hash(mode, peer, own);
+    let mut hash = vec![0; expected.len()];
+    stream.read_exact(&mut hash)?;
+    if hash != expected {
+        return Err(Error::AuthenticationFailed(Some(
+            "RSA-AES key hash mismatch".to_string(),
+        )));
+    }
+    Ok(())
+}
+
+fn finish(stream: AesEaxStream<Box<dyn Stream>>, mode: RsaAesMode) -> Box<dyn Stream> {
+    match mode.encrypts_session() {
+        true => Box::new(stream),
+        false => stream.into_inner(),
+    }
+}
+
+pub type KeyVerifier = Box<dyn FnMut(&[u8], &[u8]) -> bool + Send>;
+
+/// RSA-AES (RA2, RA2ne, RA256 and RAne256) as used by RealVNC and TigerVNC.
+pub struct RsaAesAuthenticator {
+    mode: RsaAesMode,
+    username: String,
+    password: String,
+    key_verifier: Option<KeyVerifier>,
+}
+
+impl RsaAesAuthenticator {
+    pub fn new(mode: RsaAesMode, username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            mode,
+            username: username.into(),
+            password: password.into(),
+            key_verifier: None,
+        }
+    }
+
+    /// Checks the modulus and exponent of the server key, e.g. against a known
+    /// fingerprint. Every key is trusted otherwise.
+    pub fn with_key_verifier(
+        mut self,
+        verifier: impl FnMut(&[u8], &[u8]) -> bool + Send + 'static,
+    ) -> Self {
+        self.key_verifier = Some(Box::new(verifier));
+        self
+    }
+}
+
+impl ClientAuthenticator for RsaAesAuthenticator {
+    fn security_type(&self) -> SecurityType {
+        self.mode.security_type()
+    }
+
+    fn authenticate(
+        &mut self,
+        mut stream: Box<dyn Stream>,
+        _version: &Version,
+    ) -> Result<Box<dyn Stream>, Error> {
+        let mode = self.mode;
+        let server_key = PublicKey::decode_from(&mut stream)?;
+        if let Some(verifier) = &mut self.key_verifier {
+            if !verifier(&server_key.modulus, &server_key.exponent) {
+                return Err(Error::AuthenticationFailed(Some(
+                    "untrusted server key".to_string(),
+                )));
+            }
+        }
+        let private_key = Rsa::generate(KEY_BITS)?;
+        let client_key = PublicKey::from_rsa(&private_key)?;
+        (&client_key).encode_to(&mut stream)?;
+        let client_random = write_random(&mut stream, mode, &server_key)?;
+        let server_random = read_random(&mut stream, mode, &private_key)?;
+
+        let mut stream = AesEaxStream::new(
+            stream,
+            mode.cipher(&server_random, &client_random),
+            mode.cipher(&client_random, &server_random),
+        );
+        exchange_hashes(&mut stream, mode, &client_key, &server_key)?;
+
+        let subtype = u8::decode_from(&mut stream)?;
+        let username = match subtype {
+            SUBTYPE_USER_PASSWORD => self.username.as_bytes(),
+            SUBTYPE_PASSWORD => &[],
+            _ => return Err(Error::BadResponse),
+        };
+        u8::try_from(username.len())?.encode_to(&mut stream)?;
+        stream.write_all(username)?;
+        u8::try_from(self.password.len())?.encode_to(&mut stream)?;
+        stream.write_all(self.password.as_bytes())?;
+        stream.flush()?;
+        Ok(finish(stream, mode))
+    }
+}
+
+/// Server side of RSA-AES.
+pub struct RsaAesVerifier {
+    mode: RsaAesMode,
+    key: Rsa<Private>,
+    password_only: bool,
+    verifier: CredentialsVerifier,
+}
+
+impl RsaAesVerifier {
+    /// Uses a freshly generated 2048-bit server key, `verifier` receives the
+    /// username and the password.
+    pub fn new(
+        mode: RsaAesMode,
+        verifier: impl FnMut(&str, &str) -> bool + Send + 'static,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            mode,
+            key: Rsa::generate(KEY_BITS)?,
+            password_only: false,
+            verifier: Box::new(verifier),
+        })
+    }
+
+    pub fn with_key(mut self, key: Rsa<Private>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Only asks clients for a password, `verifier` then gets an empty username.
+    pub fn password_only(mut self) -> Self {
+        self.password_only = true;
+        self
+    }
+}
+
+impl ServerAuthenticator for RsaAesVerifier {
+    fn security_type(&self) -> SecurityType {
+        self.mode.security_type()
+    }
+
+    fn authenticate(
+        &mut self,
+        mut stream: Box<dyn Stream>,
+        _version: &Version,
+    ) -> Result<(Box<dyn Stream>, Verdict), Error> {
+        let mode = self.mode;
+        let server_key = PublicKey::from_rsa(&self.key)?;
+        (&server_key).encode_to(&mut stream)?;
+        stream.flush()?;
+        let client_key = PublicKey::decode_from(&mut stream)?;
+        let server_random = write_random(&mut stream, mode, &client_key)?;
+        let client_random = read_random(&mut stream, mode, &self.key)?;
+
+        let mut stream = AesEaxStream::new(
+            stream,
+            mode.cipher(&client_random, &server_random),
+            mode.cipher(&server_random, &client_random),
+        );
+        exchange_hashes(&mut stream, mode, &server_key, &client_key)?;
+
+        match self.password_only {
+            true => SUBTYPE_PASSWORD,
+            false => SUBTYPE_USER_PASSWORD,
+        }
+        .encode_to(&mut stream)?;
+        stream.flush()?;
+        let mut credentials = Vec::with_capacity(2);
+        for _ in 0..2 {
+            let mut buf = vec![0; u8::decode_from(&mut stream)? as usize];
+            stream.read_exact(&mut buf)?;
+            credentials.push(String::from_utf8(buf)?);
+        }
+        let verdict = match (self.verifier)(&credentials[0], &credentials[1]) {
+            true => Verdict::Accepted,
+            false => Verdict::Rejected("invalid credentials".to_string()),
+        };
+        Ok((finish(stream, mode), verdict))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::tests::connect;
+    use std::os::unix::net::UnixStream;
+
+    fn pair(mode: RsaAesMode) -> (AesEaxStream<UnixStream>, AesEaxStream<UnixStream>) {
+        let (a, b) = UnixStream::pair().unwrap();
+        (
+            AesEaxStream::new(a, mode.cipher(b"a", b"b"), mode.cipher(b"b", b"a")),
+            AesEaxStream::new(b, mode.cipher(b"b", b"a"), mode.cipher(b"a", b"b")),
+        )
+    }
+
+    #[test]
+    fn nonce_counter() {
+        let mut nonce = [0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        increment(&mut nonce);
+        assert_eq!(nonce[..3], [0, 0, 1]);
+    }
+
+    #[test]
+    fn encrypted_messages() {
+        for mode in [RsaAesMode::Ra2, RsaAesMode::Ra256] {
+            let (mut a, mut b) = pair(mode);
+            let data: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+            let writer = std::thread::spawn(move || {
+                a.write_all(b"hello").unwrap();
+                a.flush().unwrap();
+                a.write_all(&data).unwrap();
+                a.flush().unwrap();
+                data
+            });
+            let mut hello = [0; 5];
+            b.read_exact(&mut hello).unwrap();
+            assert_eq!(&hello, b"hello");
+            let mut received = vec![0; 20000];
+            b.read_exact(&mut received).unwrap();
+            assert_eq!(received, writer.join().unwrap());
+            assert_eq!(b.read_nonce[0], 4);
+        }
+    }
+
+    #[test]
+    fn tampered_message() {
+        let (a, mut b) = pair(RsaAesMode::Ra2);
+        let mut raw = a.into_inner();
+        raw.write_all(&[0, 1, 0xaa]).unwrap();
+        raw.write_all(&[0; TAG_LENGTH]).unwrap();
+        let error = b.read(&mut [0; 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn all_modes() {
+        use RsaAesMode::*;
+        for mode in [Ra2, Ra2ne, Ra256, RaNe256] {
+            for (password, accepted) in [("secret", true), ("wrong", false)] {
+                let (client, server) = connect(
+                    Version::Rfb38,
+                    vec![Box::new(RsaAesAuthenticator::new(mode, "user", password))],
+                    vec![Box::new(
+                        RsaAesVerifier::new(mode, |username, password| {
+                            username == "user" && password == "secret"
+                        })
+                        .unwrap(),
+                    )],
+                );
+                assert_eq!(client.is_ok(), accepted, "{mode:?}");
+                assert_eq!(server.is_ok(), accepted, "{mode:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn password_only_and_key_verification() {
+        let server_key = Rsa::generate(KEY_BITS).unwrap();
+        let modulus = server_key.n().to_vec();
+        let (client, server) = connect(
+            Version::Rfb38,
+            vec![Box::new(
+                RsaAesAuthenticator::new(RsaAesMode::Ra2, "ignored", "secret")
+                    .with_key_verifier(move |n, _| n == modulus),
+            )],
+            vec![Box::new(
+                RsaAesVerifier::new(RsaAesMode::Ra2, |username, password| {
+                    username.is_empty() && password == "secret"
+                })
+                .unwrap()
+                .with_key(server_key)
+                .password_only(),
+            )],
+        );
+        assert_eq!(client.unwrap(), [1, 2, 3, 4]);
+        server.unwrap();
+
+        let (client, _) = connect(
+            Version::Rfb38,
+            vec![Box::new(
+                RsaAesAuthenticator::new(RsaAesMode::Ra2, "user", "secret")
+                    .with_key_verifier(|_, _| false),
+            )],
+            vec![Box::new(
+                RsaAesVerifier::new(RsaAesMode::Ra2, |_, _| true).unwrap(),
+            )],
+        );
+        assert!(matches!(client, Err(Error::AuthenticationFailed(Some(_)))));
+    }
+}
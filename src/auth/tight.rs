@@ -0,0 +1,355 @@
+use std::io::{Read, Write};
+
+use super::{
+    read_security_result, reject, write_security_result, ClientAuthenticator, CredentialsVerifier,
+    ServerAuthenticator, Verdict,
+};
+use crate::error::Error;
+use crate::io::*;
+use crate::messages::{Capability, SecurityType, Version};
+
+/// Longest login or password accepted from a client.
+const MAX_CREDENTIAL_LENGTH: usize = 1024;
+
+/// Authentication capability standing for a nested security type.
+fn capability(security_type: SecurityType) -> Option<Capability> {
+    match security_type {
+        SecurityType::None => Some(Capability::AUTH_NONE),
+        SecurityType::VncAuthentication => Some(Capability::AUTH_VNC),
+        _ => None,
+    }
+}
+
+/// Tight security (type 16): tunnel and authentication types are negotiated
+/// as capabilities, then one of `authenticators` (None or VNC Authentication)
+/// or Unix login runs. Tunnels are not supported.
+pub struct TightAuthenticator {
+    authenticators: Vec<Box<dyn ClientAuthenticator>>,
+    unix_login: Option<UnixLogin>,
+}
+
+impl TightAuthenticator {
+    pub fn new(authenticators: Vec<Box<dyn ClientAuthenticator>>) -> Self {
+        Self {
+            authenticators,
+            unix_login: None,
+        }
+    }
+
+    /// Offers Unix login, which only exists inside Tight security, after
+    /// `authenticators` in order of preference. The login and password are
+    /// sent in clear.
+    pub fn with_unix_login(
+        mut self,
+        login: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.unix_login = Some(UnixLogin {
+            login: login.into(),
+            password: password.into(),
+        });
+        self
+    }
+}
+
+impl ClientAuthenticator for TightAuthenticator {
+    fn security_type(&self) -> SecurityType {
+        SecurityType::Tight
+    }
+
+    fn authenticate(
+        &mut self,
+        mut stream: Box<dyn Stream>,
+        version: &Version,
+    ) -> Result<Box<dyn Stream>, Error> {
+        let tunnels = Vec::<Capability>::decode_from(&mut stream)?;
+        if !tunnels.is_empty() {
+            if !tunnels.contains(&Capability::NO_TUNNEL) {
+                return Err(Error::IncompatibleSecurity);
+            }
+            Capability::NO_TUNNEL.code.encode_to(&mut stream)?;
+            stream.flush()?;
+        }
+
+        // Servers send no authentication capability when none is required.
+        let offered = Vec::<Capability>::decode_from(&mut stream)?;
+        let authenticator = if offered.is_empty() {
+            self.authenticators
+                .iter_mut()
+                .find(|a| a.security_type() == SecurityType::None)
+        } else {
+            self.authenticators
+                .iter_mut()
+                .find(|a| capability(a.security_type()).is_some_and(|c| offered.contains(&c)))
+        };
+        let Some(authenticator) = authenticator else {
+            let Some(unix_login) = &self.unix_login else {
+                return Err(Error::IncompatibleSecurity);
+            };
+            if !offered.contains(&Capability::AUTH_UNIX_LOGIN) {
+                return Err(Error::IncompatibleSecurity);
+            }
+            Capability::AUTH_UNIX_LOGIN.code.encode_to(&mut stream)?;
+            let stream = unix_login.send(stream)?;
+            return read_security_result(stream, version, SecurityType::Tight);
+        };
+        if !offered.is_empty() {
+            let code = capability(authenticator.security_type()).unwrap().code;
+            code.encode_to(&mut stream)?;
+            stream.flush()?;
+        }
+
+        let security_type = authenticator.security_type();
+        let stream = authenticator.authenticate(stream, version)?;
+        read_security_result(stream, version, security_type)
+    }
+
+    fn nested(&self) -> bool {
+        true
+    }
+}
+
+pub struct TightVerifier {
+    authenticators: Vec<Box<dyn ServerAuthenticator>>,
+    unix_login: Option<CredentialsVerifier>,
+}
+
+impl TightVerifier {
+    /// Authenticators of other types than None and VNC Authentication are
+    /// not offered.
+    pub fn new(authenticators: Vec<Box<dyn ServerAuthenticator>>) -> Self {
+        Self {
+            authenticators,
+            unix_login: None,
+        }
+    }
+
+    /// Offers Unix login after `authenticators`, checking the login and
+    /// password with `verifier`.
+    pub fn with_unix_login(
+        mut self,
+        verifier: impl FnMut(&str, &str) -> bool + Send + 'static,
+    ) -> Self {
+        self.unix_login = Some(Box::new(verifier));
+        self
+    }
+}
+
+impl ServerAuthenticator for TightVerifier {
+    fn security_type(&self) -> SecurityType {
+        SecurityType::Tight
+    }
+
+    fn authenticate(
+        &mut self,
+        mut stream: Box<dyn Stream>,
+        version: &Version,
+    ) -> Result<(Box<dyn Stream>, Verdict), Error> {
+        let mut offered: Vec<Capability> = self
+            .authenticators
+            .iter()
+            .filter_map(|a| capability(a.security_type()))
+            .collect();
+        if self.unix_login.is_some() {
+            offered.push(Capability::AUTH_UNIX_LOGIN);
+        }
+        // An empty list would let the client in without authentication.
+        if offered.is_empty() {
+            return Err(Error::IncompatibleSecurity);
+        }
+        Vec::<Capability>::new().encode_to(&mut stream)?;
+        offered.encode_to(&mut stream)?;
+        stream.flush()?;
+
+        let code = u32::decode_from(&mut stream)?;
+        let authenticator = self
+            .authenticators
+            .iter_mut()
+            .find(|a| capability(a.security_type()).is_some_and(|c| c.code == code));
+        let (stream, security_type, verdict) = match (authenticator, &mut self.unix_login) {
+            (Some(authenticator), _) => {
+                let security_type = authenticator.security_type();
+                let (stream, verdict) = authenticator.authenticate(stream, version)?;
+                (stream, security_type, verdict)
+            }
+            (None, Some(verifier)) if code == Capability::AUTH_UNIX_LOGIN.code => {
+                let (stream, verdict) = verify_unix_login(stream, verifier)?;
+                (stream, SecurityType::Tight, verdict)
+            }
+            _ => {
+                reject(stream, version, "unsupported authentication type")?;
+                return Err(Error::IncompatibleSecurity);
+            }
+        };
+        let stream = write_security_result(stream, version, security_type, verdict)?;
+        Ok((stream, Verdict::Accepted))
+    }
+
+    fn nested(&self) -> bool {
+        true
+    }
+}
+
+/// Credentials of Unix login, an authentication of the Tight security type
+/// only.
+struct UnixLogin {
+    login: String,
+    password: String,
+}
+
+impl UnixLogin {
+    fn send(&self, mut stream: Box<dyn Stream>) -> Result<Box<dyn Stream>, Error> {
+        let login: u32 = self.login.len().try_into()?;
+        let password: u32 = self.password.len().try_into()?;
+        login.encode_to(&mut stream)?;
+        password.encode_to(&mut stream)?;
+        stream.write_all(self.login.as_bytes())?;
+        stream.write_all(self.password.as_bytes())?;
+        stream.flush()?;
+        Ok(stream)
+    }
+}
+
+fn verify_unix_login(
+    mut stream: Box<dyn Stream>,
+    verifier: &mut CredentialsVerifier,
+) -> Result<(Box<dyn Stream>, Verdict), Error> {
+    let login = u32::decode_from(&mut stream)? as usize;
+    let password = u32::decode_from(&mut stream)? as usize;
+    if login > MAX_CREDENTIAL_LENGTH || password > MAX_CREDENTIAL_LENGTH {
+        return Err(Error::BadResponse);
+    }
+    let mut buf = vec![0; login + password];
+    stream.read_exact(&mut buf)?;
+    let password = String::from_utf8(buf.split_off(login))?;
+    let login = String::from_utf8(buf)?;
+    let verdict = match verifier(&login, &password) {
+        true => Verdict::Accepted,
+        false => Verdict::Rejected("invalid credentials".to_string()),
+    };
+    Ok((stream, verdict))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::tests::connect;
+    use crate::auth::{negotiate_client, NoAuthentication, VncAuthVerifier, VncAuthenticator};
+    use crate::messages::{InteractionCapabilities, TIGHT_VENDOR};
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn capability_codec() {
+        let capability = Capability::new(0x01020304, TIGHT_VENDOR, *b"SIGNATUR");
+        let mut buf = Vec::new();
+        vec![capability].encode_to(&mut buf).unwrap();
+        assert_eq!(&buf[..8], [0, 0, 0, 1, 1, 2, 3, 4]);
+        assert_eq!(&buf[8..], b"TGHTSIGNATUR");
+        assert_eq!(
+            Vec::<Capability>::decode_from(&mut &buf[..]).unwrap(),
+            [capability]
+        );
+
+        let capabilities = InteractionCapabilities {
+            server_messages: vec![],
+            client_messages: vec![capability],
+            encodings: vec![Capability::AUTH_VNC, capability],
+        };
+        let mut buf = Vec::new();
+        assert_eq!(capabilities.clone().encode_to(&mut buf).unwrap(), 56);
+        assert_eq!(&buf[..8], [0, 0, 0, 1, 0, 2, 0, 0]);
+        assert_eq!(
+            InteractionCapabilities::decode_from(&mut &buf[..]).unwrap(),
+            capabilities
+        );
+    }
+
+    #[test]
+    fn selects_tunnel_and_authentication() {
+        let (mut server, client) = UnixStream::pair().unwrap();
+        let mut script = vec![1, 16];
+        vec![Capability::NO_TUNNEL].encode_to(&mut script).unwrap();
+        vec![Capability::AUTH_VNC, Capability::AUTH_NONE]
+            .encode_to(&mut script)
+            .unwrap();
+        script.extend_from_slice(&[0; 4]);
+        server.write_all(&script).unwrap();
+
+        let mut authenticators: Vec<Box<dyn ClientAuthenticator>> = vec![Box::new(
+            TightAuthenticator::new(vec![Box::new(NoAuthentication)])
+                .with_unix_login("user", "secret"),
+        )];
+        let (_, security_type) =
+            negotiate_client(Box::new(client), &Version::Rfb38, &mut authenticators).unwrap();
+        assert_eq!(security_type, SecurityType::Tight);
+
+        server.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut sent = Vec::new();
+        server.read_to_end(&mut sent).unwrap();
+        assert_eq!(sent, [16, 0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn nested_authentication() {
+        for version in [Version::Rfb37, Version::Rfb38] {
+            let (client, server) = connect(
+                version.clone(),
+                vec![Box::new(TightAuthenticator::new(vec![Box::new(
+                    NoAuthentication,
+                )]))],
+                vec![Box::new(TightVerifier::new(vec![Box::new(
+                    NoAuthentication,
+                )]))],
+            );
+            assert_eq!(client.unwrap(), [1, 2, 3, 4]);
+            server.unwrap();
+
+            // VNC Authentication is preferred, Unix login used when it is the
+            // only one offered.
+            let verifiers: [Vec<Box<dyn ServerAuthenticator>>; 2] =
+                [vec![Box::new(VncAuthVerifier::new("secret"))], Vec::new()];
+            for verifiers in verifiers {
+                let (client, server) = connect(
+                    version.clone(),
+                    vec![Box::new(
+                        TightAuthenticator::new(vec![Box::new(VncAuthenticator::new("secret"))])
+                            .with_unix_login("user", "secret"),
+                    )],
+                    vec![Box::new(TightVerifier::new(verifiers).with_unix_login(
+                        |login, password| login == "user" && password == "secret",
+                    ))],
+                );
+                assert_eq!(client.unwrap(), [1, 2, 3, 4]);
+                server.unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn rejected_login() {
+        let (client, server) = connect(
+            Version::Rfb38,
+            vec![Box::new(
+                TightAuthenticator::new(Vec::new()).with_unix_login("user", "wrong"),
+            )],
+            vec![Box::new(
+                TightVerifier::new(Vec::new()).with_unix_login(|_, password| password == "secret"),
+            )],
+        );
+        assert!(
+            matches!(client, Err(Error::AuthenticationFailed(Some(reason))) if reason == "invalid credentials")
+        );
+        assert!(matches!(server, Err(Error::AuthenticationFailed(_))));
+    }
+
+    #[test]
+    fn oversized_credentials() {
+        let data = [0, 0, 0, 4, 0xff, 0xff, 0xff, 0xff];
+        let stream: Box<dyn Stream> = Box::new(std::io::Cursor::new(data.to_vec()));
+        let mut verifier: CredentialsVerifier = Box::new(|_, _| true);
+        assert!(matches!(
+            verify_unix_login(stream, &mut verifier),
+            Err(Error::BadResponse)
+        ));
+    }
+}
@@ -0,0 +1,201 @@
+use openssl::dh::Dh;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{Ssl, SslContext, SslMethod, SslVerifyMode, SslVersion};
+use openssl::x509::X509;
+
+use super::{
+    negotiate_client, negotiate_server, ClientAuthenticator, ServerAuthenticator, Verdict,
+};
+use crate::error::Error;
+use crate::io::Stream;
+use crate::messages::{SecurityType, Version};
+
+// Anonymous Diffie-Hellman suites only exist up to TLS 1.2 and are below every
+// OpenSSL security level but 0.
+const ANONYMOUS_CIPHERS: &str = "aNULL:!eNULL:@SECLEVEL=0";
+
+/// Certificate checks applied by the client when the server presents one.
+#[derive(Clone, Default)]
+pub struct X509Verification {
+    /// Trust anchors, the system store is used when empty.
+    pub ca_certificates: Vec<X509>,
+    /// Name the server certificate must be issued for.
+    pub domain: Option<String>,
+}
+
+pub fn connect_anonymous(stream: Box<dyn Stream>) -> Result<Box<dyn Stream>, Error> {
+    let mut context = SslContext::builder(SslMethod::tls_client())?;
+    context.set_cipher_list(ANONYMOUS_CIPHERS)?;
+    context.set_max_proto_version(Some(SslVersion::TLS1_2))?;
+    context.set_verify(SslVerifyMode::NONE);
+    let ssl = Ssl::new(&context.build())?;
+    Ok(Box::new(ssl.connect(stream)?))
+}
+
+pub fn accept_anonymous(stream: Box<dyn Stream>) -> Result<Box<dyn Stream>, Error> {
+    let mut context = SslContext::builder(SslMethod::tls_server())?;
+    context.set_cipher_list(ANONYMOUS_CIPHERS)?;
+    context.set_max_proto_version(Some(SslVersion::TLS1_2))?;
+    let dh = Dh::get_2048_256()?;
+    context.set_tmp_dh(&dh)?;
+    let ssl = Ssl::new(&context.build())?;
+    Ok(Box::new(ssl.accept(stream)?))
+}
+
+pub fn connect_x509(
+    stream: Box<dyn Stream>,
+    verification: &X509Verification,
+) -> Result<Box<dyn Stream>, Error> {
+    let mut context = SslContext::builder(SslMethod::tls_client())?;
+    context.set_verify(SslVerifyMode::PEER);
+    if verification.ca_certificates.is_empty() {
+        context.set_default_verify_paths()?;
+    }
+    for certificate in &verification.ca_certificates {
+        context.cert_store_mut().add_cert(certificate.clone())?;
+    }
+    let mut ssl = Ssl::new(&context.build())?;
+    if let Some(domain) = &verification.domain {
+        ssl.set_hostname(domain)?;
+        ssl.param_mut().set_host(domain)?;
+    }
+    Ok(Box::new(ssl.connect(stream)?))
+}
+
+pub fn accept_x509(
+    stream: Box<dyn Stream>,
+    certificate: &X509,
+    key: &PKey<Private>,
+) -> Result<Box<dyn Stream>, Error> {
+    let mut context = SslContext::builder(SslMethod::tls_server())?;
+    context.set_certificate(certificate)?;
+    context.set_private_key(key)?;
+    context.check_private_key()?;
+    let ssl = Ssl::new(&context.build())?;
+    Ok(Box::new(ssl.accept(stream)?))
+}
+
+// The nested negotiation always uses the security type list of RFB 3.7.
+fn nested_version(version: &Version) -> &Version {
+    match version {
+        Version::Rfb33 => &Version::Rfb37,
+        version => version,
+    }
+}
+
+/// Anonymous TLS (security type 18): the session is encrypted and a second
+/// security negotiation with `authenticators` runs inside it.
+pub struct TlsAuthenticator {
+    authenticators: Vec<Box<dyn ClientAuthenticator>>,
+}
+
+impl TlsAuthenticator {
+    pub fn new(authenticators: Vec<Box<dyn ClientAuthenticator>>) -> Self {
+        Self { authenticators }
+    }
+}
+
+impl ClientAuthenticator for TlsAuthenticator {
+    fn security_type(&self) -> SecurityType {
+        SecurityType::Tls
+    }
+    fn authenticate(
+        &mut self,
+        stream: Box<dyn Stream>,
+        version: &Version,
+    ) -> Result<Box<dyn Stream>, Error> {
+        let stream = connect_anonymous(stream)?;
+        let (stream, _) =
+            negotiate_client(stream, nested_version(version), &mut self.authenticators)?;
+        Ok(stream)
+    }
+    fn nested(&self) -> bool {
+        true
+    }
+}
+
+pub struct TlsVerifier {
+    authenticators: Vec<Box<dyn ServerAuthenticator>>,
+}
+
+impl TlsVerifier {
+    pub fn new(authenticators: Vec<Box<dyn ServerAuthenticator>>) -> Self {
+        Self { authenticators }
+    }
+}
+
+impl ServerAuthenticator for TlsVerifier {
+    fn security_type(&self) -> SecurityType {
+        SecurityType::Tls
+    }
+    fn authenticate(
+        &mut self,
+        stream: Box<dyn Stream>,
+        version: &Version,
+    ) -> Result<(Box<dyn Stream>, Verdict), Error> {
+        let stream = accept_anonymous(stream)?;
+        let (stream, _) =
+            negotiate_server(stream, nested_version(version), &mut self.authenticators)?;
+        Ok((stream, Verdict::Accepted))
+    }
+    fn nested(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::tests::connect;
+    use crate::auth::{NoAuthentication, VncAuthVerifier, VncAuthenticator};
+
+    #[test]
+    fn nested_negotiation() {
+        for version in [Version::Rfb37, Version::Rfb38] {
+            let (client, server) = connect(
+                version.clone(),
+                vec![Box::new(TlsAuthenticator::new(vec![Box::new(
+                    NoAuthentication,
+                )]))],
+                vec![Box::new(TlsVerifier::new(vec![
+                    Box::new(VncAuthVerifier::new("secret")),
+                    Box::new(NoAuthentication),
+                ]))],
+            );
+            assert_eq!(client.unwrap(), [1, 2, 3, 4]);
+            server.unwrap();
+
+            let (client, server) = connect(
+                version,
+                vec![
+                    Box::new(VncAuthenticator::new("secret")),
+                    Box::new(TlsAuthenticator::new(vec![Box::new(
+                        VncAuthenticator::new("secret"),
+                    )])),
+                ],
+                vec![Box::new(TlsVerifier::new(vec![Box::new(
+                    VncAuthVerifier::new("secret"),
+                )]))],
+            );
+            assert_eq!(client.unwrap(), [1, 2, 3, 4]);
+            server.unwrap();
+        }
+    }
+
+    #[test]
+    fn nested_rejection() {
+        let (client, server) = connect(
+            Version::Rfb38,
+            vec![Box::new(TlsAuthenticator::new(vec![Box::new(
+                VncAuthenticator::new("wrong"),
+            )]))],
+            vec![Box::new(TlsVerifier::new(vec![Box::new(
+                VncAuthVerifier::new("secret"),
+            )]))],
+        );
+        assert!(
+            matches!(client, Err(Error::AuthenticationFailed(Some(reason))) if reason == "invalid password")
+        );
+        assert!(matches!(server, Err(Error::AuthenticationFailed(_))));
+    }
+}
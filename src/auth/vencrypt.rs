@@ -0,0 +1,391 @@
+use std::io::{Read, Write};
+
+use openssl::pkey::{PKey, Private};
+use openssl::x509::X509;
+
+use super::tls::{self, X509Verification};
+use super::{
+    vnc, ClientAuthenticator, CredentialsVerifier, ServerAuthenticator, Verdict, VncAuthVerifier,
+};
+use crate::error::Error;
+use crate::io::*;
+use crate::messages::{SecurityType, Version};
+
+const VERSION: [u8; 2] = [0, 2];
+/// Longest username or password accepted from a client.
+const MAX_CREDENTIAL_LENGTH: usize = 1024;
+
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum VeNCryptSubtype {
+    Plain = 256,
+    TlsNone,
+    TlsVnc,
+    TlsPlain,
+    X509None,
+    X509Vnc,
+    X509Plain,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    Clear,
+    Anonymous,
+    X509,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Credentials {
+    None,
+    Vnc,
+    Plain,
+}
+
+impl VeNCryptSubtype {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        use VeNCryptSubtype::*;
+        Some(match value {
+            256 => Plain,
+            257 => TlsNone,
+            258 => TlsVnc,
+            259 => TlsPlain,
+            260 => X509None,
+            261 => X509Vnc,
+            262 => X509Plain,
+            _ => return None,
+        })
+    }
+
+    fn transport(self) -> Transport {
+        use VeNCryptSubtype::*;
+        match self {
+            Plain => Transport::Clear,
+            TlsNone | TlsVnc | TlsPlain => Transport::Anonymous,
+            X509None | X509Vnc | X509Plain => Transport::X509,
+        }
+    }
+
+    fn credentials(self) -> Credentials {
+        use VeNCryptSubtype::*;
+        match self {
+            TlsNone | X509None => Credentials::None,
+            TlsVnc | X509Vnc => Credentials::Vnc,
+            Plain | TlsPlain | X509Plain => Credentials::Plain,
+        }
+    }
+}
+
+pub struct VeNCryptAuthenticator {
+    subtypes: Vec<VeNCryptSubtype>,
+    username: String,
+    password: String,
+    verification: X509Verification,
+}
+
+impl VeNCryptAuthenticator {
+    /// `subtypes` are tried in order of preference.
+    pub fn new(subtypes: Vec<VeNCryptSubtype>) -> Self {
+        Self {
+            subtypes,
+            username: String::new(),
+            password: String::new(),
+            verification: X509Verification::default(),
+        }
+    }
+
+    pub fn with_credentials(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.username = username.into();
+        self.password = password.into();
+        self
+    }
+
+    pub fn with_verification(mut self, verification: X509Verification) -> Self {
+        self.verification = verification;
+        self
+    }
+}
+
+impl ClientAuthenticator for VeNCryptAuthenticator {
+    fn security_type(&self) -> SecurityType {
+        SecurityType::VeNCrypt
+    }
+
+    fn authenticate(
+        &mut self,
+        mut stream: Box<dyn Stream>,
+        _version: &Version,
+    ) -> Result<Box<dyn Stream>, Error> {
+        let version = <[u8; 2]>::decode_from(&mut stream)?;
+        if version < VERSION {
+            return Err(Error::IncompatibleSecurity);
+        }
+        VERSION.encode_to(&mut stream)?;
+        stream.flush()?;
+        if u8::decode_from(&mut stream)? != 0 {
+            return Err(Error::IncompatibleSecurity);
+        }
+
+        let len = u8::decode_from(&mut stream)?;
+        let mut offered = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            offered.extend(VeNCryptSubtype::from_u32(u32::decode_from(&mut stream)?));
+        }
+        let subtype = *self
+            .subtypes
+            .iter()
+            .find(|s| offered.contains(s))
+            .ok_or(Error::IncompatibleSecurity)?;
+        (subtype as u32).encode_to(&mut stream)?;
+        stream.flush()?;
+
+        let mut stream = match subtype.transport() {
+            Transport::Clear => stream,
+            transport => {
+                if u8::decode_from(&mut stream)? != 1 {
+                    return Err(Error::IncompatibleSecurity);
+                }
+                match transport {
+                    Transport::Anonymous => tls::connect_anonymous(stream)?,
+                    _ => tls::connect_x509(stream, &self.verification)?,
+                }
+            }
+        };
+
+        match subtype.credentials() {
+            Credentials::None => {}
+            Credentials::Vnc => vnc::authenticate(&mut stream, &self.password)?,
+            Credentials::Plain => {
+                let username: u32 = self.username.len().try_into()?;
+                let password: u32 = self.password.len().try_into()?;
+                username.encode_to(&mut stream)?;
+                password.encode_to(&mut stream)?;
+                stream.write_all(self.username.as_bytes())?;
+                stream.write_all(self.password.as_bytes())?;
+                stream.flush()?;
+            }
+        }
+        Ok(stream)
+    }
+}
+
+pub struct VeNCryptVerifier {
+    subtypes: Vec<VeNCryptSubtype>,
+    identity: Option<(X509, PKey<Private>)>,
+    vnc: Option<VncAuthVerifier>,
+    plain: Option<CredentialsVerifier>,
+}
+
+impl VeNCryptVerifier {
+    /// Only the subtypes whose certificate or credentials are configured are
+    /// offered to clients.
+    pub fn new(subtypes: Vec<VeNCryptSubtype>) -> Self {
+        Self {
+            subtypes,
+            identity: None,
+            vnc: None,
+            plain: None,
+        }
+    }
+
+    pub fn with_identity(mut self, certificate: X509, key: PKey<Private>) -> Self {
+        self.identity = Some((certificate, key));
+        self
+    }
+
+    pub fn with_vnc_password(mut self, password: impl Into<String>) -> Self {
+        self.vnc = Some(VncAuthVerifier::new(password));
+        self
+    }
+
+    pub fn with_plain(mut self, verifier: impl FnMut(&str, &str) -> bool + Send + 'static) -> Self {
+        self.plain = Some(Box::new(verifier));
+        self
+    }
+
+    fn offered(&self) -> Vec<VeNCryptSubtype> {
+        self.subtypes
+            .iter()
+            .copied()
+            .filter(|s| s.transport() != Transport::X509 || self.identity.is_some())
+            .filter(|s| match s.credentials() {
+                Credentials::None => true,
+                Credentials::Vnc => self.vnc.is_some(),
+                Credentials::Plain => self.plain.is_some(),
+            })
+            .collect()
+    }
+}
+
+impl ServerAuthenticator for VeNCryptVerifier {
+    fn security_type(&self) -> SecurityType {
+        SecurityType::VeNCrypt
+    }
+
+    fn authenticate(
+        &mut self,
+        mut stream: Box<dyn Stream>,
+        _version: &Version,
+    ) -> Result<(Box<dyn Stream>, Verdict), Error> {
+        VERSION.encode_to(&mut stream)?;
+        stream.flush()?;
+        if <[u8; 2]>::decode_from(&mut stream)? != VERSION {
+            255u8.encode_to(&mut stream)?;
+            stream.flush()?;
+            let reason = "unsupported VeNCrypt version".to_string();
+            return Ok((stream, Verdict::Rejected(reason)));
+        }
+        0u8.encode_to(&mut stream)?;
+
+        let offered = self.offered();
+        let len: u8 = offered.len().try_into()?;
+        len.encode_to(&mut stream)?;
+        for subtype in &offered {
+            (*subtype as u32).encode_to(&mut stream)?;
+        }
+        stream.flush()?;
+
+        let subtype = match VeNCryptSubtype::from_u32(u32::decode_from(&mut stream)?) {
+            Some(subtype) if offered.contains(&subtype) => subtype,
+            _ => {
+                let reason = "unsupported VeNCrypt subtype".to_string();
+                return Ok((stream, Verdict::Rejected(reason)));
+            }
+        };
+
+        let mut stream = match subtype.transport() {
+            Transport::Clear => stream,
+            transport => {
+                1u8.encode_to(&mut stream)?;
+                stream.flush()?;
+                match (transport, &self.identity) {
+                    (Transport::X509, Some((certificate, key))) => {
+                        tls::accept_x509(stream, certificate, key)?
+                    }
+                    _ => tls::accept_anonymous(stream)?,
+                }
+            }
+        };
+
+        let accepted = match subtype.credentials() {
+            Credentials::None => true,
+            Credentials::Vnc => match &self.vnc {
+                Some(verifier) => verifier.authenticate(&mut stream)?,
+                None => false,
+            },
+            Credentials::Plain => {
+                let username = u32::decode_from(&mut stream)? as usize;
+                let password = u32::decode_from(&mut stream)? as usize;
+                if username > MAX_CREDENTIAL_LENGTH || password > MAX_CREDENTIAL_LENGTH {
+                    return Err(Error::BadResponse);
+                }
+                let mut buf = vec![0; username + password];
+                stream.read_exact(&mut buf)?;
+                let password = String::from_utf8(buf.split_off(username))?;
+                let username = String::from_utf8(buf)?;
+                match &mut self.plain {
+                    Some(verifier) => verifier(&username, &password),
+                    None => false,
+                }
+            }
+        };
+        let verdict = match accepted {
+            true => Verdict::Accepted,
+            false => Verdict::Rejected("invalid credentials".to_string()),
+        };
+        Ok((stream, verdict))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::tests::certificate;
+
+    fn connect(
+        client: VeNCryptAuthenticator,
+        server: VeNCryptVerifier,
+    ) -> (Result<Vec<u8>, Error>, Result<(), Error>) {
+        crate::auth::tests::connect(
+            Version::Rfb38,
+            vec![Box::new(client)],
+            vec![Box::new(server)],
+        )
+    }
+
+    fn verifier(subtype: VeNCryptSubtype) -> VeNCryptVerifier {
+        let (certificate, key) = certificate();
+        VeNCryptVerifier::new(vec![subtype])
+            .with_identity(certificate, key)
+            .with_vnc_password("secret")
+            .with_plain(|username, password| username == "user" && password == "secret")
+    }
+
+    #[test]
+    fn all_subtypes() {
+        use VeNCryptSubtype::*;
+        for subtype in [
+            Plain, TlsNone, TlsVnc, TlsPlain, X509None, X509Vnc, X509Plain,
+        ] {
+            let server = verifier(subtype);
+            let certificate = server.identity.as_ref().unwrap().0.clone();
+            let client = VeNCryptAuthenticator::new(vec![subtype])
+                .with_credentials("user", "secret")
+                .with_verification(X509Verification {
+                    ca_certificates: vec![certificate],
+                    domain: Some("localhost".to_string()),
+                });
+            let (client, server) = connect(client, server);
+            assert_eq!(client.unwrap(), [1, 2, 3, 4], "{subtype:?}");
+            server.unwrap();
+        }
+    }
+
+    #[test]
+    fn subtype_preference() {
+        use VeNCryptSubtype::*;
+        let server =
+            VeNCryptVerifier::new(vec![TlsNone, TlsVnc, X509None]).with_vnc_password("secret");
+        assert_eq!(server.offered(), [TlsNone, TlsVnc]);
+        let client = VeNCryptAuthenticator::new(vec![X509None, TlsVnc, TlsNone])
+            .with_credentials("", "secret");
+        let (client, server) = connect(client, server);
+        assert_eq!(client.unwrap(), [1, 2, 3, 4]);
+        server.unwrap();
+    }
+
+    #[test]
+    fn rejected_credentials() {
+        use VeNCryptSubtype::*;
+        for subtype in [TlsVnc, X509Plain] {
+            let server = verifier(subtype);
+            let certificate = server.identity.as_ref().unwrap().0.clone();
+            let client = VeNCryptAuthenticator::new(vec![subtype])
+                .with_credentials("user", "wrong")
+                .with_verification(X509Verification {
+                    ca_certificates: vec![certificate],
+                    domain: None,
+                });
+            let (client, server) = connect(client, server);
+            assert!(matches!(client, Err(Error::AuthenticationFailed(Some(_)))));
+            assert!(matches!(server, Err(Error::AuthenticationFailed(Some(_)))));
+        }
+    }
+
+    #[test]
+    fn untrusted_certificate() {
+        let server = verifier(VeNCryptSubtype::X509None);
+        let client = VeNCryptAuthenticator::new(vec![VeNCryptSubtype::X509None]).with_verification(
+            X509Verification {
+                ca_certificates: vec![certificate().0],
+                domain: None,
+            },
+        );
+        let (client, server) = connect(client, server);
+        assert!(matches!(client, Err(Error::Tls(_))));
+        assert!(server.is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use std::io::{Read, Write};
+
+use des::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use des::Des;
+
+use super::{ClientAuthenticator, ServerAuthenticator, Verdict};
+use crate::io::Stream;
+use crate::messages::{SecurityType, Version};
+
+pub const CHALLENGE_LENGTH: usize = 16;
+
+/// Builds the DES key used by VNC Authentication: the password is truncated or
+/// zero-padded to 8 bytes and the bits of every byte are mirrored.
+pub fn des_key(password: &str) -> [u8; 8] {
+    let mut key = [0; 8];
+    for (k, b) in key.iter_mut().zip(password.bytes()) {
+        *k = b.reverse_bits();
+    }
+    key
+}
+
+pub fn encrypt_challenge(
+    password: &str,
+    challenge: [u8; CHALLENGE_LENGTH],
+) -> [u8; CHALLENGE_LENGTH] {
+    let cipher = Des::new(&GenericArray::from(des_key(password)));
+    let mut response = challenge;
+    for block in response.chunks_exact_mut(8) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+    response
+}
+
+/// Client side of VNC Authentication: reads the server challenge and answers it.
+pub fn authenticate<S: Read + Write>(stream: &mut S, password: &str) -> Result<(), crate::Error> {
+    let mut challenge = [0; CHALLENGE_LENGTH];
+    stream.read_exact(&mut challenge)?;
+    stream.write_all(&encrypt_challenge(password, challenge))?;
+    stream.flush()?;
+    Ok(())
+}
+
+pub struct VncAuthenticator {
+    password: String,
+}
+
+impl VncAuthenticator {
+    pub fn new(password: impl Into<String>) -> Self {
+        Self {
+            password: password.into(),
+        }
+    }
+}
+
+impl ClientAuthenticator for VncAuthenticator {
+    fn security_type(&self) -> SecurityType {
+        SecurityType::VncAuthentication
+    }
+    fn authenticate(
+        &mut self,
+        mut stream: Box<dyn Stream>,
+        _version: &Version,
+    ) -> Result<Box<dyn Stream>, crate::Error> {
+        authenticate(&mut stream, &self.password)?;
+        Ok(stream)
+    }
+}
+
+/// Server side of VNC Authentication.
+#[derive(Clone)]
+pub struct VncAuthVerifier {
+    password: String,
+}
+
+impl VncAuthVerifier {
+    pub fn new(password: impl Into<String>) -> Self {
+        Self {
+            password: password.into(),
+        }
+    }
+
+    pub fn challenge(&self) -> [u8; CHALLENGE_LENGTH] {
+        rand::random()
+    }
+
+    pub fn verify(
+        &self,
+        challenge: [u8; CHALLENGE_LENGTH],
+        response: [u8; CHALLENGE_LENGTH],
+    ) -> bool {
+        encrypt_challenge(&self.password, challenge) == response
+    }
+
+    /// Sends a fresh challenge and checks the client response.
+    pub fn authenticate<S: Read + Write>(&self, stream: &mut S) -> Result<bool, crate::Error> {
+        let challenge = self.challenge();
+        stream.write_all(&challenge)?;
+        stream.flush()?;
+        let mut response = [0; CHALLENGE_LENGTH];
+        stream.read_exact(&mut response)?;
+        Ok(self.verify(challenge, response))
+    }
+}
+
+impl ServerAuthenticator for VncAuthVerifier {
+    fn security_type(&self) -> SecurityType {
+        SecurityType::VncAuthentication
+    }
+    fn authenticate(
+        &mut self,
+        mut stream: Box<dyn Stream>,
+        _version: &Version,
+    ) -> Result<(Box<dyn Stream>, Verdict), crate::Error> {
+        let verdict = match VncAuthVerifier::authenticate(self, &mut stream)? {
+            true => Verdict::Accepted,
+            false => Verdict::Rejected("invalid password".to_string()),
+        };
+        Ok((stream, verdict))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn des_key_mirrors_bits() {
+        assert_eq!(
+            des_key("password"),
+            [0x0e, 0x86, 0xce, 0xce, 0xee, 0xf6, 0x4e, 0x26]
+        );
+        assert_eq!(des_key("verylongpassword"), des_key("verylong"));
+        assert_eq!(des_key(""), [0; 8]);
+    }
+
+    #[test]
+    fn challenge_vectors() {
+        let challenge = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f,
+        ];
+        assert_eq!(
+            encrypt_challenge("password", challenge),
+            [
+                0xb8, 0x66, 0x92, 0x41, 0x25, 0xc8, 0xee, 0xbb, 0x9d, 0xeb, 0xc1, 0xdb, 0x61, 0xc5,
+                0x38, 0xe2
+            ]
+        );
+
+        let challenge = [
+            0xde, 0xad, 0xbe, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0xfe, 0xdc,
+            0xba, 0x98,
+        ];
+        assert_eq!(
+            encrypt_challenge("verylongpassword", challenge),
+            [
+                0x82, 0x0e, 0xd3, 0x63, 0xe9, 0x36, 0xe5, 0xb7, 0xc0, 0x80, 0x10, 0xc9, 0xbd, 0xd5,
+                0x9b, 0x2d
+            ]
+        );
+        assert_eq!(
+            encrypt_challenge("", challenge),
+            [
+                0x5e, 0x7e, 0xd3, 0x01, 0x0e, 0xea, 0x44, 0x22, 0x70, 0x5c, 0xab, 0x62, 0x13, 0x7d,
+                0x4b, 0x58
+            ]
+        );
+    }
+
+    #[test]
+    fn challenge_response() -> Result<(), crate::Error> {
+        for (server_password, client_password, expected) in
+            [("secret", "secret", true), ("secret", "wrong", false)]
+        {
+            let (mut server, mut client) = UnixStream::pair()?;
+            let verifier = VncAuthVerifier::new(server_password);
+            let handle = std::thread::spawn(move || verifier.authenticate(&mut server));
+            authenticate(&mut client, client_password)?;
+            assert_eq!(handle.join().unwrap()?, expected);
+        }
+        Ok(())
+    }
+}
@@ -1,111 +1,545 @@
-use crate::error;
+use crate::auth::{
+    self, ArdAuthenticator, ClientAuthenticator, NoAuthentication, VncAuthenticator,
+};
+use crate::encodings::cursor::Cursor;
 use crate::io::*;
 use crate::messages::*;
-use std::io::BufRead;
-use std::io::BufReader;
-use std::io::BufWriter;
-use std::io::Read;
+use std::collections::VecDeque;
 use std::io::Write;
 use std::net::SocketAddr;
 use std::net::TcpStream;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 pub struct Client {
-    pixel_format: PixelFormat,
-    version: Version,
+    stream: Box<dyn Stream>,
+    context: SessionContext,
+    /// Protocol spoken in the session.
+    pub version: Version,
+    /// Version announced by the server, which may be higher or non-standard.
+    pub server_version: ProtocolVersion,
+    pub framebuffer_width: u16,
+    pub framebuffer_height: u16,
+    pub name: String,
+    /// Set when the server negotiated the Tight security type.
+    pub interaction_capabilities: Option<InteractionCapabilities>,
+    /// Last pointer shape sent by the server, with the Cursor or XCursor
+    /// pseudo-encodings.
+    pub cursor: Option<Cursor>,
+    /// Screen layout, known once the server sent an ExtendedDesktopSize.
+    pub screens: Vec<Screen>,
+    /// Whether the server sent EndOfContinuousUpdates, telling it accepts
+    /// EnableContinuousUpdates.
+    pub continuous_updates: bool,
+    streaming: bool,
+    /// Whether the server sent a fence, telling it accepts ClientFence.
+    pub fence: bool,
+    /// Fences sent and not answered yet: their number and when they were
+    /// sent.
+    fences: VecDeque<(u32, Instant)>,
+    fence_number: u32,
+    /// Round trip time measured with the last fence answered.
+    pub rtt: Option<Duration>,
+    /// Whether the server acknowledged the QemuExtendedKeyEvent encoding.
+    pub qemu_extended_key_events: bool,
+    /// Whether the server acknowledged the QemuAudio encoding.
+    pub qemu_audio: bool,
+    clipboard: ClipboardState,
+    /// Messages read while waiting for an update, already applied to the
+    /// session and not yet returned.
+    pending: VecDeque<ServerMessage>,
 }
 
 impl Client {
     pub fn new(addr: &SocketAddr, timeout: Duration) -> Result<Self, crate::error::Error> {
+        Self::with_authenticators(addr, timeout, vec![Box::new(NoAuthentication)])
+    }
+
+    pub fn with_password(
+        addr: &SocketAddr,
+        timeout: Duration,
+        password: &str,
+    ) -> Result<Self, crate::error::Error> {
+        Self::with_authenticators(
+            addr,
+            timeout,
+            vec![Box::new(VncAuthenticator::new(password))],
+        )
+    }
+
+    /// Offers Apple Remote Desktop authentication, falling back to VNC
+    /// Authentication with the same password.
+    pub fn with_credentials(
+        addr: &SocketAddr,
+        timeout: Duration,
+        username: &str,
+        password: &str,
+    ) -> Result<Self, crate::error::Error> {
+        Self::with_authenticators(
+            addr,
+            timeout,
+            vec![
+                Box::new(ArdAuthenticator::new(username, password)),
+                Box::new(VncAuthenticator::new(password)),
+            ],
+        )
+    }
+
+    /// Connects using the first of `authenticators` (in order of preference)
+    /// whose security type is offered by the server.
+    pub fn with_authenticators(
+        addr: &SocketAddr,
+        timeout: Duration,
+        authenticators: Vec<Box<dyn ClientAuthenticator>>,
+    ) -> Result<Self, crate::error::Error> {
         let stream = TcpStream::connect_timeout(addr, timeout)?;
-        let mut reader = BufReader::new(&stream);
-        let mut writer = BufWriter::new(&stream);
-        println!("Connected");
-        let version = Version::decode_from(&mut reader)?;
-        version.clone().encode_to(&mut writer)?;
-        writer.flush()?;
-        let security_type = SecurityType::None;
-
-        let server_security_types = match version {
-            Version::Rfb33 => {
-                let server_security_type = SecurityType::decode_from(&mut reader)?;
-                vec![server_security_type]
-            }
+        stream.set_nodelay(true)?;
+        Self::handshake(stream, authenticators)
+    }
+
+    pub fn handshake(
+        stream: impl Stream + 'static,
+        mut authenticators: Vec<Box<dyn ClientAuthenticator>>,
+    ) -> Result<Self, crate::error::Error> {
+        let mut stream: Box<dyn Stream> = Box::new(stream);
+        let server_version = ProtocolVersion::decode_from(&mut stream)?;
+        let version = Version::try_from(server_version)?;
+        version.clone().encode_to(&mut stream)?;
+        stream.flush()?;
+
+        let (stream, security_type) =
+            auth::negotiate_client(stream, &version, &mut authenticators)?;
+        let mut stream: Box<dyn Stream> = Box::new(BufStream::new(stream));
 
-            _ => Vec::<SecurityType>::decode_from(&mut reader)?,
-        };
+        ClientInit { shared: false }.encode_to(&mut stream)?;
+        stream.flush()?;
 
-        //TODO: List of securities for client, get error message if version > 3.3
-        if !server_security_types.contains(&security_type) {
-            return Err(error::Error::IncompatibleSecurity);
+        let mut server_init = ServerInit::decode_from(&mut stream)?;
+        if security_type == SecurityType::Tight {
+            server_init.interaction_capabilities =
+                Some(InteractionCapabilities::decode_from(&mut stream)?);
         }
-        security_type.encode_to(&mut writer)?;
-        writer.flush()?;
 
-        let security_result = SecurityResult::decode_from(&mut reader)?;
-        if security_result != SecurityResult::Ok {
-            if version == Version::Rfb38 {
-                let reason = String::decode_from(&mut reader)?;
-                //TODO: Handle reason
-            }
-            return Err(error::Error::HandshakeFailed);
+        Ok(Self {
+            stream,
+            context: SessionContext::new(server_init.pixel_format),
+            version,
+            server_version,
+            framebuffer_width: server_init.framebuffer_width,
+            framebuffer_height: server_init.framebuffer_height,
+            name: server_init.name,
+            interaction_capabilities: server_init.interaction_capabilities,
+            cursor: None,
+            screens: Vec::new(),
+            continuous_updates: false,
+            streaming: false,
+            fence: false,
+            fences: VecDeque::new(),
+            fence_number: 0,
+            rtt: None,
+            qemu_extended_key_events: false,
+            qemu_audio: false,
+            clipboard: ClipboardState::default(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Pixel format of the updates sent by the server.
+    pub fn pixel_format(&self) -> &PixelFormat {
+        &self.context.pixel_format
+    }
+
+    pub fn set_pixel_format(
+        &mut self,
+        pixel_format: PixelFormat,
+    ) -> Result<(), crate::error::Error> {
+        pixel_format.bytes_per_pixel()?;
+        SetPixelFormat {
+            pixel_format: pixel_format.clone(),
         }
+        .encode_to(&mut self.stream)?;
+        self.stream.flush()?;
+        self.context.pixel_format = pixel_format;
+        Ok(())
+    }
 
-        ClientInit { shared: false }.encode_to(&mut writer)?;
-        writer.flush()?;
+    /// Tells the server which encodings it may use, in order of preference.
+    /// Nothing is sent if one of them cannot be encoded.
+    pub fn set_encodings(
+        &mut self,
+        encodings: Vec<EncodingType>,
+    ) -> Result<(), crate::error::Error> {
+        let mut buf = Vec::new();
+        SetEncodings {
+            encodings: encodings.clone(),
+        }
+        .encode_to(&mut buf)?;
+        self.stream.write_all(&buf)?;
+        self.stream.flush()?;
+        if !encodings.contains(&EncodingType::ExtendedClipboard) {
+            self.clipboard.caps = None;
+        }
+        self.context.encodings = encodings;
+        Ok(())
+    }
 
-        let server_init = ServerInit::decode_from(&mut reader)?;
-        /*
-            SetEncodings {
-                encodings: vec![EncodingType::Raw],
-            }
-            .encode_to(&mut writer)?;
-        writer.flush()?;
-        */
+    /// Asks the server to change the framebuffer size and layout, which it
+    /// answers with an ExtendedDesktopSize rectangle.
+    pub fn set_desktop_size(
+        &mut self,
+        width: u16,
+        height: u16,
+        screens: Vec<Screen>,
+    ) -> Result<(), crate::error::Error> {
+        SetDesktopSize {
+            width,
+            height,
+            screens,
+        }
+        .encode_to(&mut self.stream)?;
+        self.stream.flush()?;
+        Ok(())
+    }
 
+    pub fn request_update(
+        &mut self,
+        incremental: bool,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    ) -> Result<(), crate::error::Error> {
         FramebufferUpdateRequest {
-            incremental: false,
-            x: 0,
-            y: 0,
-            width: 10,
-            height: 10,
+            incremental,
+            x,
+            y,
+            width,
+            height,
+        }
+        .encode_to(&mut self.stream)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Sends a key by its XT scancode as well as its keysym, see
+    /// [`us_scancode`] for the keys of a US keyboard. The server must have
+    /// acknowledged the QemuExtendedKeyEvent encoding.
+    pub fn extended_key_event(
+        &mut self,
+        down: bool,
+        keysym: u32,
+        keycode: u32,
+    ) -> Result<(), crate::error::Error> {
+        if !self.qemu_extended_key_events {
+            return Err(crate::Error::UnsupportedMessage(255));
+        }
+        QemuExtendedKeyEvent {
+            down,
+            keysym,
+            keycode,
         }
-        .encode_to(&mut writer)?;
-        writer.flush()?;
+        .encode_to(&mut self.stream)?;
+        self.stream.flush()?;
+        Ok(())
+    }
 
-        println!("Bonjour1");
-        let framebuffer = FramebufferUpdate::decode_from(&mut reader)?;
-        FramebufferUpdateRequest {
-            incremental: true,
+    /// Sets the clipboard the server can paste. With Extended Clipboard the
+    /// server is notified and requests the content, which is sent while
+    /// reading messages. Otherwise the text is sent, in Latin-1.
+    pub fn set_clipboard(&mut self, clipboard: Clipboard) -> Result<(), crate::error::Error> {
+        let cut_text = self.clipboard.set(clipboard);
+        self.send_cut_text(cut_text)
+    }
+
+    /// Requests the content of the server clipboard after a
+    /// `ClipboardEvent::Changed`, which comes as a `ClipboardEvent::Content`.
+    pub fn request_clipboard(&mut self) -> Result<(), crate::error::Error> {
+        match self.clipboard.request() {
+            Some(request) => self.send_cut_text(request),
+            None => Ok(()),
+        }
+    }
+
+    fn send_cut_text(&mut self, cut_text: CutText) -> Result<(), crate::error::Error> {
+        ClientMessage::ClientCutText(cut_text).encode_to(&mut self.stream)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Format of the audio samples sent by the server.
+    pub fn audio_format(&self) -> &AudioFormat {
+        &self.context.audio_format
+    }
+
+    /// Sets the format of the audio samples the server sends.
+    pub fn set_audio_format(&mut self, format: AudioFormat) -> Result<(), crate::error::Error> {
+        self.send_audio_message(QemuAudioClientMessage::SetFormat(format))?;
+        self.context.audio_format = format;
+        Ok(())
+    }
+
+    /// Asks the server to stream audio, which comes as buffers of samples in
+    /// QemuAudio messages between a begin and an end message.
+    pub fn enable_audio(&mut self) -> Result<(), crate::error::Error> {
+        self.send_audio_message(QemuAudioClientMessage::Enable)
+    }
+
+    pub fn disable_audio(&mut self) -> Result<(), crate::error::Error> {
+        self.send_audio_message(QemuAudioClientMessage::Disable)
+    }
+
+    /// The server must have acknowledged the QemuAudio encoding.
+    fn send_audio_message(
+        &mut self,
+        message: QemuAudioClientMessage,
+    ) -> Result<(), crate::error::Error> {
+        if !self.qemu_audio {
+            return Err(crate::Error::UnsupportedMessage(255));
+        }
+        message.encode_to(&mut self.stream)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Asks the server to send updates of an area as it changes, which it
+    /// does until `disable_continuous_updates`. The server must have
+    /// announced continuous updates, see the ContinuousUpdates encoding.
+    pub fn enable_continuous_updates(
+        &mut self,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+    ) -> Result<(), crate::error::Error> {
+        if !self.continuous_updates {
+            return Err(crate::Error::UnsupportedMessage(150));
+        }
+        EnableContinuousUpdates {
+            enable: true,
+            x,
+            y,
+            width,
+            height,
+        }
+        .encode_to(&mut self.stream)?;
+        self.stream.flush()?;
+        self.streaming = true;
+        Ok(())
+    }
+
+    /// Stops continuous updates, the server confirming with
+    /// EndOfContinuousUpdates once the last one is sent.
+    pub fn disable_continuous_updates(&mut self) -> Result<(), crate::error::Error> {
+        EnableContinuousUpdates {
+            enable: false,
             x: 0,
             y: 0,
-            width: 10,
-            height: 10,
+            width: self.framebuffer_width,
+            height: self.framebuffer_height,
         }
-        .encode_to(&mut writer)?;
-        writer.flush()?;
-        let framebuffer = FramebufferUpdate::decode_from(&mut reader)?;
+        .encode_to(&mut self.stream)?;
+        self.stream.flush()?;
+        Ok(())
+    }
 
-        Ok(Self {
-            pixel_format: server_init.pixel_format,
-            version,
-        })
+    /// Sends a fence the server answers once everything sent before it is
+    /// processed, measuring `rtt` when the answer is read. The server must
+    /// have sent a fence first. The data in flight towards the client is
+    /// bounded by the server, from the fences it sends along updates: they
+    /// are answered only once the updates before them are read.
+    pub fn send_fence(&mut self) -> Result<(), crate::error::Error> {
+        if !self.fence {
+            return Err(crate::Error::UnsupportedMessage(248));
+        }
+        let number = self.fence_number;
+        self.fence_number = number.wrapping_add(1);
+        Fence {
+            flags: FenceFlags {
+                block_before: true,
+                request: true,
+                ..Default::default()
+            },
+            payload: number.to_be_bytes().to_vec(),
+        }
+        .encode_to(&mut self.stream)?;
+        self.stream.flush()?;
+        self.fences.push_back((number, Instant::now()));
+        Ok(())
+    }
+
+    /// Fences sent with `send_fence` and not answered yet.
+    pub fn fences_in_flight(&self) -> usize {
+        self.fences.len()
+    }
+
+    /// Whether the server streams updates, from `enable_continuous_updates`
+    /// until it sends EndOfContinuousUpdates.
+    pub fn streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// Reads the next server message, applying it to the session. Fence
+    /// requests are answered once everything before them is read, which
+    /// lets the server measure the data in flight, and so are clipboard caps,
+    /// peeks and requests. Messages skipped by `read_update` come first.
+    pub fn read_message(&mut self) -> Result<ServerMessage, crate::error::Error> {
+        match self.pending.pop_front() {
+            Some(message) => Ok(message),
+            None => self.receive_message(),
+        }
+    }
+
+    fn receive_message(&mut self) -> Result<ServerMessage, crate::error::Error> {
+        let message = ServerMessage::decode_with(&mut self.stream, &mut self.context)?;
+        match &message {
+            ServerMessage::FramebufferUpdate(update) => {
+                for rectangle in &update.rectangles {
+                    match &rectangle.payload {
+                        Payload::Cursor(cursor) => self.cursor = Some(cursor.clone()),
+                        Payload::DesktopSize => {
+                            self.framebuffer_width = rectangle.width;
+                            self.framebuffer_height = rectangle.height;
+                        }
+                        Payload::ExtendedDesktopSize(screens) if rectangle.y == 0 => {
+                            self.framebuffer_width = rectangle.width;
+                            self.framebuffer_height = rectangle.height;
+                            self.screens = screens.clone();
+                        }
+                        Payload::QemuExtendedKeyEvent => self.qemu_extended_key_events = true,
+                        Payload::QemuAudio => self.qemu_audio = true,
+                        _ => {}
+                    }
+                }
+            }
+            ServerMessage::EndOfContinuousUpdates(_) => {
+                self.continuous_updates = true;
+                self.streaming = false;
+            }
+            ServerMessage::ServerFence(fence) if fence.flags.request => {
+                self.fence = true;
+                fence.response().encode_to(&mut self.stream)?;
+                self.stream.flush()?;
+            }
+            ServerMessage::ServerFence(fence) => {
+                if let Some(&(number, sent)) = self.fences.front() {
+                    if fence.payload == number.to_be_bytes() {
+                        self.rtt = Some(sent.elapsed());
+                        self.fences.pop_front();
+                    }
+                }
+            }
+            ServerMessage::ServerCutText(cut_text) => {
+                if let Some(answer) = self.clipboard.receive(cut_text, true) {
+                    self.send_cut_text(answer)?;
+                }
+            }
+            ServerMessage::SetColourMapEntries(_)
+            | ServerMessage::Bell(_)
+            | ServerMessage::QemuAudio(_) => {}
+        }
+        Ok(message)
+    }
+
+    /// Reads server messages until an update. The others, such as bells, cut
+    /// text, audio or EndOfContinuousUpdates, are kept for `read_message` to
+    /// return, in the order they came.
+    pub fn read_update(&mut self) -> Result<FramebufferUpdate, crate::error::Error> {
+        let position = self
+            .pending
+            .iter()
+            .position(|message| matches!(message, ServerMessage::FramebufferUpdate(_)));
+        if let Some(ServerMessage::FramebufferUpdate(update)) =
+            position.and_then(|position| self.pending.remove(position))
+        {
+            return Ok(update);
+        }
+        loop {
+            match self.receive_message()? {
+                ServerMessage::FramebufferUpdate(update) => return Ok(update),
+                message => self.pending.push_back(message),
+            }
+        }
     }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
 
     #[test]
-    fn client() {
-        use crate::client::Client;
-        println!("Bonjour");
-        Client::new(
-            &SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 14), 5900)),
-            Duration::from_millis(1000),
-        )
-        .unwrap();
+    fn non_standard_server_version() {
+        for (announced, reply) in [
+            (b"RFB 003.889\n", b"RFB 003.008\n"),
+            (b"RFB 004.001\n", b"RFB 003.008\n"),
+            (b"RFB 003.005\n", b"RFB 003.003\n"),
+        ] {
+            let (mut server, client) = UnixStream::pair().unwrap();
+            server.write_all(announced).unwrap();
+            server.write_all(&[0, 0, 0, 1]).unwrap();
+            server.shutdown(std::net::Shutdown::Write).unwrap();
+            assert!(Client::handshake(client, vec![Box::new(NoAuthentication)]).is_err());
+            let mut sent = [0; 12];
+            server.read_exact(&mut sent).unwrap();
+            assert_eq!(&sent, reply);
+        }
+    }
 
-        panic!("Bonjour")
+    #[test]
+    fn password_required() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            crate::server::Server::handshake(
+                stream,
+                Version::Rfb38,
+                vec![Box::new(NoAuthentication)],
+                crate::auth::tests::server_init(),
+                &crate::server::Sessions::new(),
+            )
+        });
+        // A server without authentication is refused, not connected to.
+        let client = Client::with_password(&addr, Duration::from_secs(1), "secret");
+        assert!(matches!(client, Err(crate::Error::IncompatibleSecurity)));
+        assert!(handle.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn client() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut server = crate::server::Server::handshake(
+                stream,
+                Version::Rfb38,
+                vec![Box::new(NoAuthentication)],
+                crate::auth::tests::server_init(),
+                &crate::server::Sessions::new(),
+            )
+            .unwrap();
+            for value in [1, 2] {
+                let request = server.read_update_request().unwrap();
+                assert_eq!(request.incremental, value == 2);
+                server
+                    .send_update(FramebufferUpdate {
+                        rectangles: vec![Rectangle {
+                            x: 0,
+                            y: 0,
+                            width: 1,
+                            height: 1,
+                            encoding_type: EncodingType::Raw,
+                            payload: Payload::Pixels(vec![value; 4]),
+                        }],
+                    })
+                    .unwrap();
+            }
+        });
+        let mut client = Client::new(&addr, Duration::from_millis(1000)).unwrap();
+        for (incremental, value) in [(false, 1), (true, 2)] {
+            client.request_update(incremental, 0, 0, 10, 10).unwrap();
+            let rectangles = client.read_update().unwrap().rectangles;
+            assert_eq!(rectangles[0].payload, Payload::Pixels(vec![value; 4]));
+        }
+        handle.join().unwrap();
     }
 }
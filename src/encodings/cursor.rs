@@ -0,0 +1,222 @@
+use std::io::{Read, Write};
+
+use crate::error::Error;
+use crate::io::*;
+use crate::messages::PixelFormat;
+
+/// A pointer shape sent by the Cursor and XCursor pseudo-encodings, to be
+/// drawn by the client at the pointer position minus the hotspot.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct Cursor {
+    pub hotspot_x: u16,
+    pub hotspot_y: u16,
+    pub width: u16,
+    pub height: u16,
+    pub image: CursorImage,
+    /// One bit per pixel, most significant first, rows padded to a byte:
+    /// set for the pixels of the shape, clear for transparent ones.
+    pub mask: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum CursorImage {
+    /// Pixels in the session pixel format, row by row (Cursor).
+    Pixels(Vec<u8>),
+    /// Two colours and a bitmap of the same layout as the mask, set for the
+    /// foreground colour (XCursor).
+    Bitmap {
+        foreground: [u8; 3],
+        background: [u8; 3],
+        bitmap: Vec<u8>,
+    },
+}
+
+/// Size of a bitmap of one bit per pixel.
+fn bitmap_len(width: u16, height: u16) -> usize {
+    (width as usize).div_ceil(8) * height as usize
+}
+
+fn bit(bitmap: &[u8], width: u16, x: u16, y: u16) -> bool {
+    let row = (width as usize).div_ceil(8);
+    bitmap[y as usize * row + x as usize / 8] & (0x80 >> (x % 8)) != 0
+}
+
+impl Cursor {
+    /// Whether the pixel at `x`, `y` of the shape is drawn.
+    pub fn visible(&self, x: u16, y: u16) -> bool {
+        x < self.width && y < self.height && bit(&self.mask, self.width, x, y)
+    }
+
+    /// Pixels of the shape in a true colour `pixel_format`, row by row.
+    pub fn pixels(&self, pixel_format: &PixelFormat) -> Result<Vec<u8>, Error> {
+        match &self.image {
+            CursorImage::Pixels(pixels) => Ok(pixels.clone()),
+            CursorImage::Bitmap { .. } if !pixel_format.true_colour_flag => {
+                Err(Error::UnsupportedPixelFormat)
+            }
+            CursorImage::Bitmap {
+                foreground,
+                background,
+                bitmap,
+            } => {
+                let (foreground, background) = (
+                    pixel_format.from_rgb(*foreground),
+                    pixel_format.from_rgb(*background),
+                );
+                Ok((0..self.height)
+                    .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+                    .flat_map(|(x, y)| match bit(bitmap, self.width, x, y) {
+                        true => foreground.clone(),
+                        false => background.clone(),
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+/// Decodes a Cursor pseudo-rectangle, whose position is the hotspot.
+pub fn decode<R: Read>(
+    reader: &mut R,
+    bytes_per_pixel: usize,
+    (hotspot_x, hotspot_y): (u16, u16),
+    width: u16,
+    height: u16,
+) -> Result<Cursor, Error> {
+    let mut pixels = vec![0; width as usize * height as usize * bytes_per_pixel];
+    reader.read_exact(&mut pixels)?;
+    let mut mask = vec![0; bitmap_len(width, height)];
+    reader.read_exact(&mut mask)?;
+    Ok(Cursor {
+        hotspot_x,
+        hotspot_y,
+        width,
+        height,
+        image: CursorImage::Pixels(pixels),
+        mask,
+    })
+}
+
+/// Decodes an XCursor pseudo-rectangle, which carries nothing when empty.
+pub fn decode_x<R: Read>(
+    reader: &mut R,
+    (hotspot_x, hotspot_y): (u16, u16),
+    width: u16,
+    height: u16,
+) -> Result<Cursor, Error> {
+    let mut cursor = Cursor {
+        hotspot_x,
+        hotspot_y,
+        width,
+        height,
+        image: CursorImage::Bitmap {
+            foreground: [0; 3],
+            background: [0; 3],
+            bitmap: Vec::new(),
+        },
+        mask: Vec::new(),
+    };
+    if width == 0 || height == 0 {
+        return Ok(cursor);
+    }
+    let foreground = <[u8; 3]>::decode_from(reader)?;
+    let background = <[u8; 3]>::decode_from(reader)?;
+    let mut bitmap = vec![0; bitmap_len(width, height)];
+    reader.read_exact(&mut bitmap)?;
+    cursor.mask.resize(bitmap.len(), 0);
+    reader.read_exact(&mut cursor.mask)?;
+    cursor.image = CursorImage::Bitmap {
+        foreground,
+        background,
+        bitmap,
+    };
+    Ok(cursor)
+}
+
+/// Checks the sizes of the image and of the mask.
+fn check(cursor: &Cursor, image_len: usize, expected: usize) -> Result<(), Error> {
+    let len = bitmap_len(cursor.width, cursor.height);
+    match image_len == expected && cursor.mask.len() == len {
+        true => Ok(()),
+        false => Err(Error::LengthTooBig),
+    }
+}
+
+pub fn encode<W: Write>(
+    writer: &mut W,
+    bytes_per_pixel: usize,
+    cursor: &Cursor,
+) -> Result<usize, Error> {
+    let CursorImage::Pixels(pixels) = &cursor.image else {
+        return Err(Error::UnsupportedEncoding);
+    };
+    let len = cursor.width as usize * cursor.height as usize * bytes_per_pixel;
+    check(cursor, pixels.len(), len)?;
+    writer.write_all(pixels)?;
+    writer.write_all(&cursor.mask)?;
+    Ok(pixels.len() + cursor.mask.len())
+}
+
+pub fn encode_x<W: Write>(writer: &mut W, cursor: &Cursor) -> Result<usize, Error> {
+    let CursorImage::Bitmap {
+        foreground,
+        background,
+        bitmap,
+    } = &cursor.image
+    else {
+        return Err(Error::UnsupportedEncoding);
+    };
+    if cursor.width == 0 || cursor.height == 0 {
+        return Ok(0);
+    }
+    check(
+        cursor,
+        bitmap.len(),
+        bitmap_len(cursor.width, cursor.height),
+    )?;
+    writer.write_all(foreground)?;
+    writer.write_all(background)?;
+    writer.write_all(bitmap)?;
+    writer.write_all(&cursor.mask)?;
+    Ok(6 + bitmap.len() + cursor.mask.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::tests::server_init;
+
+    #[test]
+    fn x_cursor() {
+        // A 10x2 arrow-like shape: the bitmap and mask rows take 2 bytes.
+        let bytes = [
+            255, 255, 255, 0, 0, 0, 0xff, 0x00, 0x80, 0x40, 0xff, 0xc0, 0xc0, 0x40,
+        ];
+        let cursor = decode_x(&mut &bytes[..], (1, 0), 10, 2).unwrap();
+        assert!(cursor.visible(9, 0) && cursor.visible(1, 1) && cursor.visible(9, 1));
+        assert!(!cursor.visible(8, 1) && !cursor.visible(10, 0));
+
+        let format = server_init().pixel_format;
+        let pixels = cursor.pixels(&format).unwrap();
+        let (white, black) = (&[255, 255, 255, 0][..], &[0, 0, 0, 0][..]);
+        assert_eq!(&pixels[..4], white);
+        assert_eq!(&pixels[32..36], black);
+        assert_eq!(&pixels[40..44], white);
+        assert_eq!(&pixels[76..], white);
+
+        let mut buf = Vec::new();
+        assert_eq!(encode_x(&mut buf, &cursor).unwrap(), bytes.len());
+        assert_eq!(buf, bytes);
+        assert!(matches!(
+            encode(&mut buf, 4, &cursor),
+            Err(Error::UnsupportedEncoding)
+        ));
+    }
+
+    #[test]
+    fn empty_x_cursor() {
+        let cursor = decode_x(&mut &[][..], (0, 0), 0, 0).unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(encode_x(&mut buf, &cursor).unwrap(), 0);
+    }
+}
@@ -0,0 +1,225 @@
+use std::io::{Read, Write};
+
+use super::{area, background, cover, fill, paste, pixels, tiles};
+use crate::error::Error;
+use crate::io::*;
+
+const TILE_SIZE: usize = 16;
+
+const RAW: u8 = 1;
+const BACKGROUND_SPECIFIED: u8 = 2;
+const FOREGROUND_SPECIFIED: u8 = 4;
+const ANY_SUBRECTS: u8 = 8;
+const SUBRECTS_COLOURED: u8 = 16;
+
+pub fn decode<R: Read>(
+    reader: &mut R,
+    bytes_per_pixel: usize,
+    width: u16,
+    height: u16,
+) -> Result<Vec<u8>, Error> {
+    let width = width as usize;
+    let mut data = vec![0; width * height as usize * bytes_per_pixel];
+    // Both colours carry over from one tile to the next.
+    let mut background = vec![0; bytes_per_pixel];
+    let mut foreground = vec![0; bytes_per_pixel];
+    let mut pixel = vec![0; bytes_per_pixel];
+
+    for tile in tiles(width, height as usize, TILE_SIZE) {
+        let (x, y, w, h) = tile;
+        let subencoding = u8::decode_from(reader)?;
+        if subencoding & RAW != 0 {
+            let mut pixels = vec![0; w * h * bytes_per_pixel];
+            reader.read_exact(&mut pixels)?;
+            paste(&mut data, width, tile, &pixels, bytes_per_pixel);
+            continue;
+        }
+        if subencoding & BACKGROUND_SPECIFIED != 0 {
+            reader.read_exact(&mut background)?;
+        }
+        fill(&mut data, width, tile, &background);
+        if subencoding & FOREGROUND_SPECIFIED != 0 {
+            reader.read_exact(&mut foreground)?;
+        }
+        if subencoding & ANY_SUBRECTS == 0 {
+            continue;
+        }
+        for _ in 0..u8::decode_from(reader)? {
+            let colour = match subencoding & SUBRECTS_COLOURED != 0 {
+                true => {
+                    reader.read_exact(&mut pixel)?;
+                    &pixel
+                }
+                false => &foreground,
+            };
+            let [xy, wh] = <[u8; 2]>::decode_from(reader)?;
+            let (sx, sy) = ((xy >> 4) as usize, (xy & 0xf) as usize);
+            let (sw, sh) = ((wh >> 4) as usize + 1, (wh & 0xf) as usize + 1);
+            if sx + sw > w || sy + sh > h {
+                return Err(Error::BadResponse);
+            }
+            fill(&mut data, width, (x + sx, y + sy, sw, sh), colour);
+        }
+    }
+    Ok(data)
+}
+
+/// Encodes each tile with the cheapest subencoding: a background, a
+/// foreground and subrectangles of it, coloured subrectangles, or raw.
+pub fn encode<W: Write>(
+    writer: &mut W,
+    bytes_per_pixel: usize,
+    width: u16,
+    height: u16,
+    data: &[u8],
+) -> Result<usize, Error> {
+    let width = width as usize;
+    let mut buf = Vec::new();
+    // Colours known to the decoder from previous tiles.
+    let mut last_background: Option<Vec<u8>> = None;
+    let mut last_foreground: Option<Vec<u8>> = None;
+
+    for tile in tiles(width, height as usize, TILE_SIZE) {
+        let (_, _, w, h) = tile;
+        let pixels_of_tile = area(data, width, tile, bytes_per_pixel);
+        let background = background(&pixels_of_tile, bytes_per_pixel).to_vec();
+        let subrectangles = cover(&pixels_of_tile, bytes_per_pixel, w, &background);
+        let mut colours: Vec<&[u8]> = pixels(&pixels_of_tile, bytes_per_pixel)
+            .filter(|p| *p != background.as_slice())
+            .collect();
+        colours.sort();
+        colours.dedup();
+
+        let background_cost = match last_background.as_ref() == Some(&background) {
+            true => 0,
+            false => bytes_per_pixel,
+        };
+        let raw_cost = w * h * bytes_per_pixel;
+        let cost = match colours.as_slice() {
+            [] => Some(background_cost),
+            [foreground] => {
+                let foreground_cost = match last_foreground.as_deref() == Some(*foreground) {
+                    true => 0,
+                    false => bytes_per_pixel,
+                };
+                Some(background_cost + foreground_cost + 1 + 2 * subrectangles.len())
+            }
+            _ => Some(background_cost + 1 + (bytes_per_pixel + 2) * subrectangles.len()),
+        }
+        .filter(|&cost| cost < raw_cost && subrectangles.len() <= u8::MAX as usize);
+
+        let Some(_) = cost else {
+            buf.push(RAW);
+            buf.extend_from_slice(&pixels_of_tile);
+            last_background = None;
+            last_foreground = None;
+            continue;
+        };
+
+        let mut subencoding = 0;
+        let mut body = Vec::new();
+        if background_cost != 0 {
+            subencoding |= BACKGROUND_SPECIFIED;
+            body.extend_from_slice(&background);
+        }
+        let coloured = colours.len() > 1;
+        if colours.len() == 1 && last_foreground.as_deref() != Some(colours[0]) {
+            subencoding |= FOREGROUND_SPECIFIED;
+            body.extend_from_slice(colours[0]);
+            last_foreground = Some(colours[0].to_vec());
+        }
+        if !subrectangles.is_empty() {
+            subencoding |= ANY_SUBRECTS;
+            if coloured {
+                subencoding |= SUBRECTS_COLOURED;
+                // The foreground is not defined after coloured subrectangles.
+                last_foreground = None;
+            }
+            body.push(subrectangles.len() as u8);
+            for s in &subrectangles {
+                if coloured {
+                    let start = (s.y * w + s.x) * bytes_per_pixel;
+                    body.extend_from_slice(&pixels_of_tile[start..start + bytes_per_pixel]);
+                }
+                body.push(((s.x as u8) << 4) | s.y as u8);
+                body.push((((s.width - 1) as u8) << 4) | (s.height - 1) as u8);
+            }
+        }
+        buf.push(subencoding);
+        buf.extend_from_slice(&body);
+        last_background = Some(background);
+    }
+    writer.write_all(&buf)?;
+    Ok(buf.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn golden_bytes() {
+        // Two 16x16 tiles of 8-bit pixels: a background with a foreground
+        // square, then the same background with two coloured pixels.
+        let mut data = vec![5; 32 * 16];
+        fill(&mut data, 32, (2, 3, 4, 2), &[9]);
+        fill(&mut data, 32, (16, 0, 1, 1), &[1]);
+        fill(&mut data, 32, (31, 15, 1, 1), &[2]);
+        let mut buf = Vec::new();
+        assert_eq!(encode(&mut buf, 1, 32, 16, &data).unwrap(), buf.len());
+        assert_eq!(
+            buf,
+            [
+                BACKGROUND_SPECIFIED | FOREGROUND_SPECIFIED | ANY_SUBRECTS,
+                5,
+                9,
+                1,
+                0x23,
+                0x31,
+                ANY_SUBRECTS | SUBRECTS_COLOURED,
+                2,
+                1,
+                0x00,
+                0x00,
+                2,
+                0xff,
+                0x00,
+            ]
+        );
+        assert_eq!(decode(&mut &buf[..], 1, 32, 16).unwrap(), data);
+    }
+
+    #[test]
+    fn round_trip() {
+        for bytes_per_pixel in [1, 2, 4] {
+            let (width, height) = (37, 21);
+            // Flat areas, a gradient and noise so that every subencoding is
+            // used, with a partial last row and column of tiles.
+            let data: Vec<u8> = (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .flat_map(|(x, y): (usize, usize)| {
+                    let value = match (x / 16, y / 16) {
+                        (0, 0) => ((x / 4 + y / 4) % 2) as u8,
+                        (1, 0) => (x * y) as u8,
+                        (2, _) => ((x * 7919 + y * 104729) % 251) as u8,
+                        _ => 3,
+                    };
+                    std::iter::repeat_n(value, bytes_per_pixel)
+                })
+                .collect();
+            let mut buf = Vec::new();
+            encode(
+                &mut buf,
+                bytes_per_pixel,
+                width as u16,
+                height as u16,
+                &data,
+            )
+            .unwrap();
+            assert!(buf.len() < data.len());
+            let decoded =
+                decode(&mut &buf[..], bytes_per_pixel, width as u16, height as u16).unwrap();
+            assert_eq!(decoded, data, "{bytes_per_pixel} bytes per pixel");
+        }
+    }
+}
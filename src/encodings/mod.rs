@@ -0,0 +1,146 @@
+// Codecs of the encodings carrying pixels. Decoders return the pixels of a
+// rectangle in the session pixel format, row by row, and encoders take them.
+
+pub mod cursor;
+pub mod hextile;
+pub mod rre;
+pub mod tight;
+pub mod trle;
+pub(crate) mod zlib;
+pub mod zrle;
+
+use std::collections::HashMap;
+
+/// Pixels of `bytes_per_pixel` bytes each.
+pub(crate) fn pixels(data: &[u8], bytes_per_pixel: usize) -> std::slice::ChunksExact<'_, u8> {
+    data.chunks_exact(bytes_per_pixel)
+}
+
+/// Most frequent pixel, the first one on ties.
+pub(crate) fn background(data: &[u8], bytes_per_pixel: usize) -> &[u8] {
+    let mut counts: HashMap<&[u8], (usize, usize)> = HashMap::new();
+    for (i, pixel) in pixels(data, bytes_per_pixel).enumerate() {
+        counts.entry(pixel).or_insert((0, i)).0 += 1;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|&(_, (count, first))| (count, std::cmp::Reverse(first)))
+        .map_or(&data[..0], |(pixel, _)| pixel)
+}
+
+/// Pixels of an area of a `width` pixels wide rectangle.
+pub(crate) fn area(
+    data: &[u8],
+    width: usize,
+    (x, y, w, h): (usize, usize, usize, usize),
+    bytes_per_pixel: usize,
+) -> Vec<u8> {
+    (y..y + h)
+        .flat_map(|row| {
+            let start = (row * width + x) * bytes_per_pixel;
+            &data[start..start + w * bytes_per_pixel]
+        })
+        .copied()
+        .collect()
+}
+
+/// Copies `pixels` to an area of a `width` pixels wide rectangle.
+pub(crate) fn paste(
+    data: &mut [u8],
+    width: usize,
+    (x, y, w, _): (usize, usize, usize, usize),
+    pixels: &[u8],
+    bytes_per_pixel: usize,
+) {
+    let len = w * bytes_per_pixel;
+    for (row, line) in pixels.chunks_exact(len).enumerate() {
+        let start = ((y + row) * width + x) * bytes_per_pixel;
+        data[start..start + len].copy_from_slice(line);
+    }
+}
+
+/// Areas of at most `size` pixels a side covering a rectangle, row by row.
+pub(crate) fn tiles(
+    width: usize,
+    height: usize,
+    size: usize,
+) -> impl Iterator<Item = (usize, usize, usize, usize)> {
+    (0..height).step_by(size).flat_map(move |y| {
+        (0..width)
+            .step_by(size)
+            .map(move |x| (x, y, size.min(width - x), size.min(height - y)))
+    })
+}
+
+/// Fills an area of a `width` pixels wide rectangle with `pixel`.
+pub(crate) fn fill(
+    data: &mut [u8],
+    width: usize,
+    (x, y, w, h): (usize, usize, usize, usize),
+    pixel: &[u8],
+) {
+    let bytes_per_pixel = pixel.len();
+    for row in y..y + h {
+        let start = (row * width + x) * bytes_per_pixel;
+        for target in data[start..start + w * bytes_per_pixel].chunks_exact_mut(bytes_per_pixel) {
+            target.copy_from_slice(pixel);
+        }
+    }
+}
+
+/// A subrectangle of a single colour, on top of the background.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) struct Subrectangle {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Greedily covers the pixels differing from `background` with single colour
+/// subrectangles, grown from the first uncovered pixel in the direction that
+/// covers the most.
+pub(crate) fn cover(
+    data: &[u8],
+    bytes_per_pixel: usize,
+    width: usize,
+    background: &[u8],
+) -> Vec<Subrectangle> {
+    let pixels: Vec<&[u8]> = pixels(data, bytes_per_pixel).collect();
+    let height = pixels.len().checked_div(width).unwrap_or(0);
+    let at = |x: usize, y: usize| pixels[y * width + x];
+    let mut covered = vec![false; pixels.len()];
+    let mut subrectangles = Vec::new();
+
+    for y in 0..height {
+        for x in 0..width {
+            let colour = at(x, y);
+            if covered[y * width + x] || colour == background {
+                continue;
+            }
+            let row = |y: usize, x1: usize| (x..x1).all(|x| at(x, y) == colour);
+            let column = |x: usize, y1: usize| (y..y1).all(|y| at(x, y) == colour);
+
+            // Rows as wide as possible, or columns as high as possible.
+            let w = (x..width).take_while(|&x| at(x, y) == colour).count();
+            let h = (y..height).take_while(|&y| row(y, x + w)).count();
+            let tall = (y..height).take_while(|&y| at(x, y) == colour).count();
+            let wide = (x..width).take_while(|&x| column(x, y + tall)).count();
+            let (w, h) = match w * h >= wide * tall {
+                true => (w, h),
+                false => (wide, tall),
+            };
+
+            for row in y..y + h {
+                covered[row * width + x..row * width + x + w].fill(true);
+            }
+            subrectangles.push(Subrectangle {
+                x,
+                y,
+                width: w,
+                height: h,
+            });
+        }
+    }
+    subrectangles
+}
@@ -0,0 +1,142 @@
+use std::io::{Read, Write};
+
+use super::{background, cover, fill};
+use crate::error::Error;
+use crate::io::*;
+
+/// Largest side of a CoRRE rectangle, whose subrectangles use u8 geometry.
+pub const CORRE_MAX_SIZE: u16 = 255;
+
+/// Decodes RRE, or CoRRE when `compact`.
+pub fn decode<R: Read>(
+    reader: &mut R,
+    bytes_per_pixel: usize,
+    width: u16,
+    height: u16,
+    compact: bool,
+) -> Result<Vec<u8>, Error> {
+    let (width, height) = (width as usize, height as usize);
+    let count = u32::decode_from(reader)?;
+    let mut pixel = vec![0; bytes_per_pixel];
+    reader.read_exact(&mut pixel)?;
+    let mut data = pixel.repeat(width * height);
+    for _ in 0..count {
+        reader.read_exact(&mut pixel)?;
+        let [x, y, w, h] = match compact {
+            true => <[u8; 4]>::decode_from(reader)?.map(usize::from),
+            false => <[u16; 4]>::decode_from(reader)?.map(usize::from),
+        };
+        if x + w > width || y + h > height {
+            return Err(Error::BadResponse);
+        }
+        fill(&mut data, width, (x, y, w, h), &pixel);
+    }
+    Ok(data)
+}
+
+/// Encodes RRE, or CoRRE when `compact` in which case the rectangle must not
+/// be larger than [`CORRE_MAX_SIZE`].
+pub fn encode<W: Write>(
+    writer: &mut W,
+    bytes_per_pixel: usize,
+    width: u16,
+    height: u16,
+    data: &[u8],
+    compact: bool,
+) -> Result<usize, Error> {
+    if compact && (width > CORRE_MAX_SIZE || height > CORRE_MAX_SIZE) {
+        return Err(Error::LengthTooBig);
+    }
+    let background = background(data, bytes_per_pixel);
+    let subrectangles = cover(data, bytes_per_pixel, width as usize, background);
+
+    let mut buf = Vec::new();
+    u32::try_from(subrectangles.len())?.encode_to(&mut buf)?;
+    buf.extend_from_slice(background);
+    for s in subrectangles {
+        let start = (s.y * width as usize + s.x) * bytes_per_pixel;
+        buf.extend_from_slice(&data[start..start + bytes_per_pixel]);
+        match compact {
+            true => [s.x, s.y, s.width, s.height]
+                .map(|v| v as u8)
+                .encode_to(&mut buf)?,
+            false => [s.x, s.y, s.width, s.height]
+                .map(|v| v as u16)
+                .encode_to(&mut buf)?,
+        };
+    }
+    writer.write_all(&buf)?;
+    Ok(buf.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4x3 rectangle of 16-bit pixels: background 0x0001 with a 2x2 square
+    /// of 0x0202 and a single 0x0303 pixel.
+    fn rectangle() -> Vec<u8> {
+        [[1, 1, 1, 1], [1, 0x202, 0x202, 1], [1, 0x202, 0x202, 0x303]]
+            .into_iter()
+            .flatten()
+            .flat_map(|p: u16| p.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn corre_golden_bytes() {
+        let data = rectangle();
+        let mut buf = Vec::new();
+        assert_eq!(encode(&mut buf, 2, 4, 3, &data, true).unwrap(), 18);
+        assert_eq!(
+            buf,
+            [
+                0, 0, 0, 2, // subrectangles
+                1, 0, // background
+                2, 2, 1, 1, 2, 2, // square
+                3, 3, 3, 2, 1, 1, // pixel
+            ]
+        );
+        assert_eq!(decode(&mut &buf[..], 2, 4, 3, true).unwrap(), data);
+    }
+
+    #[test]
+    fn rre_golden_bytes() {
+        let data: Vec<u8> = rectangle()
+            .chunks(2)
+            .flat_map(|p| [p[0], p[1], 0, 0xff])
+            .collect();
+        let mut buf = Vec::new();
+        assert_eq!(encode(&mut buf, 4, 4, 3, &data, false).unwrap(), 32);
+        assert_eq!(
+            buf,
+            [
+                0, 0, 0, 2, // subrectangles
+                1, 0, 0, 0xff, // background
+                2, 2, 0, 0xff, 0, 1, 0, 1, 0, 2, 0, 2, // square
+                3, 3, 0, 0xff, 0, 3, 0, 2, 0, 1, 0, 1, // pixel
+            ]
+        );
+        assert_eq!(decode(&mut &buf[..], 4, 4, 3, false).unwrap(), data);
+    }
+
+    #[test]
+    fn flat_ui() {
+        // A window with a title bar and a button, at 8 bits per pixel.
+        let (width, height) = (40, 30);
+        let mut data = vec![7; width * height];
+        fill(&mut data, width, (2, 2, 36, 26), &[1]);
+        fill(&mut data, width, (2, 2, 36, 4), &[2]);
+        fill(&mut data, width, (30, 20, 6, 4), &[3]);
+        let mut buf = Vec::new();
+        encode(&mut buf, 1, 40, 30, &data, false).unwrap();
+        // Background 1, then the frame as 4 sides, the title bar and button.
+        assert_eq!(u32::decode_from(&mut &buf[..]).unwrap(), 6);
+        assert_eq!(buf[4], 1);
+        assert_eq!(decode(&mut &buf[..], 1, 40, 30, false).unwrap(), data);
+
+        assert!(encode(&mut Vec::new(), 1, 256, 1, &[0; 256], true).is_err());
+        let outside = [0, 0, 0, 1, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 1];
+        assert!(decode(&mut &outside[..], 1, 3, 3, false).is_err());
+    }
+}
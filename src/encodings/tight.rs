@@ -0,0 +1,542 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use super::pixels;
+use super::zlib::ZlibStream;
+use crate::error::Error;
+use crate::io::*;
+use crate::messages::PixelFormat;
+
+/// Widest rectangle the encoder sends, as decoders of basic compression
+/// expect.
+pub const MAX_WIDTH: u16 = 2048;
+
+const FILL: u8 = 0x80;
+const JPEG: u8 = 0x90;
+const EXPLICIT_FILTER: u8 = 0x40;
+
+const COPY_FILTER: u8 = 0;
+const PALETTE_FILTER: u8 = 1;
+const GRADIENT_FILTER: u8 = 2;
+
+/// Data shorter than this is sent without compression.
+const MIN_TO_COMPRESS: usize = 12;
+/// Largest palette of the palette filter.
+const PALETTE_SIZE: usize = 256;
+/// Fewest colours of the rectangles sent as JPEG when the client set a
+/// quality level.
+const JPEG_MIN_COLOURS: usize = 16;
+/// JPEG qualities of the quality levels.
+const JPEG_QUALITY: [u8; 10] = [15, 29, 41, 42, 62, 77, 79, 86, 92, 100];
+
+const FULL_COLOUR_STREAM: usize = 0;
+const MONO_STREAM: usize = 1;
+const PALETTE_STREAM: usize = 2;
+
+/// How pixels are sent: as TPIXELs of red, green and blue bytes for 32 bits
+/// formats of depth 24 with 8 bits components, as is otherwise.
+struct TPixel<'a> {
+    pixel_format: &'a PixelFormat,
+    bytes_per_pixel: usize,
+    compact: bool,
+}
+
+impl<'a> TPixel<'a> {
+    fn new(pixel_format: &'a PixelFormat) -> Result<Self, Error> {
+        let compact = pixel_format.true_colour_flag
+            && pixel_format.bits_per_pixel == 32
+            && pixel_format.depth == 24
+            && [
+                pixel_format.red_max,
+                pixel_format.green_max,
+                pixel_format.blue_max,
+            ] == [255; 3];
+        Ok(Self {
+            pixel_format,
+            bytes_per_pixel: pixel_format.bytes_per_pixel()?,
+            compact,
+        })
+    }
+
+    fn len(&self) -> usize {
+        match self.compact {
+            true => 3,
+            false => self.bytes_per_pixel,
+        }
+    }
+
+    fn components(&self, bytes: &[u8]) -> [u16; 3] {
+        match self.compact {
+            true => [bytes[0], bytes[1], bytes[2]].map(u16::from),
+            false => self.pixel_format.components(self.pixel_format.value(bytes)),
+        }
+    }
+
+    fn pixel(&self, components: [u16; 3]) -> Vec<u8> {
+        let value = self.pixel_format.from_components(components);
+        self.pixel_format.pixel(value)
+    }
+
+    fn to_pixel(&self, bytes: &[u8]) -> Vec<u8> {
+        match self.compact {
+            true => self.pixel(self.components(bytes)),
+            false => bytes.to_vec(),
+        }
+    }
+
+    fn read<R: Read>(&self, reader: &mut R) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![0; self.len()];
+        reader.read_exact(&mut bytes)?;
+        Ok(self.to_pixel(&bytes))
+    }
+
+    fn write(&self, pixel: &[u8], buf: &mut Vec<u8>) {
+        match self.compact {
+            true => {
+                let components = self.pixel_format.components(self.pixel_format.value(pixel));
+                buf.extend(components.map(|component| component as u8));
+            }
+            false => buf.extend_from_slice(pixel),
+        }
+    }
+}
+
+/// Lengths take 1 to 3 bytes, of 7, 7 and 8 bits, the high bit of the first
+/// two telling whether another byte follows.
+fn read_compact_length<R: Read>(reader: &mut R) -> Result<usize, Error> {
+    let mut len = 0;
+    for shift in [0, 7] {
+        let byte = u8::decode_from(reader)? as usize;
+        len |= (byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(len);
+        }
+    }
+    Ok(len | (u8::decode_from(reader)? as usize) << 14)
+}
+
+fn write_compact_length(len: usize, buf: &mut Vec<u8>) -> Result<(), Error> {
+    if len >= 1 << 22 {
+        return Err(Error::LengthTooBig);
+    }
+    let mut len = len;
+    for _ in 0..2 {
+        if len < 0x80 {
+            buf.push(len as u8);
+            return Ok(());
+        }
+        buf.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    buf.push(len as u8);
+    Ok(())
+}
+
+/// Reads `len` bytes of data, compressed with `stream` unless shorter than
+/// 12 bytes.
+fn read_data<R: Read>(
+    reader: &mut R,
+    stream: &mut ZlibStream,
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    if len < MIN_TO_COMPRESS {
+        let mut buf = vec![0; len];
+        reader.read_exact(&mut buf)?;
+        return Ok(buf);
+    }
+    let mut buf = vec![0; read_compact_length(reader)?];
+    reader.read_exact(&mut buf)?;
+    let data = stream.decompress(&buf, len)?;
+    match data.len() == len {
+        true => Ok(data),
+        false => Err(Error::BadResponse),
+    }
+}
+
+fn write_data(stream: &mut ZlibStream, data: &[u8], buf: &mut Vec<u8>) -> Result<(), Error> {
+    if data.len() < MIN_TO_COMPRESS {
+        buf.extend_from_slice(data);
+        return Ok(());
+    }
+    let compressed = stream.compress(data)?;
+    write_compact_length(compressed.len(), buf)?;
+    buf.extend_from_slice(&compressed);
+    Ok(())
+}
+
+/// Undoes the gradient filter: every component was sent as its difference
+/// with the prediction from the left, upper and upper left pixels.
+fn ungradient(tpixel: &TPixel, data: &[u8], width: usize) -> Result<Vec<u8>, Error> {
+    let format = tpixel.pixel_format;
+    if !format.true_colour_flag {
+        return Err(Error::UnsupportedPixelFormat);
+    }
+    let maxes = [format.red_max, format.green_max, format.blue_max].map(i64::from);
+    let mut pixels = Vec::with_capacity(data.len() / tpixel.len() * tpixel.bytes_per_pixel);
+    let mut above = vec![[0i64; 3]; width];
+    for row in data.chunks_exact(width * tpixel.len()) {
+        let mut current: Vec<[i64; 3]> = Vec::with_capacity(width);
+        for (x, bytes) in row.chunks_exact(tpixel.len()).enumerate() {
+            let error = tpixel.components(bytes);
+            let (left, upper_left) = match x {
+                0 => ([0; 3], [0; 3]),
+                _ => (current[x - 1], above[x - 1]),
+            };
+            let value: [i64; 3] = std::array::from_fn(|i| {
+                let prediction = (left[i] + above[x][i] - upper_left[i]).clamp(0, maxes[i]);
+                (prediction + error[i] as i64) % (maxes[i] + 1)
+            });
+            pixels.extend(tpixel.pixel(value.map(|c| c as u16)));
+            current.push(value);
+        }
+        above = current;
+    }
+    Ok(pixels)
+}
+
+fn decode_jpeg(
+    data: &[u8],
+    pixel_format: &PixelFormat,
+    width: usize,
+    height: usize,
+) -> Result<Vec<u8>, Error> {
+    if !pixel_format.true_colour_flag {
+        return Err(Error::UnsupportedPixelFormat);
+    }
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    // The header is checked first, not to decode an image of any size.
+    decoder.read_info()?;
+    let info = decoder.info().ok_or(Error::BadResponse)?;
+    if (info.width as usize, info.height as usize) != (width, height) {
+        return Err(Error::BadResponse);
+    }
+    let image = decoder.decode()?;
+    match info.pixel_format {
+        jpeg_decoder::PixelFormat::RGB24 => Ok(image
+            .chunks_exact(3)
+            .flat_map(|rgb| pixel_format.from_rgb([rgb[0], rgb[1], rgb[2]]))
+            .collect()),
+        jpeg_decoder::PixelFormat::L8 => Ok(image
+            .iter()
+            .flat_map(|&luma| pixel_format.from_rgb([luma; 3]))
+            .collect()),
+        format => Err(Error::Jpeg(format!("unsupported pixel format {format:?}"))),
+    }
+}
+
+/// Decodes a rectangle, resetting the zlib `streams` the server asks to.
+pub(crate) fn decode<R: Read>(
+    reader: &mut R,
+    streams: &mut [ZlibStream; 4],
+    pixel_format: &PixelFormat,
+    width: u16,
+    height: u16,
+) -> Result<Vec<u8>, Error> {
+    let tpixel = TPixel::new(pixel_format)?;
+    let (width, height) = (width as usize, height as usize);
+    let count = width * height;
+    let control = u8::decode_from(reader)?;
+    for (i, stream) in streams.iter_mut().enumerate() {
+        if control & 1 << i != 0 {
+            *stream = ZlibStream::default();
+        }
+    }
+    match control & 0xf0 {
+        FILL => return Ok(tpixel.read(reader)?.repeat(count)),
+        JPEG => {
+            let mut buf = vec![0; read_compact_length(reader)?];
+            reader.read_exact(&mut buf)?;
+            return decode_jpeg(&buf, pixel_format, width, height);
+        }
+        compression if compression & 0x80 != 0 => return Err(Error::BadResponse),
+        _ => {}
+    }
+
+    let stream = &mut streams[(control >> 4 & 3) as usize];
+    let filter = match control & EXPLICIT_FILTER {
+        0 => COPY_FILTER,
+        _ => u8::decode_from(reader)?,
+    };
+    match filter {
+        COPY_FILTER => {
+            let data = read_data(reader, stream, count * tpixel.len())?;
+            Ok(data
+                .chunks_exact(tpixel.len())
+                .flat_map(|bytes| tpixel.to_pixel(bytes))
+                .collect())
+        }
+        PALETTE_FILTER => {
+            let size = u8::decode_from(reader)? as usize + 1;
+            let palette = (0..size)
+                .map(|_| tpixel.read(reader))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut pixels = Vec::with_capacity(count * tpixel.bytes_per_pixel);
+            if size == 2 {
+                // One bit per pixel, rows padded to a byte.
+                let row = width.div_ceil(8);
+                let data = read_data(reader, stream, row * height)?;
+                for y in 0..height {
+                    for x in 0..width {
+                        let bit = data[y * row + x / 8] >> (7 - x % 8) & 1;
+                        pixels.extend_from_slice(&palette[bit as usize]);
+                    }
+                }
+            } else {
+                for index in read_data(reader, stream, count)? {
+                    let pixel = palette.get(index as usize).ok_or(Error::BadResponse)?;
+                    pixels.extend_from_slice(pixel);
+                }
+            }
+            Ok(pixels)
+        }
+        GRADIENT_FILTER => {
+            let data = read_data(reader, stream, count * tpixel.len())?;
+            ungradient(&tpixel, &data, width)
+        }
+        _ => Err(Error::BadResponse),
+    }
+}
+
+/// Encodes a rectangle as a fill, as JPEG if the client set a `quality`
+/// level and it has many colours, or with basic compression at the client's
+/// compression `level` and the palette filter if it has few colours.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn encode<W: Write>(
+    writer: &mut W,
+    streams: &mut [ZlibStream; 4],
+    pixel_format: &PixelFormat,
+    width: u16,
+    height: u16,
+    data: &[u8],
+    quality: Option<u8>,
+    level: Option<u8>,
+) -> Result<usize, Error> {
+    let tpixel = TPixel::new(pixel_format)?;
+    let mut indices: HashMap<&[u8], u8> = HashMap::new();
+    let mut colours = Vec::new();
+    let mut too_many = false;
+    for pixel in pixels(data, tpixel.bytes_per_pixel) {
+        if !indices.contains_key(pixel) {
+            if colours.len() == PALETTE_SIZE {
+                too_many = true;
+                break;
+            }
+            indices.insert(pixel, colours.len() as u8);
+            colours.push(pixel);
+        }
+    }
+
+    let mut buf = Vec::new();
+    let jpeg = quality.filter(|_| {
+        pixel_format.true_colour_flag
+            && tpixel.bytes_per_pixel > 1
+            && (too_many || colours.len() > JPEG_MIN_COLOURS)
+    });
+    if colours.len() <= 1 {
+        // Empty rectangles have no colour, they are sent as a fill of black.
+        let black = vec![0; tpixel.bytes_per_pixel];
+        buf.push(FILL);
+        tpixel.write(colours.first().copied().unwrap_or(&black), &mut buf);
+    } else if let Some(quality) = jpeg {
+        let rgb: Vec<u8> = pixels(data, tpixel.bytes_per_pixel)
+            .flat_map(|pixel| pixel_format.rgb(pixel))
+            .collect();
+        let mut jpeg = Vec::new();
+        let quality = JPEG_QUALITY[quality.min(9) as usize];
+        jpeg_encoder::Encoder::new(&mut jpeg, quality).encode(
+            &rgb,
+            width,
+            height,
+            jpeg_encoder::ColorType::Rgb,
+        )?;
+        buf.push(JPEG);
+        write_compact_length(jpeg.len(), &mut buf)?;
+        buf.extend_from_slice(&jpeg);
+    } else {
+        let id = match colours.len() {
+            _ if too_many => FULL_COLOUR_STREAM,
+            2 => MONO_STREAM,
+            _ => PALETTE_STREAM,
+        };
+        // Streams are reset to change their level.
+        let level = level.map_or(ZlibStream::default().level(), |level| level.min(9) as u32);
+        let mut control = (id as u8) << 4;
+        if streams[id].level() != level {
+            streams[id] = ZlibStream::new(level);
+            control |= 1 << id;
+        }
+        let mut filtered = Vec::new();
+        if id == FULL_COLOUR_STREAM {
+            buf.push(control);
+            for pixel in pixels(data, tpixel.bytes_per_pixel) {
+                tpixel.write(pixel, &mut filtered);
+            }
+        } else {
+            buf.extend_from_slice(&[control | EXPLICIT_FILTER, PALETTE_FILTER]);
+            buf.push((colours.len() - 1) as u8);
+            colours
+                .iter()
+                .for_each(|colour| tpixel.write(colour, &mut buf));
+            let row_len = width as usize * tpixel.bytes_per_pixel;
+            for row in data.chunks_exact(row_len) {
+                let row = pixels(row, tpixel.bytes_per_pixel).map(|pixel| indices[pixel]);
+                match id {
+                    MONO_STREAM => {
+                        let mut bits = vec![0; (width as usize).div_ceil(8)];
+                        for (x, index) in row.enumerate() {
+                            bits[x / 8] |= index << (7 - x % 8);
+                        }
+                        filtered.extend_from_slice(&bits);
+                    }
+                    _ => filtered.extend(row),
+                }
+            }
+        }
+        write_data(&mut streams[id], &filtered, &mut buf)?;
+    }
+    writer.write_all(&buf)?;
+    Ok(buf.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::tests::server_init;
+    use crate::messages::framebuffer_update::tests::pixel_format;
+
+    fn streams() -> [ZlibStream; 4] {
+        std::array::from_fn(|_| ZlibStream::default())
+    }
+
+    #[test]
+    fn compact_lengths() {
+        for (len, bytes) in [
+            (10, &[10][..]),
+            (200, &[0xc8, 0x01]),
+            (20000, &[0xa0, 0x9c, 0x01]),
+            ((1 << 22) - 1, &[0xff, 0xff, 0xff]),
+        ] {
+            let mut buf = Vec::new();
+            write_compact_length(len, &mut buf).unwrap();
+            assert_eq!(buf, bytes);
+            assert_eq!(read_compact_length(&mut &buf[..]).unwrap(), len);
+        }
+        assert!(write_compact_length(1 << 22, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn golden_rectangles() {
+        // Blue is the lowest byte of the pixels of the session format.
+        let format = server_init().pixel_format;
+        let pixel = |rgb: [u8; 3]| [rgb[2], rgb[1], rgb[0], 0];
+        let (red, blue) = (pixel([255, 0, 0]), pixel([0, 0, 255]));
+        // Gradient filter: the last pixel is predicted as blue, 12 bytes of
+        // data being compressed.
+        let gradient = [255, 0, 0, 1, 0, 255, 1, 0, 255, 255, 0, 1];
+        let gradient = ZlibStream::default().compress(&gradient).unwrap();
+        let gradient = [
+            &[0x40, GRADIENT_FILTER, gradient.len() as u8],
+            &gradient[..],
+        ]
+        .concat();
+        let cases = [
+            (2, vec![FILL, 255, 0, 0], vec![red; 4]),
+            // Copy filter, not compressed as shorter than 12 bytes.
+            (
+                3,
+                vec![0x00, 255, 0, 0, 0, 0, 255, 0, 0, 255],
+                vec![red, blue, blue],
+            ),
+            // Palette filter, one bit per pixel.
+            (
+                2,
+                vec![0x50, PALETTE_FILTER, 1, 255, 0, 0, 0, 0, 255, 0x40, 0x80],
+                vec![red, blue, blue, red],
+            ),
+            (2, gradient, vec![red, blue, blue, red]),
+        ];
+        for (width, bytes, pixels) in cases {
+            let height = pixels.len() as u16 / width;
+            let decoded = decode(&mut &bytes[..], &mut streams(), &format, width, height);
+            assert_eq!(decoded.unwrap(), pixels.concat(), "{bytes:x?}");
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let formats = [
+            pixel_format(8),
+            pixel_format(16),
+            pixel_format(32),
+            server_init().pixel_format,
+        ];
+        for format in formats {
+            let bytes_per_pixel = format.bytes_per_pixel().unwrap();
+            let (mut server, mut client) = (streams(), streams());
+            // A fill, two colours, a palette and too many colours, at two
+            // compression levels so that streams are reset.
+            for (colours, level) in [
+                (1, None),
+                (2, None),
+                (40, None),
+                (300, None),
+                (300, Some(1)),
+            ] {
+                let data: Vec<u8> = (0..37 * 19usize)
+                    .flat_map(|i| {
+                        let value = (i * 7 % colours) as u32;
+                        let value = (value % 256) | ((value / 256) << format.green_shift);
+                        format.pixel(value & format.from_components([u16::MAX; 3]))
+                    })
+                    .collect();
+                let mut buf = Vec::new();
+                encode(&mut buf, &mut server, &format, 37, 19, &data, None, level).unwrap();
+                assert_eq!(buf[0] & 0x0f != 0, level.is_some(), "{colours} colours");
+                let decoded = decode(&mut &buf[..], &mut client, &format, 37, 19).unwrap();
+                assert_eq!(decoded, data, "{bytes_per_pixel} bytes, {colours} colours");
+            }
+            let mut buf = Vec::new();
+            encode(&mut buf, &mut server, &format, 0, 0, &[], None, None).unwrap();
+            assert_eq!(buf[0], FILL);
+            assert!(decode(&mut &buf[..], &mut client, &format, 0, 0)
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn jpeg() {
+        let format = server_init().pixel_format;
+        let (width, height) = (40, 24);
+        let data: Vec<u8> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| format.from_rgb([x * 6, y * 10, 128]))
+            .collect();
+        let mut buf = Vec::new();
+        let (width, height) = (width as u16, height as u16);
+        encode(
+            &mut buf,
+            &mut streams(),
+            &format,
+            width,
+            height,
+            &data,
+            Some(9),
+            None,
+        )
+        .unwrap();
+        assert_eq!(buf[0], JPEG);
+        assert!(matches!(
+            decode(&mut &buf[..], &mut streams(), &format, width, height - 1),
+            Err(Error::BadResponse)
+        ));
+        let decoded = decode(&mut &buf[..], &mut streams(), &format, width, height).unwrap();
+        assert_eq!(decoded.len(), data.len());
+        for (decoded, pixel) in pixels(&decoded, 4).zip(pixels(&data, 4)) {
+            let (decoded, pixel) = (format.rgb(decoded), format.rgb(pixel));
+            for (a, b) in decoded.iter().zip(pixel) {
+                assert!(a.abs_diff(b) <= 8, "{decoded:?} {pixel:?}");
+            }
+        }
+    }
+}
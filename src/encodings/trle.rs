@@ -0,0 +1,491 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::ops::Range;
+
+use super::{area, paste, pixels, tiles};
+use crate::error::Error;
+use crate::io::*;
+use crate::messages::PixelFormat;
+
+const TILE_SIZE: usize = 16;
+
+const RAW: u8 = 0;
+const SOLID: u8 = 1;
+const REUSED_PACKED_PALETTE: u8 = 127;
+const PLAIN_RLE: u8 = 128;
+const REUSED_PALETTE_RLE: u8 = 129;
+const PALETTE_RLE: u8 = 128;
+
+/// Largest palette of the packed palette subencodings.
+const PACKED_PALETTE_SIZE: usize = 16;
+/// Largest palette of the palette RLE subencodings.
+const RLE_PALETTE_SIZE: usize = 127;
+
+/// How pixels are sent in tiles: all the bytes of the pixel or only those
+/// holding its colour.
+#[derive(Debug, Clone)]
+pub(crate) struct CPixel {
+    bytes_per_pixel: usize,
+    bytes: Range<usize>,
+}
+
+impl CPixel {
+    pub(crate) fn new(pixel_format: &PixelFormat) -> Result<Self, Error> {
+        Ok(Self {
+            bytes_per_pixel: pixel_format.bytes_per_pixel()?,
+            bytes: pixel_format.compressed_pixel_bytes()?,
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn read<R: Read>(&self, reader: &mut R) -> Result<Vec<u8>, Error> {
+        let mut pixel = vec![0; self.bytes_per_pixel];
+        reader.read_exact(&mut pixel[self.bytes.clone()])?;
+        Ok(pixel)
+    }
+}
+
+/// Bits of a packed palette index.
+fn index_bits(palette_size: usize) -> usize {
+    match palette_size {
+        2 => 1,
+        3..=4 => 2,
+        _ => 4,
+    }
+}
+
+fn read_palette<R: Read>(
+    reader: &mut R,
+    cpixel: &CPixel,
+    size: usize,
+) -> Result<Vec<Vec<u8>>, Error> {
+    (0..size).map(|_| cpixel.read(reader)).collect()
+}
+
+/// Run lengths are sent minus one as bytes of 255 followed by the rest.
+fn read_run_length<R: Read>(reader: &mut R, max: usize) -> Result<usize, Error> {
+    let mut len = 1;
+    loop {
+        let byte = u8::decode_from(reader)?;
+        len += byte as usize;
+        if len > max {
+            return Err(Error::BadResponse);
+        }
+        if byte != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+fn write_run_length(len: usize, buf: &mut Vec<u8>) {
+    let rest = len - 1;
+    buf.extend(std::iter::repeat_n(255, rest / 255));
+    buf.push((rest % 255) as u8);
+}
+
+fn run_length_len(len: usize) -> usize {
+    (len - 1) / 255 + 1
+}
+
+fn read_packed<R: Read>(
+    reader: &mut R,
+    palette: &[Vec<u8>],
+    width: usize,
+    height: usize,
+    pixels: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let bits = index_bits(palette.len());
+    let mut row = vec![0; (width * bits).div_ceil(8)];
+    for _ in 0..height {
+        reader.read_exact(&mut row)?;
+        for x in 0..width {
+            let bit = x * bits;
+            let index = (row[bit / 8] >> (8 - bits - bit % 8)) & ((1 << bits) - 1);
+            pixels.extend(palette.get(index as usize).ok_or(Error::BadResponse)?);
+        }
+    }
+    Ok(())
+}
+
+fn read_palette_rle<R: Read>(
+    reader: &mut R,
+    palette: &[Vec<u8>],
+    count: usize,
+    pixels: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let mut done = 0;
+    while done < count {
+        let index = u8::decode_from(reader)?;
+        let run = match index & 128 {
+            0 => 1,
+            _ => read_run_length(reader, count - done)?,
+        };
+        let pixel = palette
+            .get((index & 127) as usize)
+            .ok_or(Error::BadResponse)?;
+        pixels.extend(pixel.repeat(run));
+        done += run;
+    }
+    Ok(())
+}
+
+/// Decodes a tile, `palette` being the last one received. TRLE tiles may
+/// reuse it if `reuse` is set, ZRLE ones may not.
+fn decode_tile<R: Read>(
+    reader: &mut R,
+    cpixel: &CPixel,
+    width: usize,
+    height: usize,
+    palette: &mut Vec<Vec<u8>>,
+    reuse: bool,
+) -> Result<Vec<u8>, Error> {
+    let count = width * height;
+    let mut pixels = Vec::with_capacity(count * cpixel.bytes_per_pixel);
+    match u8::decode_from(reader)? {
+        RAW => {
+            for _ in 0..count {
+                pixels.extend(cpixel.read(reader)?);
+            }
+        }
+        SOLID => pixels = cpixel.read(reader)?.repeat(count),
+        size @ 2..=16 => {
+            *palette = read_palette(reader, cpixel, size as usize)?;
+            read_packed(reader, palette, width, height, &mut pixels)?;
+        }
+        REUSED_PACKED_PALETTE if reuse && (2..=PACKED_PALETTE_SIZE).contains(&palette.len()) => {
+            read_packed(reader, palette, width, height, &mut pixels)?;
+        }
+        PLAIN_RLE => {
+            let mut done = 0;
+            while done < count {
+                let pixel = cpixel.read(reader)?;
+                let run = read_run_length(reader, count - done)?;
+                pixels.extend(pixel.repeat(run));
+                done += run;
+            }
+        }
+        REUSED_PALETTE_RLE if reuse && !palette.is_empty() => {
+            read_palette_rle(reader, palette, count, &mut pixels)?;
+        }
+        subencoding @ 130.. => {
+            let size = (subencoding - PALETTE_RLE) as usize;
+            *palette = read_palette(reader, cpixel, size)?;
+            read_palette_rle(reader, palette, count, &mut pixels)?;
+        }
+        _ => return Err(Error::BadResponse),
+    }
+    Ok(pixels)
+}
+
+fn write_packed(indices: &[u8], width: usize, bits: usize, buf: &mut Vec<u8>) {
+    for row in indices.chunks_exact(width) {
+        let mut packed = vec![0; (width * bits).div_ceil(8)];
+        for (x, index) in row.iter().enumerate() {
+            let bit = x * bits;
+            packed[bit / 8] |= index << (8 - bits - bit % 8);
+        }
+        buf.extend_from_slice(&packed);
+    }
+}
+
+fn write_palette_rle(runs: &[(&[u8], usize)], palette: &HashMap<&[u8], u8>, buf: &mut Vec<u8>) {
+    for &(pixel, len) in runs {
+        match len {
+            1 => buf.push(palette[pixel]),
+            _ => {
+                buf.push(palette[pixel] | 128);
+                write_run_length(len, buf);
+            }
+        }
+    }
+}
+
+/// Encodes a tile with the smallest subencoding, `palette` being the last
+/// one sent, which is reused when it holds all the colours of the tile and
+/// `reuse` is set.
+fn encode_tile(
+    cpixel: &CPixel,
+    data: &[u8],
+    width: usize,
+    palette: &mut Vec<Vec<u8>>,
+    reuse: bool,
+    buf: &mut Vec<u8>,
+) {
+    let tile: Vec<&[u8]> = pixels(data, cpixel.bytes_per_pixel)
+        .map(|pixel| &pixel[cpixel.bytes.clone()])
+        .collect();
+    let mut indices: HashMap<&[u8], u8> = HashMap::new();
+    let mut colours = Vec::new();
+    let mut runs: Vec<(&[u8], usize)> = Vec::new();
+    for &pixel in &tile {
+        match runs.last_mut() {
+            Some((last, len)) if *last == pixel => *len += 1,
+            _ => runs.push((pixel, 1)),
+        }
+        if colours.len() <= RLE_PALETTE_SIZE && !indices.contains_key(pixel) {
+            indices.insert(pixel, colours.len() as u8);
+            colours.push(pixel);
+        }
+    }
+    if colours.len() == 1 {
+        buf.push(SOLID);
+        buf.extend_from_slice(colours[0]);
+        return;
+    }
+
+    let c = cpixel.len();
+    let height = tile.len() / width;
+    let packed_len = |size: usize| match size <= PACKED_PALETTE_SIZE {
+        true => height * (width * index_bits(size)).div_ceil(8),
+        false => usize::MAX,
+    };
+    let runs_len: usize = runs
+        .iter()
+        .map(|&(_, len)| if len == 1 { 1 } else { 1 + run_length_len(len) })
+        .sum();
+
+    let reused: HashMap<&[u8], u8> = palette
+        .iter()
+        .enumerate()
+        .map(|(i, colour)| (colour.as_slice(), i as u8))
+        .collect();
+    let reusable = reuse && colours.iter().all(|colour| reused.contains_key(colour));
+    let (reused_packed, reused_rle) = match reusable {
+        true => (packed_len(palette.len()), runs_len),
+        false => (usize::MAX, usize::MAX),
+    };
+    let (packed, palette_rle) = match colours.len() <= RLE_PALETTE_SIZE {
+        true => (
+            (colours.len() * c).saturating_add(packed_len(colours.len())),
+            colours.len() * c + runs_len,
+        ),
+        false => (usize::MAX, usize::MAX),
+    };
+    let plain_rle = runs.iter().map(|&(_, len)| c + run_length_len(len)).sum();
+    let raw = tile.len() * c;
+
+    let smallest = [
+        reused_packed,
+        reused_rle,
+        packed,
+        palette_rle,
+        plain_rle,
+        raw,
+    ]
+    .into_iter()
+    .min()
+    .unwrap();
+    if smallest == reused_packed {
+        buf.push(REUSED_PACKED_PALETTE);
+        let indices: Vec<u8> = tile.iter().map(|pixel| reused[pixel]).collect();
+        write_packed(&indices, width, index_bits(palette.len()), buf);
+    } else if smallest == reused_rle {
+        buf.push(REUSED_PALETTE_RLE);
+        write_palette_rle(&runs, &reused, buf);
+    } else if smallest == packed || smallest == palette_rle {
+        match smallest == packed {
+            true => buf.push(colours.len() as u8),
+            false => buf.push(PALETTE_RLE + colours.len() as u8),
+        }
+        colours
+            .iter()
+            .for_each(|colour| buf.extend_from_slice(colour));
+        match smallest == packed {
+            true => {
+                let indices: Vec<u8> = tile.iter().map(|pixel| indices[pixel]).collect();
+                write_packed(&indices, width, index_bits(colours.len()), buf);
+            }
+            false => write_palette_rle(&runs, &indices, buf),
+        }
+        *palette = colours.iter().map(|colour| colour.to_vec()).collect();
+    } else if smallest == plain_rle {
+        buf.push(PLAIN_RLE);
+        for (pixel, len) in runs {
+            buf.extend_from_slice(pixel);
+            write_run_length(len, buf);
+        }
+    } else {
+        buf.push(RAW);
+        tile.iter().for_each(|pixel| buf.extend_from_slice(pixel));
+    }
+}
+
+/// Decodes the tiles of `tile_size` pixels a side of a rectangle, with the
+/// palette reuse of TRLE if `reuse` is set.
+pub(crate) fn decode_tiles<R: Read>(
+    reader: &mut R,
+    cpixel: &CPixel,
+    width: usize,
+    height: usize,
+    tile_size: usize,
+    reuse: bool,
+) -> Result<Vec<u8>, Error> {
+    let mut data = vec![0; width * height * cpixel.bytes_per_pixel];
+    let mut palette = Vec::new();
+    for tile in tiles(width, height, tile_size) {
+        let (_, _, w, h) = tile;
+        let pixels = decode_tile(reader, cpixel, w, h, &mut palette, reuse)?;
+        paste(&mut data, width, tile, &pixels, cpixel.bytes_per_pixel);
+    }
+    Ok(data)
+}
+
+pub(crate) fn encode_tiles(
+    cpixel: &CPixel,
+    width: usize,
+    height: usize,
+    data: &[u8],
+    tile_size: usize,
+    reuse: bool,
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let mut palette = Vec::new();
+    for tile in tiles(width, height, tile_size) {
+        let (_, _, w, _) = tile;
+        let pixels = area(data, width, tile, cpixel.bytes_per_pixel);
+        encode_tile(cpixel, &pixels, w, &mut palette, reuse, &mut buf);
+    }
+    buf
+}
+
+pub fn decode<R: Read>(
+    reader: &mut R,
+    pixel_format: &PixelFormat,
+    width: u16,
+    height: u16,
+) -> Result<Vec<u8>, Error> {
+    let cpixel = CPixel::new(pixel_format)?;
+    decode_tiles(
+        reader,
+        &cpixel,
+        width as usize,
+        height as usize,
+        TILE_SIZE,
+        true,
+    )
+}
+
+pub fn encode<W: Write>(
+    writer: &mut W,
+    pixel_format: &PixelFormat,
+    width: u16,
+    height: u16,
+    data: &[u8],
+) -> Result<usize, Error> {
+    let cpixel = CPixel::new(pixel_format)?;
+    let buf = encode_tiles(
+        &cpixel,
+        width as usize,
+        height as usize,
+        data,
+        TILE_SIZE,
+        true,
+    );
+    writer.write_all(&buf)?;
+    Ok(buf.len())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::messages::framebuffer_update::tests::pixel_format;
+
+    #[test]
+    fn subencodings() {
+        let cpixel = CPixel::new(&pixel_format(32)).unwrap();
+        let tile =
+            |pixels: &[u8]| -> Vec<u8> { pixels.iter().flat_map(|&p| [p, 0, 0, 0]).collect() };
+        let cases: [(&[u8], &[u8]); 5] = [
+            (&[RAW, 1, 0, 0, 2, 0, 0, 3, 0, 0, 4, 0, 0], &[1, 2, 3, 4]),
+            (&[SOLID, 7, 0, 0], &[7, 7, 7, 7]),
+            (
+                &[2, 5, 0, 0, 6, 0, 0, 0b1000_0000, 0b0100_0000],
+                &[6, 5, 5, 6],
+            ),
+            (&[PLAIN_RLE, 9, 0, 0, 2, 8, 0, 0, 0], &[9, 9, 9, 8]),
+            (
+                &[PALETTE_RLE + 2, 5, 0, 0, 6, 0, 0, 1, 128, 1, 1],
+                &[6, 5, 5, 6],
+            ),
+        ];
+        for (bytes, pixels) in cases {
+            let decoded = decode_tiles(&mut &bytes[..], &cpixel, 2, 2, TILE_SIZE, false);
+            assert_eq!(decoded.unwrap(), tile(pixels), "{bytes:?}");
+        }
+        assert!(decode_tiles(&mut &[20u8][..], &cpixel, 2, 2, TILE_SIZE, true).is_err());
+        // A run going past the tile.
+        let bytes = [PLAIN_RLE, 9, 0, 0, 4];
+        assert!(decode_tiles(&mut &bytes[..], &cpixel, 2, 2, TILE_SIZE, true).is_err());
+    }
+
+    #[test]
+    fn reused_palettes() {
+        let cpixel = CPixel::new(&pixel_format(8)).unwrap();
+        // Three 2x1 tiles: a packed palette, then tiles reusing it packed and
+        // as runs.
+        let bytes = [2, 5, 6, 0b0100_0000, REUSED_PACKED_PALETTE, 0b1000_0000];
+        let bytes = [&bytes[..], &[REUSED_PALETTE_RLE, 128, 1]].concat();
+        let decoded = decode_tiles(&mut &bytes[..], &cpixel, 6, 1, 2, true).unwrap();
+        assert_eq!(decoded, [5, 6, 6, 5, 5, 5]);
+        // ZRLE has no palette reuse, nor can the first tile reuse one.
+        assert!(decode_tiles(&mut &bytes[..], &cpixel, 6, 1, 2, false).is_err());
+        assert!(decode_tiles(&mut &bytes[4..], &cpixel, 4, 1, 2, true).is_err());
+
+        // Two 4x4 checkerboards of the same colours.
+        let data: Vec<u8> = (0..32).map(|i| 5 + (i + i / 8) as u8 % 2).collect();
+        let rows = [0b0101_0000, 0b1010_0000, 0b0101_0000, 0b1010_0000];
+        let expected = [&[2, 5, 6][..], &rows, &[REUSED_PACKED_PALETTE], &rows].concat();
+        assert_eq!(encode_tiles(&cpixel, 8, 4, &data, 4, true), expected);
+        assert_eq!(
+            decode_tiles(&mut &expected[..], &cpixel, 8, 4, 4, true).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn long_runs() {
+        let mut buf = Vec::new();
+        write_run_length(600, &mut buf);
+        assert_eq!(buf, [255, 255, 89]);
+        assert_eq!(read_run_length(&mut &buf[..], 4096).unwrap(), 600);
+        assert!(read_run_length(&mut &buf[..], 599).is_err());
+    }
+
+    /// Flat areas, few colours, long runs and noise, so that every
+    /// subencoding is used, in tiles cut by the rectangle edges.
+    pub(crate) fn frame(width: usize, height: usize, bytes_per_pixel: usize) -> Vec<u8> {
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y): (usize, usize)| {
+                let value = match (x / 64, y / 64) {
+                    (0, 0) => ((x / 8 + y / 8) % 3) as u8,
+                    (1, 0) => (y % 40) as u8 + (x > 100) as u8,
+                    (2, 0) => ((x * 7919 + y * 104729) % 251) as u8,
+                    (0, 1) => (x % 11) as u8,
+                    _ => 42,
+                };
+                let mut pixel = vec![value; bytes_per_pixel];
+                if bytes_per_pixel == 4 {
+                    pixel[3] = 0;
+                }
+                pixel
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for bits_per_pixel in [8, 16, 32] {
+            let format = pixel_format(bits_per_pixel);
+            let data = frame(150, 70, format.bytes_per_pixel().unwrap());
+            let mut buf = Vec::new();
+            encode(&mut buf, &format, 150, 70, &data).unwrap();
+            assert!(buf.len() < data.len());
+            let decoded = decode(&mut &buf[..], &format, 150, 70).unwrap();
+            assert_eq!(decoded, data, "{bits_per_pixel} bits per pixel");
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::io::{Read, Write};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+
+use crate::error::Error;
+use crate::io::*;
+
+/// A zlib stream kept for the whole connection: every rectangle is a sync
+/// flush of it and only inflates after the ones sent before.
+#[derive(Debug)]
+pub(crate) struct ZlibStream {
+    compress: Compress,
+    decompress: Decompress,
+    level: u32,
+}
+
+impl Default for ZlibStream {
+    fn default() -> Self {
+        Self::new(Compression::default().level())
+    }
+}
+
+impl ZlibStream {
+    /// A stream compressing at `level`, from 0 to 9.
+    pub(crate) fn new(level: u32) -> Self {
+        Self {
+            compress: Compress::new(Compression::new(level), true),
+            decompress: Decompress::new(true),
+            level,
+        }
+    }
+
+    pub(crate) fn level(&self) -> u32 {
+        self.level
+    }
+
+    pub(crate) fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let start = self.compress.total_in();
+        let mut buf = Vec::with_capacity(data.len() / 2 + 64);
+        loop {
+            if buf.len() == buf.capacity() {
+                buf.reserve(buf.capacity());
+            }
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&data[consumed..], &mut buf, FlushCompress::Sync)?;
+            // The flush is complete once it leaves room in the buffer.
+            if (self.compress.total_in() - start) as usize == data.len()
+                && buf.len() < buf.capacity()
+            {
+                return Ok(buf);
+            }
+        }
+    }
+
+    /// Inflates `data`, which must not give more than `limit` bytes.
+    pub(crate) fn decompress(&mut self, data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+        let start = self.decompress.total_in();
+        let mut buf = Vec::with_capacity(limit.min(data.len() * 4) + 1);
+        loop {
+            if buf.len() == buf.capacity() {
+                buf.reserve(buf.capacity());
+            }
+            let (consumed, produced) = ((self.decompress.total_in() - start) as usize, buf.len());
+            self.decompress
+                .decompress_vec(&data[consumed..], &mut buf, FlushDecompress::Sync)?;
+            let done = (self.decompress.total_in() - start) as usize;
+            if buf.len() > limit {
+                return Err(Error::BadResponse);
+            }
+            if done == data.len() && buf.len() < buf.capacity() {
+                return Ok(buf);
+            }
+            if done == consumed && buf.len() == produced {
+                return Err(Error::BadResponse);
+            }
+        }
+    }
+}
+
+/// Reads a u32 length and that many bytes of `stream`, inflating to `len`
+/// bytes of raw pixels.
+pub(crate) fn decode<R: Read>(
+    reader: &mut R,
+    stream: &mut ZlibStream,
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0; u32::decode_from(reader)? as usize];
+    reader.read_exact(&mut buf)?;
+    let pixels = stream.decompress(&buf, len)?;
+    match pixels.len() == len {
+        true => Ok(pixels),
+        false => Err(Error::BadResponse),
+    }
+}
+
+pub(crate) fn encode<W: Write>(
+    writer: &mut W,
+    stream: &mut ZlibStream,
+    data: &[u8],
+) -> Result<usize, Error> {
+    let buf = stream.compress(data)?;
+    let len: u32 = buf.len().try_into()?;
+    len.encode_to(writer)?;
+    writer.write_all(&buf)?;
+    Ok(4 + buf.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persistent_stream() {
+        let (mut server, mut client) = (ZlibStream::default(), ZlibStream::default());
+        let first: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        let second: Vec<u8> = first.iter().rev().copied().collect();
+        let mut buf = Vec::new();
+        encode(&mut buf, &mut server, &first).unwrap();
+        let len = buf.len();
+        encode(&mut buf, &mut server, &second).unwrap();
+        // The second rectangle refers to the first one's data.
+        assert!(buf.len() - len < len);
+
+        let mut reader = &buf[..];
+        assert_eq!(
+            decode(&mut reader, &mut client, first.len()).unwrap(),
+            first
+        );
+        assert_eq!(
+            decode(&mut reader, &mut client, second.len()).unwrap(),
+            second
+        );
+
+        // A new stream cannot pick up in the middle.
+        let mut reader = &buf[len..];
+        assert!(decode(&mut reader, &mut ZlibStream::default(), second.len()).is_err());
+    }
+
+    #[test]
+    fn wrong_length() {
+        let mut buf = Vec::new();
+        encode(&mut buf, &mut ZlibStream::default(), &[1; 100]).unwrap();
+        let result = decode(&mut &buf[..], &mut ZlibStream::default(), 99);
+        assert!(matches!(result, Err(Error::BadResponse)));
+    }
+}
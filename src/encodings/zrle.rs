@@ -0,0 +1,107 @@
+use std::io::{Read, Write};
+
+use super::trle::{decode_tiles, encode_tiles, CPixel};
+use super::zlib::ZlibStream;
+use crate::error::Error;
+use crate::io::*;
+use crate::messages::PixelFormat;
+
+const TILE_SIZE: usize = 64;
+
+/// Reads a u32 length and that many bytes of `stream`, inflating to tiles of
+/// 64 pixels a side.
+pub(crate) fn decode<R: Read>(
+    reader: &mut R,
+    stream: &mut ZlibStream,
+    pixel_format: &PixelFormat,
+    width: u16,
+    height: u16,
+) -> Result<Vec<u8>, Error> {
+    let cpixel = CPixel::new(pixel_format)?;
+    let (width, height) = (width as usize, height as usize);
+    let mut buf = vec![0; u32::decode_from(reader)? as usize];
+    reader.read_exact(&mut buf)?;
+    // Palettes and runs of single pixels can make tiles larger than raw.
+    let tiles = width.div_ceil(TILE_SIZE) * height.div_ceil(TILE_SIZE);
+    let limit = tiles * (1 + 127 * cpixel.len()) + width * height * (cpixel.len() + 1);
+    let buf = stream.decompress(&buf, limit)?;
+    decode_tiles(&mut &buf[..], &cpixel, width, height, TILE_SIZE, false)
+}
+
+pub(crate) fn encode<W: Write>(
+    writer: &mut W,
+    stream: &mut ZlibStream,
+    pixel_format: &PixelFormat,
+    width: u16,
+    height: u16,
+    data: &[u8],
+) -> Result<usize, Error> {
+    let cpixel = CPixel::new(pixel_format)?;
+    let tiles = encode_tiles(
+        &cpixel,
+        width as usize,
+        height as usize,
+        data,
+        TILE_SIZE,
+        false,
+    );
+    let buf = stream.compress(&tiles)?;
+    let len: u32 = buf.len().try_into()?;
+    len.encode_to(writer)?;
+    writer.write_all(&buf)?;
+    Ok(4 + buf.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encodings::trle::tests::frame;
+    use crate::messages::framebuffer_update::tests::pixel_format;
+
+    #[test]
+    fn compressed_pixels() {
+        let mut format = PixelFormat {
+            red_max: 255,
+            green_max: 255,
+            blue_max: 255,
+            red_shift: 16,
+            green_shift: 8,
+            blue_shift: 0,
+            ..pixel_format(32)
+        };
+        assert_eq!(format.compressed_pixel_bytes().unwrap(), 0..3);
+        format.big_endian_flag = true;
+        assert_eq!(format.compressed_pixel_bytes().unwrap(), 1..4);
+        format.red_shift = 24;
+        format.green_shift = 16;
+        format.blue_shift = 8;
+        assert_eq!(format.compressed_pixel_bytes().unwrap(), 0..3);
+        format.big_endian_flag = false;
+        assert_eq!(format.compressed_pixel_bytes().unwrap(), 1..4);
+        format.blue_shift = 0;
+        assert_eq!(format.compressed_pixel_bytes().unwrap(), 0..4);
+        format.depth = 32;
+        format.blue_shift = 8;
+        assert_eq!(format.compressed_pixel_bytes().unwrap(), 0..4);
+        assert_eq!(pixel_format(16).compressed_pixel_bytes().unwrap(), 0..2);
+    }
+
+    #[test]
+    fn round_trip() {
+        for bits_per_pixel in [8, 16, 32] {
+            let format = pixel_format(bits_per_pixel);
+            let data = frame(150, 70, format.bytes_per_pixel().unwrap());
+            let (mut server, mut client) = (ZlibStream::default(), ZlibStream::default());
+            let mut buf = Vec::new();
+            for _ in 0..2 {
+                encode(&mut buf, &mut server, &format, 150, 70, &data).unwrap();
+            }
+            assert!(buf.len() < data.len());
+            let mut reader = &buf[..];
+            for _ in 0..2 {
+                let decoded = decode(&mut reader, &mut client, &format, 150, 70);
+                assert_eq!(decoded.unwrap(), data, "{bits_per_pixel} bits per pixel");
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::{convert::Infallible, fmt::Display, io, string::FromUtf8Error, num::TryFromIntError};
+use std::{convert::Infallible, fmt::Display, io, num::TryFromIntError, string::FromUtf8Error};
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
@@ -9,12 +9,36 @@ pub enum Error {
     IncompatibleSecurity,
     HandshakeFailed,
     UnsupportedEncoding,
-    LengthTooBig
+    UnsupportedPixelFormat,
+    UnsupportedMessage(u8),
+    LengthTooBig,
+    ConnectionFailed(String),
+    AuthenticationFailed(Option<String>),
+    TooManyAttempts(Option<String>),
+    Tls(String),
+    Zlib(String),
+    Jpeg(String),
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("data")
+        match self {
+            Self::Io(error) => write!(f, "io error: {error}"),
+            Self::ConnectionFailed(reason) => write!(f, "connection failed: {reason}"),
+            Self::AuthenticationFailed(Some(reason)) => {
+                write!(f, "authentication failed: {reason}")
+            }
+            Self::AuthenticationFailed(None) => f.write_str("authentication failed"),
+            Self::TooManyAttempts(Some(reason)) => write!(f, "too many attempts: {reason}"),
+            Self::TooManyAttempts(None) => f.write_str("too many attempts"),
+            Self::Tls(error) => write!(f, "tls error: {error}"),
+            Self::Zlib(error) => write!(f, "zlib error: {error}"),
+            Self::Jpeg(error) => write!(f, "jpeg error: {error}"),
+            Self::UnsupportedMessage(message_type) => {
+                write!(f, "unsupported message type {message_type}")
+            }
+            _ => f.write_str("data"),
+        }
     }
 }
 
@@ -48,9 +72,49 @@ impl From<Infallible> for Error {
     }
 }
 
-
 impl From<TryFromIntError> for Error {
-    fn from(value: TryFromIntError) -> Self {
+    fn from(_: TryFromIntError) -> Self {
         Self::LengthTooBig
     }
-}
\ No newline at end of file
+}
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(value: openssl::error::ErrorStack) -> Self {
+        Self::Tls(value.to_string())
+    }
+}
+
+impl<S> From<openssl::ssl::HandshakeError<S>> for Error {
+    fn from(value: openssl::ssl::HandshakeError<S>) -> Self {
+        match value {
+            openssl::ssl::HandshakeError::SetupFailure(error) => error.into(),
+            openssl::ssl::HandshakeError::Failure(stream)
+            | openssl::ssl::HandshakeError::WouldBlock(stream) => {
+                Self::Tls(stream.error().to_string())
+            }
+        }
+    }
+}
+
+impl From<flate2::CompressError> for Error {
+    fn from(value: flate2::CompressError) -> Self {
+        Self::Zlib(value.to_string())
+    }
+}
+
+impl From<flate2::DecompressError> for Error {
+    fn from(value: flate2::DecompressError) -> Self {
+        Self::Zlib(value.to_string())
+    }
+}
+
+impl From<jpeg_decoder::Error> for Error {
+    fn from(value: jpeg_decoder::Error) -> Self {
+        Self::Jpeg(value.to_string())
+    }
+}
+
+impl From<jpeg_encoder::EncodingError> for Error {
+    fn from(value: jpeg_encoder::EncodingError) -> Self {
+        Self::Jpeg(value.to_string())
+    }
+}
@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use crate::error::Error;
+use crate::messages::{EncodingType, Payload, PixelFormat, Rectangle};
+
+/// Moved areas covering fewer changed pixels are sent as pixels.
+const MIN_COPY_PIXELS: usize = 64;
+/// Positions of a probe in the previous frame considered as moves.
+const MAX_PROBE_MATCHES: usize = 8;
+const PROBE_LENGTH: usize = 8;
+
+/// Pixels of a desktop in a pixel format, row by row. The client applies
+/// updates to it, the server computes updates from its changes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Framebuffer {
+    width: u16,
+    height: u16,
+    bytes_per_pixel: usize,
+    data: Vec<u8>,
+}
+
+/// Area whose pixels changed between two frames, ends are exclusive.
+#[derive(Clone, Copy, Debug)]
+struct Area {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+}
+
+impl Framebuffer {
+    pub fn new(width: u16, height: u16, pixel_format: &PixelFormat) -> Result<Self, Error> {
+        let bytes_per_pixel = pixel_format.bytes_per_pixel()?;
+        Ok(Self {
+            width,
+            height,
+            bytes_per_pixel,
+            data: vec![0; width as usize * height as usize * bytes_per_pixel],
+        })
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Changes the size, keeping the pixels of the area common to both sizes
+    /// and clearing the others.
+    pub fn resize(&mut self, width: u16, height: u16) {
+        let mut resized = Self {
+            width,
+            height,
+            bytes_per_pixel: self.bytes_per_pixel,
+            data: vec![0; width as usize * height as usize * self.bytes_per_pixel],
+        };
+        let (common_width, common_height) = (width.min(self.width), height.min(self.height));
+        if common_width > 0 && common_height > 0 {
+            let pixels = self.read(0, 0, common_width, common_height).unwrap();
+            resized.write(0, 0, common_width, &pixels);
+        }
+        *self = resized;
+    }
+
+    fn offset(&self, x: usize, y: usize) -> usize {
+        (y * self.width as usize + x) * self.bytes_per_pixel
+    }
+
+    fn check(&self, x: u16, y: u16, width: u16, height: u16) -> Result<(), Error> {
+        match x as usize + width as usize <= self.width as usize
+            && y as usize + height as usize <= self.height as usize
+        {
+            true => Ok(()),
+            false => Err(Error::BadResponse),
+        }
+    }
+
+    /// Pixels of an area, row by row.
+    pub fn read(&self, x: u16, y: u16, width: u16, height: u16) -> Result<Vec<u8>, Error> {
+        self.check(x, y, width, height)?;
+        let len = width as usize * self.bytes_per_pixel;
+        let mut pixels = Vec::with_capacity(len * height as usize);
+        for row in y as usize..(y + height) as usize {
+            let offset = self.offset(x as usize, row);
+            pixels.extend_from_slice(&self.data[offset..offset + len]);
+        }
+        Ok(pixels)
+    }
+
+    fn write(&mut self, x: u16, y: u16, width: u16, pixels: &[u8]) {
+        let len = width as usize * self.bytes_per_pixel;
+        for (row, line) in pixels.chunks_exact(len).enumerate() {
+            let offset = self.offset(x as usize, y as usize + row);
+            self.data[offset..offset + len].copy_from_slice(line);
+        }
+    }
+
+    /// Draws a decoded rectangle, CopyRect sources are read before anything
+    /// is written so overlapping copies work. Size changes resize the
+    /// framebuffer, pointer shapes are not part of it and are ignored.
+    pub fn apply(&mut self, rectangle: &Rectangle) -> Result<(), Error> {
+        let Rectangle {
+            x,
+            y,
+            width,
+            height,
+            ..
+        } = *rectangle;
+        match &rectangle.payload {
+            Payload::Pixels(pixels) => {
+                self.check(x, y, width, height)?;
+                if pixels.len() != width as usize * height as usize * self.bytes_per_pixel {
+                    return Err(Error::BadResponse);
+                }
+                self.write(x, y, width, pixels);
+            }
+            Payload::CopyRect { src_x, src_y } => {
+                self.check(x, y, width, height)?;
+                let pixels = self.read(*src_x, *src_y, width, height)?;
+                self.write(x, y, width, &pixels);
+            }
+            Payload::DesktopSize => self.resize(width, height),
+            // The y position is the status, the size is unchanged on errors.
+            Payload::ExtendedDesktopSize(_) if y == 0 => self.resize(width, height),
+            Payload::Cursor(_)
+            | Payload::ExtendedDesktopSize(_)
+            | Payload::QemuExtendedKeyEvent
+            | Payload::QemuAudio => {}
+        }
+        Ok(())
+    }
+
+    /// Replaces the content with `frame` and returns the rectangles turning
+    /// the previous content into it: a CopyRect for the largest moved area
+    /// when `copy_rect` is set, then the changed pixels as Raw rectangles.
+    pub fn update(&mut self, frame: &[u8], copy_rect: bool) -> Result<Vec<Rectangle>, Error> {
+        if frame.len() != self.data.len() {
+            return Err(Error::LengthTooBig);
+        }
+        let mut rectangles = Vec::new();
+        if copy_rect {
+            if let Some(copy) = self.find_copy(frame) {
+                self.apply(&copy)?;
+                rectangles.push(copy);
+            }
+        }
+
+        // Consecutive changed rows are sent as one rectangle.
+        let mut y = 0;
+        while let Some(start) =
+            (y..self.height as usize).find(|&y| self.changes(frame, y).is_some())
+        {
+            let (mut x0, mut x1) = (usize::MAX, 0);
+            y = start;
+            while let Some((first, last)) = self.changes(frame, y) {
+                x0 = x0.min(first);
+                x1 = x1.max(last + 1);
+                y += 1;
+            }
+            let pixels = (start..y)
+                .flat_map(|row| {
+                    let offset = self.offset(0, row);
+                    &frame[offset + x0 * self.bytes_per_pixel..offset + x1 * self.bytes_per_pixel]
+                })
+                .copied()
+                .collect();
+            rectangles.push(Rectangle {
+                x: x0 as u16,
+                y: start as u16,
+                width: (x1 - x0) as u16,
+                height: (y - start) as u16,
+                encoding_type: EncodingType::Raw,
+                payload: Payload::Pixels(pixels),
+            });
+        }
+        self.data.copy_from_slice(frame);
+        Ok(rectangles)
+    }
+
+    /// First and last changed pixels of a row of `frame`.
+    fn changes(&self, frame: &[u8], y: usize) -> Option<(usize, usize)> {
+        if y >= self.height as usize {
+            return None;
+        }
+        let offset = self.offset(0, y);
+        let len = self.width as usize * self.bytes_per_pixel;
+        let old = self.data[offset..offset + len].chunks_exact(self.bytes_per_pixel);
+        let new = frame[offset..offset + len].chunks_exact(self.bytes_per_pixel);
+        let mut changed = old.zip(new).enumerate().filter(|(_, (a, b))| a != b);
+        let (first, _) = changed.next()?;
+        let last = changed.next_back().map_or(first, |(x, _)| x);
+        Some((first, last))
+    }
+
+    fn row<'a>(&self, buf: &'a [u8], y: usize) -> &'a [u8] {
+        &buf[self.offset(0, y)..self.offset(0, y + 1)]
+    }
+
+    fn pixel<'a>(&self, buf: &'a [u8], x: usize, y: usize) -> &'a [u8] {
+        let offset = self.offset(x, y);
+        &buf[offset..offset + self.bytes_per_pixel]
+    }
+
+    /// Looks for an area of `frame` that is a moved area of the current
+    /// content. Offsets are guessed from whole rows found elsewhere (scrolls)
+    /// and from short probes of changed pixels found elsewhere (moves).
+    fn find_copy(&self, frame: &[u8]) -> Option<Rectangle> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let mut area: Option<Area> = None;
+        for y in 0..height {
+            if let Some((first, last)) = self.changes(frame, y) {
+                let a = area.get_or_insert(Area {
+                    x0: first,
+                    y0: y,
+                    x1: last + 1,
+                    y1: y + 1,
+                });
+                a.x0 = a.x0.min(first);
+                a.x1 = a.x1.max(last + 1);
+                a.y1 = y + 1;
+            }
+        }
+        let area = area?;
+
+        // Flat rows and probes, found all over backgrounds, are no evidence
+        // of a move.
+        let flat = |pixels: &[u8]| {
+            pixels
+                .chunks_exact(self.bytes_per_pixel)
+                .all(|p| p == &pixels[..self.bytes_per_pixel])
+        };
+        let mut offsets = Vec::new();
+        let hash = |row: &[u8]| {
+            let mut hasher = DefaultHasher::new();
+            row.hash(&mut hasher);
+            hasher.finish()
+        };
+        let mut rows: HashMap<u64, Vec<usize>> = HashMap::new();
+        for y in (0..height).filter(|&y| !flat(self.row(&self.data, y))) {
+            rows.entry(hash(self.row(&self.data, y)))
+                .or_default()
+                .push(y);
+        }
+        let mut scrolls: HashMap<isize, usize> = HashMap::new();
+        for y in area.y0..area.y1 {
+            for &old in rows.get(&hash(self.row(frame, y))).into_iter().flatten() {
+                *scrolls.entry(old as isize - y as isize).or_default() += 1;
+            }
+        }
+        let mut scrolls: Vec<_> = scrolls.into_iter().filter(|&(dy, _)| dy != 0).collect();
+        scrolls.sort_by_key(|&(dy, count)| (std::cmp::Reverse(count), dy));
+        offsets.extend(scrolls.into_iter().take(2).map(|(dy, _)| (0, dy)));
+
+        let probe_len = PROBE_LENGTH.min(width);
+        for i in 0..4 {
+            let y = area.y0 + (area.y1 - area.y0) * i / 4;
+            let Some((first, _)) = self.changes(frame, y) else {
+                continue;
+            };
+            let x = first.min(width - probe_len);
+            let probe = &frame[self.offset(x, y)..self.offset(x + probe_len, y)];
+            if flat(probe) {
+                continue;
+            }
+            let matches = (0..height)
+                .flat_map(|oy| (0..=width - probe_len).map(move |ox| (ox, oy)))
+                .filter(|&(ox, oy)| {
+                    &self.data[self.offset(ox, oy)..self.offset(ox + probe_len, oy)] == probe
+                })
+                .take(MAX_PROBE_MATCHES);
+            offsets.extend(
+                matches.map(|(ox, oy)| (ox as isize - x as isize, oy as isize - y as isize)),
+            );
+        }
+        offsets.sort();
+        offsets.dedup();
+
+        offsets
+            .into_iter()
+            .filter(|&offset| offset != (0, 0))
+            .filter_map(|offset| self.moved_area(frame, area, offset))
+            .max_by_key(|&(_, changed)| changed)
+            .filter(|&(_, changed)| changed >= MIN_COPY_PIXELS)
+            .map(|((a, (dx, dy)), _)| Rectangle {
+                x: a.x0 as u16,
+                y: a.y0 as u16,
+                width: (a.x1 - a.x0) as u16,
+                height: (a.y1 - a.y0) as u16,
+                encoding_type: EncodingType::CopyRect,
+                payload: Payload::CopyRect {
+                    src_x: (a.x0 as isize + dx) as u16,
+                    src_y: (a.y0 as isize + dy) as u16,
+                },
+            })
+    }
+
+    /// Largest area of `area` in `frame` equal to the current content moved
+    /// by `offset`, along with how many changed pixels it covers.
+    #[allow(clippy::type_complexity)]
+    fn moved_area(
+        &self,
+        frame: &[u8],
+        area: Area,
+        (dx, dy): (isize, isize),
+    ) -> Option<((Area, (isize, isize)), usize)> {
+        let (width, height) = (self.width as isize, self.height as isize);
+        let x0 = (area.x0 as isize).max(-dx) as usize;
+        let x1 = (area.x1 as isize).min(width - dx);
+        let y0 = (area.y0 as isize).max(-dy) as usize;
+        let y1 = (area.y1 as isize).min(height - dy);
+        if x1 <= x0 as isize || y1 <= y0 as isize {
+            return None;
+        }
+        let (x1, y1) = (x1 as usize, y1 as usize);
+
+        // Largest rectangle under the histogram of matching pixel columns.
+        let mut heights = vec![0; x1 - x0];
+        let mut best = (
+            0,
+            Area {
+                x0,
+                y0,
+                x1: x0,
+                y1: y0,
+            },
+        );
+        for y in y0..y1 {
+            for (i, x) in (x0..x1).enumerate() {
+                let source = self.pixel(
+                    &self.data,
+                    (x as isize + dx) as usize,
+                    (y as isize + dy) as usize,
+                );
+                heights[i] = match self.pixel(frame, x, y) == source {
+                    true => heights[i] + 1,
+                    false => 0,
+                };
+            }
+            let mut stack: Vec<usize> = Vec::new();
+            for i in 0..=heights.len() {
+                let h = heights.get(i).copied().unwrap_or(0);
+                while let Some(&top) = stack.last().filter(|&&top| heights[top] >= h) {
+                    stack.pop();
+                    let start = stack.last().map_or(0, |&s| s + 1);
+                    let size = heights[top] * (i - start);
+                    if size > best.0 {
+                        best = (
+                            size,
+                            Area {
+                                x0: x0 + start,
+                                y0: y + 1 - heights[top],
+                                x1: x0 + i,
+                                y1: y + 1,
+                            },
+                        );
+                    }
+                }
+                stack.push(i);
+            }
+        }
+        let (_, found) = best;
+        let changed = (found.y0..found.y1)
+            .flat_map(|y| (found.x0..found.x1).map(move |x| (x, y)))
+            .filter(|&(x, y)| self.pixel(&self.data, x, y) != self.pixel(frame, x, y))
+            .count();
+        Some(((found, (dx, dy)), changed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::framebuffer_update::tests::pixel_format;
+
+    /// A frame of distinct 16-bit pixels, with a flat background.
+    fn frame(width: usize, height: usize, pixel: impl Fn(usize, usize) -> u16) -> Vec<u8> {
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| pixel(x, y).to_le_bytes())
+            .collect()
+    }
+
+    fn framebuffer(width: u16, height: u16) -> Framebuffer {
+        Framebuffer::new(width, height, &pixel_format(16)).unwrap()
+    }
+
+    /// Applies the rectangles of an update to a copy of the previous frame.
+    fn replay(previous: &Framebuffer, rectangles: &[Rectangle]) -> Framebuffer {
+        let mut client = previous.clone();
+        for rectangle in rectangles {
+            client.apply(rectangle).unwrap();
+        }
+        client
+    }
+
+    #[test]
+    fn apply() {
+        let mut framebuffer = framebuffer(4, 3);
+        framebuffer
+            .apply(&Rectangle {
+                x: 1,
+                y: 1,
+                width: 2,
+                height: 2,
+                encoding_type: EncodingType::Raw,
+                payload: Payload::Pixels((1..=8).collect()),
+            })
+            .unwrap();
+        assert_eq!(
+            framebuffer.read(1, 1, 2, 2).unwrap(),
+            (1..=8).collect::<Vec<_>>()
+        );
+
+        // Overlapping copy one pixel to the left.
+        framebuffer
+            .apply(&Rectangle {
+                x: 0,
+                y: 1,
+                width: 3,
+                height: 2,
+                encoding_type: EncodingType::CopyRect,
+                payload: Payload::CopyRect { src_x: 1, src_y: 1 },
+            })
+            .unwrap();
+        assert_eq!(
+            framebuffer.read(0, 1, 4, 2).unwrap(),
+            [1, 2, 3, 4, 0, 0, 0, 0, 5, 6, 7, 8, 0, 0, 0, 0]
+        );
+
+        let outside = Rectangle {
+            x: 3,
+            y: 0,
+            width: 2,
+            height: 1,
+            encoding_type: EncodingType::CopyRect,
+            payload: Payload::CopyRect { src_x: 0, src_y: 0 },
+        };
+        assert!(framebuffer.apply(&outside).is_err());
+    }
+
+    #[test]
+    fn scroll() {
+        let (width, height) = (40, 30);
+        let mut server = framebuffer(width, height);
+        let text = |x: usize, y: usize| (x * 7 + y * 131) as u16;
+        server.update(&frame(40, 30, text), true).unwrap();
+        let previous = server.clone();
+
+        // Scrolled up by 5 rows, new text at the bottom.
+        let scrolled = frame(40, 30, |x, y| text(x, y + 5));
+        let rectangles = server.update(&scrolled, true).unwrap();
+        assert_eq!(
+            rectangles[0],
+            Rectangle {
+                x: 0,
+                y: 0,
+                width,
+                height: 25,
+                encoding_type: EncodingType::CopyRect,
+                payload: Payload::CopyRect { src_x: 0, src_y: 5 },
+            }
+        );
+        assert_eq!(rectangles.len(), 2);
+        assert_eq!((rectangles[1].y, rectangles[1].height), (25, 5));
+        assert_eq!(replay(&previous, &rectangles).data(), scrolled);
+    }
+
+    #[test]
+    fn moved_window() {
+        let window = |x0: usize, y0: usize| {
+            move |x: usize, y: usize| match (x.wrapping_sub(x0), y.wrapping_sub(y0)) {
+                (x @ 0..12, y @ 0..10) => 1000 + (x * 10 + y) as u16,
+                _ => 0,
+            }
+        };
+        let mut server = framebuffer(48, 32);
+        server.update(&frame(48, 32, window(3, 4)), true).unwrap();
+        let previous = server.clone();
+
+        let moved = frame(48, 32, window(20, 15));
+        let rectangles = server.update(&moved, true).unwrap();
+        let copy = &rectangles[0];
+        assert_eq!(copy.encoding_type, EncodingType::CopyRect);
+        assert!(
+            copy.x <= 20 && copy.y <= 15 && copy.x + copy.width >= 32 && copy.y + copy.height >= 25
+        );
+        assert_eq!(
+            copy.payload,
+            Payload::CopyRect {
+                src_x: copy.x - 17,
+                src_y: copy.y - 11
+            }
+        );
+        assert_eq!(replay(&previous, &rectangles).data(), moved);
+
+        // Without CopyRect everything changed is sent.
+        let mut server = previous.clone();
+        let rectangles = server.update(&moved, false).unwrap();
+        assert!(rectangles
+            .iter()
+            .all(|r| r.encoding_type == EncodingType::Raw));
+        assert_eq!(replay(&previous, &rectangles).data(), moved);
+    }
+}
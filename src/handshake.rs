@@ -35,19 +35,3 @@ pub struct PointerEvent {
     x: u16,
     y: u16,
 }
-pub struct ClientCutText {
-    text: String,
-}
-
-pub struct EnableContinuousUpdates {
-    enable_flag: bool,
-    x: u16,
-    y: u16,
-    width: u16,
-    height: u16,
-}
-
-pub struct ClientFence {
-    flags: u32,
-    payload: Vec<u8>,
-}
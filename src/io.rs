@@ -1,6 +1,47 @@
-use std::array;
-use std::convert::Infallible;
-use std::io::{Read, Write};
+use std::io::{BufReader, BufWriter, Read, Write};
+
+/// A bidirectional byte stream that the handshake can wrap, e.g. in TLS.
+pub trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+/// `stream` read through a `BufReader` and written through a `BufWriter`,
+/// whose writes are sent on flush or before reading.
+pub struct BufStream<S: Read + Write> {
+    reader: BufReader<WriteHalf<S>>,
+}
+
+struct WriteHalf<S: Read + Write>(BufWriter<S>);
+
+impl<S: Read + Write> BufStream<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            reader: BufReader::new(WriteHalf(BufWriter::new(stream))),
+        }
+    }
+}
+
+impl<S: Read + Write> Read for WriteHalf<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.flush()?;
+        self.0.get_mut().read(buf)
+    }
+}
+
+impl<S: Read + Write> Read for BufStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for BufStream<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.reader.get_mut().0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.reader.get_mut().0.flush()
+    }
+}
 
 pub trait Length {
     const LENGTH: usize;
@@ -44,6 +85,18 @@ impl<R: Read> DecodeFrom<R> for String {
     }
 }
 
+/// Decoding that depends on session state `C`, such as the pixel format.
+pub trait DecodeWith<R: Read, C>: Sized {
+    type Error;
+    fn decode_with(reader: &mut R, context: &mut C) -> Result<Self, Self::Error>;
+}
+
+/// Encoding that depends on session state `C`, see [`DecodeWith`].
+pub trait EncodeWith<W: Write, C>: Sized {
+    type Error: From<std::io::Error>;
+    fn encode_with(self, writer: &mut W, context: &mut C) -> Result<usize, Self::Error>;
+}
+
 pub trait Encode: Length {
     type Error;
     fn encode(self) -> Result<[u8; <Self as Length>::LENGTH], Self::Error>;
@@ -106,16 +159,15 @@ pub struct BigEndian<T> {
     pub data: T,
 }
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
-
 pub struct LittleEndian<T> {
     pub data: T,
 }
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
-
 pub struct NativeEndian<T> {
     pub data: T,
 }
 
+#[allow(dead_code)]
 trait Endian {}
 impl<T> Endian for BigEndian<T> {}
 impl<T> Endian for LittleEndian<T> {}
@@ -152,7 +204,6 @@ macro_rules! impl_decode {
     };
 }
 #[macro_export]
-
 macro_rules! impl_endians {
     ($endian:ident, $type:ident, $from_bytes: ident, $to_bytes: ident) => {
         impl Length for $endian<$type> {
@@ -174,6 +225,7 @@ macro_rules! impl_endians {
             }
         }
 
+        #[allow(clippy::infallible_try_from)]
         impl TryFrom<$endian<$type>> for usize {
             type Error = <$type as TryInto<usize>>::Error;
             fn try_from(value: $endian<$type>) -> Result<usize, Self::Error> {
@@ -1,8 +1,11 @@
-#![feature(exclusive_range_pattern)]
+#![allow(incomplete_features)]
 #![feature(generic_const_exprs)]
 #![feature(array_try_from_fn)]
+pub mod auth;
 pub mod client;
+pub mod encodings;
 pub mod error;
+pub mod framebuffer;
 pub mod io;
 pub mod messages;
 pub mod server;
@@ -0,0 +1,83 @@
+use std::io::{Read, Write};
+
+use crate::io::*;
+use crate::messages::*;
+
+/// A message sent by the client once the session is initialised.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum ClientMessage {
+    SetPixelFormat(SetPixelFormat),
+    SetEncodings(SetEncodings),
+    FramebufferUpdateRequest(FramebufferUpdateRequest),
+    KeyEvent(KeyEvent),
+    PointerEvent(PointerEvent),
+    ClientCutText(CutText),
+    SetDesktopSize(SetDesktopSize),
+    EnableContinuousUpdates(EnableContinuousUpdates),
+    ClientFence(Fence),
+    QemuExtendedKeyEvent(QemuExtendedKeyEvent),
+    QemuAudio(QemuAudioClientMessage),
+}
+
+impl<R: Read> DecodeFrom<R> for ClientMessage {
+    type Error = crate::Error;
+    fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
+        let message_type = [u8::decode_from(reader)?];
+        // Message decoders expect their type, which has been read already.
+        let reader = &mut message_type.as_slice().chain(reader);
+        let message_type = message_type[0];
+        Ok(match MessageType::from(message_type) {
+            MessageType::SetPixelFormat => Self::SetPixelFormat(DecodeFrom::decode_from(reader)?),
+            MessageType::SetEncodings => Self::SetEncodings(DecodeFrom::decode_from(reader)?),
+            MessageType::FramebufferUpdateRequest => {
+                Self::FramebufferUpdateRequest(DecodeFrom::decode_from(reader)?)
+            }
+            MessageType::KeyEvent => Self::KeyEvent(DecodeFrom::decode_from(reader)?),
+            MessageType::PointerEvent => Self::PointerEvent(DecodeFrom::decode_from(reader)?),
+            MessageType::ClientCutText => {
+                <[u8; 4]>::decode_from(reader)?;
+                Self::ClientCutText(DecodeFrom::decode_from(reader)?)
+            }
+            MessageType::PierreOssmanSetDesktopSize => {
+                Self::SetDesktopSize(DecodeFrom::decode_from(reader)?)
+            }
+            MessageType::TightVncEnableContinuousUpdates => {
+                Self::EnableContinuousUpdates(DecodeFrom::decode_from(reader)?)
+            }
+            MessageType::ClientFence => Self::ClientFence(DecodeFrom::decode_from(reader)?),
+            MessageType::AnthonyLiguori => {
+                // QEMU messages are told apart by a submessage type.
+                let types = <[u8; 2]>::decode_from(reader)?;
+                let reader = &mut types.as_slice().chain(reader);
+                match types[1] {
+                    0 => Self::QemuExtendedKeyEvent(DecodeFrom::decode_from(reader)?),
+                    1 => Self::QemuAudio(DecodeFrom::decode_from(reader)?),
+                    _ => return Err(crate::Error::UnsupportedMessage(message_type)),
+                }
+            }
+            _ => return Err(crate::Error::UnsupportedMessage(message_type)),
+        })
+    }
+}
+
+impl<W: Write> EncodeTo<W> for ClientMessage {
+    type Error = crate::Error;
+    fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
+        match self {
+            Self::SetPixelFormat(message) => message.encode_to(writer),
+            Self::SetEncodings(message) => message.encode_to(writer),
+            Self::FramebufferUpdateRequest(message) => message.encode_to(writer),
+            Self::KeyEvent(message) => message.encode_to(writer),
+            Self::PointerEvent(message) => message.encode_to(writer),
+            Self::ClientCutText(message) => {
+                writer.write_all(&[6, 0, 0, 0])?;
+                Ok(4 + message.encode_to(writer)?)
+            }
+            Self::SetDesktopSize(message) => message.encode_to(writer),
+            Self::EnableContinuousUpdates(message) => message.encode_to(writer),
+            Self::ClientFence(message) => message.encode_to(writer),
+            Self::QemuExtendedKeyEvent(message) => message.encode_to(writer),
+            Self::QemuAudio(message) => message.encode_to(writer),
+        }
+    }
+}
@@ -0,0 +1,474 @@
+use std::io::{Read, Write};
+
+use crate::encodings::zlib::ZlibStream;
+use crate::io::*;
+
+/// Largest size of a clipboard format we accept and announce.
+pub const MAX_CLIPBOARD_SIZE: u32 = 20 << 20;
+
+/// Largest cut text payload: every format at its largest size, with its
+/// length.
+const MAX_PAYLOAD: usize = 3 * (MAX_CLIPBOARD_SIZE as usize + 4) + 4;
+
+const CAPS: u32 = 1 << 24;
+const REQUEST: u32 = 1 << 25;
+const PEEK: u32 = 1 << 26;
+const NOTIFY: u32 = 1 << 27;
+const PROVIDE: u32 = 1 << 28;
+
+/// Formats of the Extended Clipboard pseudo-encoding we know of. The DIB and
+/// files formats are ignored.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Copy, Default)]
+pub struct ClipboardFormats {
+    /// UTF-8 text.
+    pub text: bool,
+    /// Rich Text Format.
+    pub rtf: bool,
+    /// HTML clipboard fragment.
+    pub html: bool,
+}
+
+impl ClipboardFormats {
+    pub fn is_empty(&self) -> bool {
+        !(self.text || self.rtf || self.html)
+    }
+}
+
+impl From<u32> for ClipboardFormats {
+    fn from(value: u32) -> Self {
+        Self {
+            text: value & 1 != 0,
+            rtf: value & 2 != 0,
+            html: value & 4 != 0,
+        }
+    }
+}
+
+impl From<ClipboardFormats> for u32 {
+    fn from(value: ClipboardFormats) -> Self {
+        value.text as u32 | (value.rtf as u32) << 1 | (value.html as u32) << 2
+    }
+}
+
+/// Actions a peer accepts, and formats it accepts along with their largest
+/// size.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Clone)]
+pub struct ClipboardCaps {
+    pub request: bool,
+    pub peek: bool,
+    pub notify: bool,
+    pub provide: bool,
+    pub text: Option<u32>,
+    pub rtf: Option<u32>,
+    pub html: Option<u32>,
+}
+
+/// Every action and format, up to `MAX_CLIPBOARD_SIZE`.
+impl Default for ClipboardCaps {
+    fn default() -> Self {
+        Self {
+            request: true,
+            peek: true,
+            notify: true,
+            provide: true,
+            text: Some(MAX_CLIPBOARD_SIZE),
+            rtf: Some(MAX_CLIPBOARD_SIZE),
+            html: Some(MAX_CLIPBOARD_SIZE),
+        }
+    }
+}
+
+impl ClipboardCaps {
+    pub fn formats(&self) -> ClipboardFormats {
+        ClipboardFormats {
+            text: self.text.is_some(),
+            rtf: self.rtf.is_some(),
+            html: self.html.is_some(),
+        }
+    }
+}
+
+/// Clipboard content, in any of the formats. Text uses `\n` line endings
+/// whatever the ones on the wire.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Default)]
+pub struct Clipboard {
+    pub text: Option<String>,
+    pub rtf: Option<String>,
+    pub html: Option<String>,
+}
+
+impl Clipboard {
+    pub fn text(text: &str) -> Self {
+        Self {
+            text: Some(text.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn formats(&self) -> ClipboardFormats {
+        ClipboardFormats {
+            text: self.text.is_some(),
+            rtf: self.rtf.is_some(),
+            html: self.html.is_some(),
+        }
+    }
+
+    /// The content in `formats` only.
+    pub fn only(&self, formats: ClipboardFormats) -> Self {
+        Self {
+            text: self.text.clone().filter(|_| formats.text),
+            rtf: self.rtf.clone().filter(|_| formats.rtf),
+            html: self.html.clone().filter(|_| formats.html),
+        }
+    }
+}
+
+/// Message of the Extended Clipboard pseudo-encoding.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum ExtendedClipboard {
+    Caps(ClipboardCaps),
+    /// Asks for the content in these formats.
+    Request(ClipboardFormats),
+    /// Asks which formats the content is available in.
+    Peek,
+    /// The content changed and is available in these formats.
+    Notify(ClipboardFormats),
+    Provide(Clipboard),
+}
+
+/// What a cut text tells about the clipboard of the peer.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum ClipboardEvent {
+    /// The clipboard changed, its content can be requested in these formats.
+    Changed(ClipboardFormats),
+    /// The clipboard content.
+    Content(Clipboard),
+}
+
+/// Content of ClientCutText and ServerCutText after their type and padding:
+/// Latin-1 text, or an Extended Clipboard message if the length is negative.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum CutText {
+    Text(String),
+    Extended(ExtendedClipboard),
+}
+
+impl CutText {
+    pub fn event(&self) -> Option<ClipboardEvent> {
+        match self {
+            Self::Text(text) => Some(ClipboardEvent::Content(Clipboard::text(text))),
+            Self::Extended(ExtendedClipboard::Notify(formats)) => {
+                Some(ClipboardEvent::Changed(*formats))
+            }
+            Self::Extended(ExtendedClipboard::Provide(clipboard)) => {
+                Some(ClipboardEvent::Content(clipboard.clone()))
+            }
+            Self::Extended(_) => None,
+        }
+    }
+}
+
+/// Formats are sent null-terminated, text with `\r\n` line endings.
+fn encode_format(data: &str, text: bool) -> Vec<u8> {
+    let mut data = match text {
+        true => data
+            .replace("\r\n", "\n")
+            .replace('\n', "\r\n")
+            .into_bytes(),
+        false => data.as_bytes().to_vec(),
+    };
+    data.push(0);
+    data
+}
+
+fn decode_format(mut data: Vec<u8>, text: bool) -> Result<String, crate::Error> {
+    while data.last() == Some(&0) {
+        data.pop();
+    }
+    let data = String::from_utf8(data)?;
+    Ok(match text {
+        true => data.replace("\r\n", "\n"),
+        false => data,
+    })
+}
+
+impl<R: Read> DecodeFrom<R> for CutText {
+    type Error = crate::Error;
+    fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
+        let len = i32::decode_from(reader)?;
+        let size = len.unsigned_abs() as usize;
+        if size > MAX_PAYLOAD || (len < 0 && size < 4) {
+            return Err(crate::Error::BadResponse);
+        }
+        let mut data = vec![0; size];
+        reader.read_exact(&mut data)?;
+        if len >= 0 {
+            return Ok(Self::Text(data.into_iter().map(char::from).collect()));
+        }
+
+        let flags = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let formats = ClipboardFormats::from(flags);
+        let mut data = &data[4..];
+        let message = if flags & CAPS != 0 {
+            // A size follows for each format, including the unknown ones.
+            let mut sizes = [None; 16];
+            for (i, size) in sizes.iter_mut().enumerate() {
+                if flags & 1 << i != 0 {
+                    *size = Some(u32::decode_from(&mut data)?);
+                }
+            }
+            ExtendedClipboard::Caps(ClipboardCaps {
+                request: flags & REQUEST != 0,
+                peek: flags & PEEK != 0,
+                notify: flags & NOTIFY != 0,
+                provide: flags & PROVIDE != 0,
+                text: sizes[0],
+                rtf: sizes[1],
+                html: sizes[2],
+            })
+        } else if flags & REQUEST != 0 {
+            ExtendedClipboard::Request(formats)
+        } else if flags & PEEK != 0 {
+            ExtendedClipboard::Peek
+        } else if flags & NOTIFY != 0 {
+            ExtendedClipboard::Notify(formats)
+        } else if flags & PROVIDE != 0 {
+            let data = ZlibStream::default().decompress(data, MAX_PAYLOAD)?;
+            let mut data = data.as_slice();
+            let mut clipboard = Clipboard::default();
+            for i in 0..16 {
+                if flags & 1 << i == 0 {
+                    continue;
+                }
+                let size = u32::decode_from(&mut data)? as usize;
+                if size > data.len() {
+                    return Err(crate::Error::BadResponse);
+                }
+                let (format, rest) = data.split_at(size);
+                data = rest;
+                let format = format.to_vec();
+                match i {
+                    0 => clipboard.text = Some(decode_format(format, true)?),
+                    1 => clipboard.rtf = Some(decode_format(format, false)?),
+                    2 => clipboard.html = Some(decode_format(format, false)?),
+                    _ => {}
+                }
+            }
+            ExtendedClipboard::Provide(clipboard)
+        } else {
+            return Err(crate::Error::BadResponse);
+        };
+        Ok(Self::Extended(message))
+    }
+}
+
+impl<W: Write> EncodeTo<W> for CutText {
+    type Error = crate::Error;
+    fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
+        let message = match self {
+            Self::Text(text) => {
+                // Characters outside of Latin-1 cannot be sent.
+                let data: Vec<u8> = text
+                    .chars()
+                    .map(|c| u8::try_from(c).unwrap_or(b'?'))
+                    .collect();
+                let len: i32 = data.len().try_into()?;
+                len.encode_to(writer)?;
+                writer.write_all(&data)?;
+                return Ok(4 + data.len());
+            }
+            Self::Extended(message) => message,
+        };
+        let mut data = Vec::new();
+        match message {
+            ExtendedClipboard::Caps(caps) => {
+                let actions = [caps.request, caps.peek, caps.notify, caps.provide];
+                let actions = (actions.iter().zip([REQUEST, PEEK, NOTIFY, PROVIDE]))
+                    .filter(|(supported, _)| **supported)
+                    .fold(CAPS, |flags, (_, action)| flags | action);
+                let formats = u32::from(caps.formats());
+                (actions | formats).encode_to(&mut data)?;
+                for size in [caps.text, caps.rtf, caps.html].into_iter().flatten() {
+                    size.encode_to(&mut data)?;
+                }
+            }
+            ExtendedClipboard::Request(formats) => {
+                (REQUEST | u32::from(formats)).encode_to(&mut data)?;
+            }
+            ExtendedClipboard::Peek => {
+                PEEK.encode_to(&mut data)?;
+            }
+            ExtendedClipboard::Notify(formats) => {
+                (NOTIFY | u32::from(formats)).encode_to(&mut data)?;
+            }
+            ExtendedClipboard::Provide(clipboard) => {
+                (PROVIDE | u32::from(clipboard.formats())).encode_to(&mut data)?;
+                let mut formats = Vec::new();
+                for (format, text) in [
+                    (clipboard.text, true),
+                    (clipboard.rtf, false),
+                    (clipboard.html, false),
+                ] {
+                    if let Some(format) = format {
+                        let format = encode_format(&format, text);
+                        let len: u32 = format.len().try_into()?;
+                        len.encode_to(&mut formats)?;
+                        formats.extend(format);
+                    }
+                }
+                data.extend(ZlibStream::default().compress(&formats)?);
+            }
+        }
+        let len = -i32::try_from(data.len())?;
+        len.encode_to(writer)?;
+        writer.write_all(&data)?;
+        Ok(4 + data.len())
+    }
+}
+
+/// Clipboard side of a session: our content, and what the peer told about
+/// its Extended Clipboard support and content.
+#[derive(Debug, Default)]
+pub(crate) struct ClipboardState {
+    pub(crate) local: Clipboard,
+    pub(crate) caps: Option<ClipboardCaps>,
+    pub(crate) notified: ClipboardFormats,
+}
+
+impl ClipboardState {
+    /// Applies a cut text of the peer, returning the answer to send if any.
+    /// Caps are answered with ours when `answer_caps` is set.
+    pub(crate) fn receive(&mut self, cut_text: &CutText, answer_caps: bool) -> Option<CutText> {
+        let answer = match cut_text {
+            CutText::Extended(ExtendedClipboard::Caps(caps)) => {
+                self.caps = Some(caps.clone());
+                answer_caps.then(|| ExtendedClipboard::Caps(ClipboardCaps::default()))
+            }
+            CutText::Extended(ExtendedClipboard::Peek) => {
+                Some(ExtendedClipboard::Notify(self.local.formats()))
+            }
+            CutText::Extended(ExtendedClipboard::Request(formats)) => {
+                Some(ExtendedClipboard::Provide(self.provide(*formats)))
+            }
+            CutText::Extended(ExtendedClipboard::Notify(formats)) => {
+                self.notified = *formats;
+                None
+            }
+            _ => None,
+        };
+        answer.map(CutText::Extended)
+    }
+
+    /// Sets our content, returning the cut text telling the peer: a notify
+    /// if it accepts them, the content in formats it accepts otherwise.
+    pub(crate) fn set(&mut self, clipboard: Clipboard) -> CutText {
+        self.local = clipboard;
+        match &self.caps {
+            Some(caps) if caps.notify => {
+                CutText::Extended(ExtendedClipboard::Notify(self.local.formats()))
+            }
+            Some(caps) if caps.provide => {
+                CutText::Extended(ExtendedClipboard::Provide(self.provide(caps.formats())))
+            }
+            _ => CutText::Text(self.local.text.clone().unwrap_or_default()),
+        }
+    }
+
+    /// The request for the content the peer notified, if any.
+    pub(crate) fn request(&self) -> Option<CutText> {
+        let request = ExtendedClipboard::Request(self.notified);
+        (self.caps.as_ref()?.request && !self.notified.is_empty())
+            .then_some(CutText::Extended(request))
+    }
+
+    /// Our content in `formats`, without the ones too large for the peer.
+    fn provide(&self, formats: ClipboardFormats) -> Clipboard {
+        let caps = self.caps.clone().unwrap_or_default();
+        let fits =
+            |format: &String, max: Option<u32>| max.is_some_and(|max| format.len() < max as usize);
+        let clipboard = self.local.only(formats);
+        Clipboard {
+            text: clipboard.text.filter(|text| fits(text, caps.text)),
+            rtf: clipboard.rtf.filter(|rtf| fits(rtf, caps.rtf)),
+            html: clipboard.html.filter(|html| fits(html, caps.html)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cut_texts() {
+        let mut buf = Vec::new();
+        CutText::Text("café ☕".to_string())
+            .encode_to(&mut buf)
+            .unwrap();
+        assert_eq!(buf, [0, 0, 0, 6, b'c', b'a', b'f', 0xe9, b' ', b'?']);
+        assert_eq!(
+            CutText::decode_from(&mut buf.as_slice()).unwrap(),
+            CutText::Text("café ?".to_string())
+        );
+
+        let caps = ClipboardCaps {
+            peek: false,
+            rtf: None,
+            html: Some(1024),
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        CutText::Extended(ExtendedClipboard::Caps(caps.clone()))
+            .encode_to(&mut buf)
+            .unwrap();
+        let flags = [0x1b, 0, 0, 5];
+        assert_eq!(buf[..8], [[0xff, 0xff, 0xff, 0xf4], flags].concat());
+        assert_eq!(buf[8..], [1, 0x40, 0, 0, 0, 0, 4, 0]);
+        assert_eq!(
+            CutText::decode_from(&mut buf.as_slice()).unwrap(),
+            CutText::Extended(ExtendedClipboard::Caps(caps))
+        );
+
+        for message in [
+            ExtendedClipboard::Request(ClipboardFormats {
+                text: true,
+                ..Default::default()
+            }),
+            ExtendedClipboard::Peek,
+            ExtendedClipboard::Notify(ClipboardFormats {
+                text: true,
+                html: true,
+                ..Default::default()
+            }),
+            ExtendedClipboard::Provide(Clipboard {
+                text: Some("Grüße\nwelt".to_string()),
+                rtf: Some(r"{\rtf1 Grüße}".to_string()),
+                html: None,
+            }),
+        ] {
+            let mut buf = Vec::new();
+            let cut_text = CutText::Extended(message);
+            cut_text.clone().encode_to(&mut buf).unwrap();
+            assert_eq!(CutText::decode_from(&mut buf.as_slice()).unwrap(), cut_text);
+        }
+    }
+
+    #[test]
+    fn provided_text() {
+        // A provide of text as sent by other implementations, compressed
+        // in one go.
+        let mut text = Vec::new();
+        13u32.encode_to(&mut text).unwrap();
+        text.extend(b"line\r\nline 2\0");
+        let mut compressed = flate2::write::ZlibEncoder::new(Vec::new(), Default::default());
+        compressed.write_all(&text).unwrap();
+        let compressed = compressed.finish().unwrap();
+        let mut buf = (-(4 + compressed.len() as i32)).to_be_bytes().to_vec();
+        buf.extend([0x10, 0, 0, 1]);
+        buf.extend(compressed);
+        assert_eq!(
+            CutText::decode_from(&mut buf.as_slice()).unwrap().event(),
+            Some(ClipboardEvent::Content(Clipboard::text("line\nline 2")))
+        );
+    }
+}
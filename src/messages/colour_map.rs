@@ -0,0 +1,124 @@
+use std::io::{Read, Write};
+
+use crate::io::*;
+
+/// An entry of the colour map, each component from 0 to 65535.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Colour {
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
+}
+
+impl Length for Colour {
+    const LENGTH: usize = 6;
+}
+
+impl Decode for Colour {
+    type Error = crate::Error;
+    fn decode(data: [u8; <Self as Length>::LENGTH]) -> Result<Self, Self::Error> {
+        Ok(Self {
+            red: u16::from_be_bytes([data[0], data[1]]),
+            green: u16::from_be_bytes([data[2], data[3]]),
+            blue: u16::from_be_bytes([data[4], data[5]]),
+        })
+    }
+}
+
+impl Encode for Colour {
+    type Error = crate::Error;
+    fn encode(self) -> Result<[u8; <Self as Length>::LENGTH], Self::Error> {
+        let [red, green, blue] = [self.red, self.green, self.blue].map(u16::to_be_bytes);
+        Ok([red[0], red[1], green[0], green[1], blue[0], blue[1]])
+    }
+}
+
+/// Sets entries of the colour map of a client whose pixel format is not true
+/// colour, from `first_colour` on.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct SetColourMapEntries {
+    pub first_colour: u16,
+    pub colours: Vec<Colour>,
+}
+
+impl<R: Read> DecodeFrom<R> for SetColourMapEntries {
+    type Error = crate::Error;
+    fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
+        let [_, _, first @ .., len_high, len_low] = <[u8; 6]>::decode_from(reader)?;
+        Ok(Self {
+            first_colour: u16::from_be_bytes(first),
+            colours: (0..u16::from_be_bytes([len_high, len_low]))
+                .map(|_| Colour::decode_from(reader))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl<W: Write> EncodeTo<W> for SetColourMapEntries {
+    type Error = crate::Error;
+    fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
+        let len: u16 = self.colours.len().try_into()?;
+        let (first, count) = (self.first_colour.to_be_bytes(), len.to_be_bytes());
+        writer.write_all(&[1, 0, first[0], first[1], count[0], count[1]])?;
+        for colour in self.colours {
+            colour.encode_to(writer)?;
+        }
+        Ok(6 + len as usize * Colour::LENGTH)
+    }
+}
+
+/// Rings the bell of the client.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct Bell;
+
+impl Length for Bell {
+    const LENGTH: usize = 1;
+}
+
+impl Decode for Bell {
+    type Error = crate::Error;
+    fn decode(_: [u8; <Self as Length>::LENGTH]) -> Result<Self, Self::Error> {
+        Ok(Self)
+    }
+}
+
+impl Encode for Bell {
+    type Error = crate::Error;
+    fn encode(self) -> Result<[u8; <Self as Length>::LENGTH], Self::Error> {
+        Ok([2])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colour_map_entries() {
+        let message = SetColourMapEntries {
+            first_colour: 3,
+            colours: vec![
+                Colour {
+                    red: 0xffff,
+                    green: 0,
+                    blue: 0x1234,
+                },
+                Colour {
+                    red: 1,
+                    green: 2,
+                    blue: 3,
+                },
+            ],
+        };
+        let mut buf = Vec::new();
+        assert_eq!(message.clone().encode_to(&mut buf).unwrap(), 18);
+        assert_eq!(
+            buf,
+            [1, 0, 0, 3, 0, 2, 0xff, 0xff, 0, 0, 0x12, 0x34, 0, 1, 0, 2, 0, 3]
+        );
+        assert_eq!(
+            SetColourMapEntries::decode_from(&mut buf.as_slice()).unwrap(),
+            message
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use std::io::{Read, Write};
+
+use crate::io::*;
+
+/// A screen of a multi-screen framebuffer layout.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct Screen {
+    pub id: u32,
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    pub flags: u32,
+}
+
+impl Length for Screen {
+    const LENGTH: usize = 16;
+}
+
+impl Decode for Screen {
+    type Error = crate::Error;
+    fn decode(data: [u8; 16]) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+            x: u16::from_be_bytes([data[4], data[5]]),
+            y: u16::from_be_bytes([data[6], data[7]]),
+            width: u16::from_be_bytes([data[8], data[9]]),
+            height: u16::from_be_bytes([data[10], data[11]]),
+            flags: u32::from_be_bytes([data[12], data[13], data[14], data[15]]),
+        })
+    }
+}
+
+impl Encode for Screen {
+    type Error = crate::Error;
+    fn encode(self) -> Result<[u8; 16], Self::Error> {
+        let mut data = [0; 16];
+        data[..4].copy_from_slice(&self.id.to_be_bytes());
+        data[4..6].copy_from_slice(&self.x.to_be_bytes());
+        data[6..8].copy_from_slice(&self.y.to_be_bytes());
+        data[8..10].copy_from_slice(&self.width.to_be_bytes());
+        data[10..12].copy_from_slice(&self.height.to_be_bytes());
+        data[12..].copy_from_slice(&self.flags.to_be_bytes());
+        Ok(data)
+    }
+}
+
+impl Screen {
+    /// Whether the layout `screens` fits a framebuffer of `width` by `height`
+    /// pixels: at least one screen, none empty or outside.
+    pub fn valid_layout(screens: &[Screen], width: u16, height: u16) -> bool {
+        !screens.is_empty()
+            && screens.iter().all(|screen| {
+                screen.width > 0
+                    && screen.height > 0
+                    && screen.x as u32 + screen.width as u32 <= width as u32
+                    && screen.y as u32 + screen.height as u32 <= height as u32
+            })
+    }
+}
+
+/// Layout of the ExtendedDesktopSize pseudo-rectangle: a u8 count followed by
+/// 3 bytes of padding.
+impl<R: Read> DecodeFrom<R> for Vec<Screen> {
+    type Error = crate::Error;
+    fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
+        let [len, ..] = <[u8; 4]>::decode_from(reader)?;
+        (0..len).map(|_| Screen::decode_from(reader)).collect()
+    }
+}
+
+impl<W: Write> EncodeTo<W> for Vec<Screen> {
+    type Error = crate::Error;
+    fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
+        let len: u8 = self.len().try_into()?;
+        [len, 0, 0, 0].encode_to(writer)?;
+        for screen in self {
+            screen.encode_to(writer)?;
+        }
+        Ok(4 + len as usize * Screen::LENGTH)
+    }
+}
+
+/// Who a framebuffer size change comes from, sent as the x position of the
+/// ExtendedDesktopSize pseudo-rectangle.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Copy)]
+pub enum DesktopSizeReason {
+    Server,
+    /// The client the rectangle is sent to, answering its SetDesktopSize.
+    Client,
+    OtherClient,
+    Unassigned(u16),
+}
+
+impl From<u16> for DesktopSizeReason {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => Self::Server,
+            1 => Self::Client,
+            2 => Self::OtherClient,
+            _ => Self::Unassigned(value),
+        }
+    }
+}
+
+impl From<DesktopSizeReason> for u16 {
+    fn from(value: DesktopSizeReason) -> Self {
+        match value {
+            DesktopSizeReason::Server => 0,
+            DesktopSizeReason::Client => 1,
+            DesktopSizeReason::OtherClient => 2,
+            DesktopSizeReason::Unassigned(value) => value,
+        }
+    }
+}
+
+/// Result of a SetDesktopSize, sent as the y position of the
+/// ExtendedDesktopSize pseudo-rectangle.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Copy)]
+pub enum DesktopSizeStatus {
+    Success,
+    Prohibited,
+    OutOfResources,
+    InvalidLayout,
+    Unassigned(u16),
+}
+
+impl From<u16> for DesktopSizeStatus {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => Self::Success,
+            1 => Self::Prohibited,
+            2 => Self::OutOfResources,
+            3 => Self::InvalidLayout,
+            _ => Self::Unassigned(value),
+        }
+    }
+}
+
+impl From<DesktopSizeStatus> for u16 {
+    fn from(value: DesktopSizeStatus) -> Self {
+        match value {
+            DesktopSizeStatus::Success => 0,
+            DesktopSizeStatus::Prohibited => 1,
+            DesktopSizeStatus::OutOfResources => 2,
+            DesktopSizeStatus::InvalidLayout => 3,
+            DesktopSizeStatus::Unassigned(value) => value,
+        }
+    }
+}
+
+/// Request of the client to change the framebuffer size and layout.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct SetDesktopSize {
+    pub width: u16,
+    pub height: u16,
+    pub screens: Vec<Screen>,
+}
+
+impl<R: Read> DecodeFrom<R> for SetDesktopSize {
+    type Error = crate::Error;
+    fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
+        let [_, _, width @ .., len, _] = <[u8; 8]>::decode_from(reader)?;
+        Ok(Self {
+            width: u16::from_be_bytes([width[0], width[1]]),
+            height: u16::from_be_bytes([width[2], width[3]]),
+            screens: (0..len)
+                .map(|_| Screen::decode_from(reader))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl<W: Write> EncodeTo<W> for SetDesktopSize {
+    type Error = crate::Error;
+    fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
+        let len: u8 = self.screens.len().try_into()?;
+        let (width, height) = (self.width.to_be_bytes(), self.height.to_be_bytes());
+        [251, 0, width[0], width[1], height[0], height[1], len, 0].encode_to(writer)?;
+        for screen in self.screens {
+            screen.encode_to(writer)?;
+        }
+        Ok(8 + len as usize * Screen::LENGTH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_desktop_size() {
+        let message = SetDesktopSize {
+            width: 3840,
+            height: 1080,
+            screens: vec![
+                Screen {
+                    id: 1,
+                    x: 0,
+                    y: 0,
+                    width: 1920,
+                    height: 1080,
+                    flags: 0,
+                },
+                Screen {
+                    id: 2,
+                    x: 1920,
+                    y: 0,
+                    width: 1920,
+                    height: 1080,
+                    flags: 0,
+                },
+            ],
+        };
+        let mut buf = Vec::new();
+        assert_eq!(message.clone().encode_to(&mut buf).unwrap(), 40);
+        assert_eq!(&buf[..8], [251, 0, 15, 0, 4, 56, 2, 0]);
+        assert_eq!(&buf[24..32], [0, 0, 0, 2, 7, 128, 0, 0]);
+        assert_eq!(SetDesktopSize::decode_from(&mut &buf[..]).unwrap(), message);
+        assert!(Screen::valid_layout(&message.screens, 3840, 1080));
+        assert!(!Screen::valid_layout(&message.screens, 3839, 1080));
+        assert!(!Screen::valid_layout(&[], 3840, 1080));
+    }
+}
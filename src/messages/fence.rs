@@ -0,0 +1,130 @@
+use std::io::{Read, Write};
+
+use crate::io::*;
+
+/// Largest payload of a fence, which the peer sends back unchanged.
+pub const MAX_FENCE_PAYLOAD: usize = 64;
+
+/// How a fence orders the messages around it.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Copy, Default)]
+pub struct FenceFlags {
+    /// Messages before the fence are processed before it is answered.
+    pub block_before: bool,
+    /// Messages after the fence wait until it is answered.
+    pub block_after: bool,
+    /// The message following the fence is processed before the next one.
+    pub sync_next: bool,
+    /// The fence is a request the peer answers, a response otherwise.
+    pub request: bool,
+}
+
+impl From<u32> for FenceFlags {
+    fn from(value: u32) -> Self {
+        Self {
+            block_before: value & 1 != 0,
+            block_after: value & 2 != 0,
+            sync_next: value & 4 != 0,
+            request: value & 1 << 31 != 0,
+        }
+    }
+}
+
+impl From<FenceFlags> for u32 {
+    fn from(value: FenceFlags) -> Self {
+        value.block_before as u32
+            | (value.block_after as u32) << 1
+            | (value.sync_next as u32) << 2
+            | (value.request as u32) << 31
+    }
+}
+
+/// ServerFence or ClientFence, both have the same number and layout.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Default)]
+pub struct Fence {
+    pub flags: FenceFlags,
+    pub payload: Vec<u8>,
+}
+
+impl Fence {
+    /// The answer to this fence request: its payload and the flags we know
+    /// of, without `request`.
+    pub fn response(&self) -> Self {
+        Self {
+            flags: FenceFlags {
+                request: false,
+                ..self.flags
+            },
+            payload: self.payload.clone(),
+        }
+    }
+}
+
+impl<R: Read> DecodeFrom<R> for Fence {
+    type Error = crate::Error;
+    fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
+        let [_, _, _, _, flags @ .., len] = <[u8; 9]>::decode_from(reader)?;
+        if len as usize > MAX_FENCE_PAYLOAD {
+            return Err(crate::Error::BadResponse);
+        }
+        let mut payload = vec![0; len as usize];
+        reader.read_exact(&mut payload)?;
+        Ok(Self {
+            flags: u32::from_be_bytes(flags).into(),
+            payload,
+        })
+    }
+}
+
+impl<W: Write> EncodeTo<W> for Fence {
+    type Error = crate::Error;
+    fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
+        if self.payload.len() > MAX_FENCE_PAYLOAD {
+            return Err(crate::Error::LengthTooBig);
+        }
+        let flags = u32::from(self.flags).to_be_bytes();
+        let len = self.payload.len() as u8;
+        [248, 0, 0, 0, flags[0], flags[1], flags[2], flags[3], len].encode_to(writer)?;
+        writer.write_all(&self.payload)?;
+        Ok(9 + self.payload.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fence() {
+        let fence = Fence {
+            flags: FenceFlags {
+                block_before: true,
+                request: true,
+                ..Default::default()
+            },
+            payload: vec![1, 2, 3],
+        };
+        let mut buf = Vec::new();
+        assert_eq!(fence.clone().encode_to(&mut buf).unwrap(), 12);
+        assert_eq!(buf, [248, 0, 0, 0, 0x80, 0, 0, 1, 3, 1, 2, 3]);
+        assert_eq!(Fence::decode_from(&mut buf.as_slice()).unwrap(), fence);
+        assert_eq!(u32::from(fence.response().flags), 1);
+
+        // Unknown flags are dropped, long payloads refused both ways.
+        let fence = Fence::decode_from(&mut [248, 0, 0, 0, 0, 0, 0, 0x1a, 0].as_slice());
+        assert_eq!(u32::from(fence.unwrap().flags), 2);
+        let mut long = vec![248, 0, 0, 0, 0, 0, 0, 0, 65];
+        long.resize(9 + 65, 0);
+        assert!(matches!(
+            Fence::decode_from(&mut long.as_slice()),
+            Err(crate::Error::BadResponse)
+        ));
+        let fence = Fence {
+            payload: vec![0; 65],
+            ..Default::default()
+        };
+        assert!(matches!(
+            fence.encode_to(&mut Vec::new()),
+            Err(crate::Error::LengthTooBig)
+        ));
+    }
+}
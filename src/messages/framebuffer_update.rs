@@ -1,13 +1,77 @@
 use std::io::{Read, Write};
 
-use crate::io::{BigEndian, DecodeFrom, EncodeTo};
+use crate::encodings::cursor::{self, Cursor};
+use crate::encodings::zlib::{self, ZlibStream};
+use crate::encodings::{hextile, rre, tight, trle, zrle};
+use crate::io::{DecodeFrom, DecodeWith, EncodeTo, EncodeWith};
 
-use super::EncodingType;
+use super::{AudioFormat, EncodingType, PixelFormat, Screen};
+
+/// Session state rectangles are decoded and encoded with: the pixel format
+/// set by the client, the encodings it accepts, the format of QEMU audio and
+/// the compression streams living as long as the connection.
+#[derive(Debug)]
+pub struct SessionContext {
+    pub pixel_format: PixelFormat,
+    pub encodings: Vec<EncodingType>,
+    pub audio_format: AudioFormat,
+    pub(crate) zlib: ZlibStream,
+    pub(crate) zrle: ZlibStream,
+    pub(crate) tight: [ZlibStream; 4],
+}
+
+impl SessionContext {
+    pub fn new(pixel_format: PixelFormat) -> Self {
+        Self {
+            pixel_format,
+            encodings: vec![EncodingType::Raw],
+            audio_format: AudioFormat::default(),
+            zlib: ZlibStream::default(),
+            zrle: ZlibStream::default(),
+            tight: std::array::from_fn(|_| ZlibStream::default()),
+        }
+    }
+
+    /// JPEG quality level of Tight set by the client.
+    pub fn quality_level(&self) -> Option<u8> {
+        self.encodings.iter().find_map(|e| match e {
+            EncodingType::QualityLevel(level) => Some(*level),
+            _ => None,
+        })
+    }
+
+    /// Compression level of Tight set by the client.
+    pub fn compress_level(&self) -> Option<u8> {
+        self.encodings.iter().find_map(|e| match e {
+            EncodingType::CompressLevel(level) => Some(*level),
+            _ => None,
+        })
+    }
+
+    /// Encoding for pixels: the first one accepted by the client that has an
+    /// encoder, Raw otherwise.
+    pub fn pixel_encoding(&self) -> EncodingType {
+        use EncodingType::*;
+        self.encodings
+            .iter()
+            .copied()
+            .find(|e| matches!(e, Raw | Rre | CoRre | Hextile | Zlib | Tight | Trle | Zrle))
+            .unwrap_or(Raw)
+    }
+
+    /// Raw is always accepted, other encodings once set by the client.
+    fn check(&self, encoding_type: EncodingType) -> Result<(), crate::Error> {
+        match encoding_type == EncodingType::Raw || self.encodings.contains(&encoding_type) {
+            true => Ok(()),
+            false => Err(crate::Error::UnsupportedEncoding),
+        }
+    }
+}
 #[derive(Debug, PartialEq, PartialOrd)]
 pub struct FramebufferUpdate {
     pub rectangles: Vec<Rectangle>,
 }
-#[derive(PartialEq, PartialOrd)]
+#[derive(PartialEq, PartialOrd, Clone)]
 
 pub struct Rectangle {
     pub x: u16,
@@ -15,21 +79,107 @@ pub struct Rectangle {
     pub width: u16,
     pub height: u16,
     pub encoding_type: EncodingType,
-    pub pixels: Vec<u8>,
+    pub payload: Payload,
+}
+
+/// Content of a rectangle once decoded.
+#[derive(PartialEq, PartialOrd, Clone)]
+pub enum Payload {
+    /// Pixels in the session pixel format, row by row, whatever encoding
+    /// carried them.
+    Pixels(Vec<u8>),
+    /// The rectangle is a copy of the framebuffer area at this position.
+    CopyRect { src_x: u16, src_y: u16 },
+    /// New pointer shape, from the Cursor and XCursor pseudo-encodings.
+    Cursor(Cursor),
+    /// New framebuffer size, the one of the rectangle.
+    DesktopSize,
+    /// New framebuffer size and screen layout, the x and y positions of the
+    /// rectangle being the reason and the status of the change.
+    ExtendedDesktopSize(Vec<Screen>),
+    /// The server accepts QEMU extended key events.
+    QemuExtendedKeyEvent,
+    /// The server accepts QEMU audio messages.
+    QemuAudio,
+}
+
+impl std::fmt::Debug for Payload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pixels(pixels) => write!(f, "Pixels({} bytes)", pixels.len()),
+            Self::CopyRect { src_x, src_y } => f
+                .debug_struct("CopyRect")
+                .field("src_x", src_x)
+                .field("src_y", src_y)
+                .finish(),
+            Self::Cursor(cursor) => write!(
+                f,
+                "Cursor({}x{}, hotspot {},{})",
+                cursor.width, cursor.height, cursor.hotspot_x, cursor.hotspot_y
+            ),
+            Self::DesktopSize => f.write_str("DesktopSize"),
+            Self::ExtendedDesktopSize(screens) => {
+                f.debug_tuple("ExtendedDesktopSize").field(screens).finish()
+            }
+            Self::QemuExtendedKeyEvent => f.write_str("QemuExtendedKeyEvent"),
+            Self::QemuAudio => f.write_str("QemuAudio"),
+        }
+    }
+}
+
+impl Rectangle {
+    /// Splits a rectangle of pixels into tiles of at most `size` pixels
+    /// a side, row by row.
+    pub fn tiles(self, size: u16, bytes_per_pixel: usize) -> Vec<Rectangle> {
+        let Payload::Pixels(pixels) = &self.payload else {
+            return vec![self];
+        };
+        if self.width <= size && self.height <= size {
+            return vec![self];
+        }
+        let stride = self.width as usize * bytes_per_pixel;
+        let mut tiles = Vec::new();
+        for y in (0..self.height).step_by(size as usize) {
+            for x in (0..self.width).step_by(size as usize) {
+                let (width, height) = (size.min(self.width - x), size.min(self.height - y));
+                let pixels = (y..y + height)
+                    .flat_map(|row| {
+                        let start = row as usize * stride + x as usize * bytes_per_pixel;
+                        &pixels[start..start + width as usize * bytes_per_pixel]
+                    })
+                    .copied()
+                    .collect();
+                tiles.push(Rectangle {
+                    x: self.x + x,
+                    y: self.y + y,
+                    width,
+                    height,
+                    encoding_type: self.encoding_type,
+                    payload: Payload::Pixels(pixels),
+                });
+            }
+        }
+        tiles
+    }
+
+    /// Size of the pixels of the rectangle in the session pixel format.
+    pub fn pixels_len(&self, context: &SessionContext) -> Result<usize, crate::Error> {
+        Ok(self.width as usize * self.height as usize * context.pixel_format.bytes_per_pixel()?)
+    }
 }
 
 impl std::fmt::Debug for Rectangle {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!(
-            "Rectangle {{ x: {}, y: {}, width: {}, height: {}, encoding_type: {:?} }}",
-            self.x, self.y, self.width, self.height, self.encoding_type
+            "Rectangle {{ x: {}, y: {}, width: {}, height: {}, encoding_type: {:?}, payload: {:?} }}",
+            self.x, self.y, self.width, self.height, self.encoding_type, self.payload
         ))
     }
 }
 
-impl<R: Read> DecodeFrom<R> for Rectangle {
+impl<R: Read> DecodeWith<R, SessionContext> for Rectangle {
     type Error = crate::Error;
-    fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
+    fn decode_with(reader: &mut R, context: &mut SessionContext) -> Result<Self, Self::Error> {
         let mut buf = [0; 8];
 
         reader.read_exact(&mut buf)?;
@@ -39,81 +189,295 @@ impl<R: Read> DecodeFrom<R> for Rectangle {
         let height = u16::from_be_bytes(buf[6..8].try_into().unwrap());
 
         let encoding_type = EncodingType::decode_from(reader)?;
-        let size = (width as usize) * (height as usize) * 4;
-        let mut buf = vec![0; size];
-        reader.read_exact(&mut buf)?;
-        let data = Self {
+        context.check(encoding_type)?;
+        let mut data = Self {
             x,
             y,
             width,
             height,
             encoding_type,
-            pixels: buf,
+            payload: Payload::Pixels(Vec::new()),
+        };
+        data.payload = match encoding_type {
+            EncodingType::Raw => {
+                let mut buf = vec![0; data.pixels_len(context)?];
+                reader.read_exact(&mut buf)?;
+                Payload::Pixels(buf)
+            }
+            EncodingType::CopyRect => Payload::CopyRect {
+                src_x: u16::decode_from(reader)?,
+                src_y: u16::decode_from(reader)?,
+            },
+            EncodingType::Rre | EncodingType::CoRre => Payload::Pixels(rre::decode(
+                reader,
+                context.pixel_format.bytes_per_pixel()?,
+                width,
+                height,
+                encoding_type == EncodingType::CoRre,
+            )?),
+            EncodingType::Hextile => Payload::Pixels(hextile::decode(
+                reader,
+                context.pixel_format.bytes_per_pixel()?,
+                width,
+                height,
+            )?),
+            EncodingType::Zlib => {
+                let len = data.pixels_len(context)?;
+                Payload::Pixels(zlib::decode(reader, &mut context.zlib, len)?)
+            }
+            EncodingType::Tight => Payload::Pixels(tight::decode(
+                reader,
+                &mut context.tight,
+                &context.pixel_format,
+                width,
+                height,
+            )?),
+            EncodingType::Trle => {
+                Payload::Pixels(trle::decode(reader, &context.pixel_format, width, height)?)
+            }
+            EncodingType::Zrle => Payload::Pixels(zrle::decode(
+                reader,
+                &mut context.zrle,
+                &context.pixel_format,
+                width,
+                height,
+            )?),
+            EncodingType::Cursor => Payload::Cursor(cursor::decode(
+                reader,
+                context.pixel_format.bytes_per_pixel()?,
+                (x, y),
+                width,
+                height,
+            )?),
+            EncodingType::XCursor => {
+                Payload::Cursor(cursor::decode_x(reader, (x, y), width, height)?)
+            }
+            EncodingType::DesktopSize => Payload::DesktopSize,
+            EncodingType::ExtendedDesktopSize => {
+                Payload::ExtendedDesktopSize(Vec::<Screen>::decode_from(reader)?)
+            }
+            EncodingType::QemuExtendedKeyEvent => Payload::QemuExtendedKeyEvent,
+            EncodingType::QemuAudio => Payload::QemuAudio,
+            _ => return Err(crate::Error::UnsupportedEncoding),
         };
-        println!("Received: {data:?}");
         Ok(data)
     }
 }
 
-impl<W: Write> EncodeTo<W> for Rectangle {
+impl<W: Write> EncodeWith<W, SessionContext> for Rectangle {
     type Error = crate::Error;
-    fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
-        println!("Sent: {self:?}");
-        let len = self.pixels.len();
+    fn encode_with(
+        self,
+        writer: &mut W,
+        context: &mut SessionContext,
+    ) -> Result<usize, Self::Error> {
+        context.check(self.encoding_type)?;
+        if let Payload::Pixels(pixels) = &self.payload {
+            if pixels.len() != self.pixels_len(context)? {
+                return Err(crate::Error::LengthTooBig);
+            }
+        }
         self.x.encode_to(writer)?;
         self.y.encode_to(writer)?;
         self.width.encode_to(writer)?;
         self.height.encode_to(writer)?;
         self.encoding_type.encode_to(writer)?;
-        writer.write_all(&self.pixels)?;
-        Ok(len * 2 + 8)
+        let len = match (self.encoding_type, self.payload) {
+            (EncodingType::Raw, Payload::Pixels(pixels)) => {
+                writer.write_all(&pixels)?;
+                pixels.len()
+            }
+            (EncodingType::CopyRect, Payload::CopyRect { src_x, src_y }) => {
+                src_x.encode_to(writer)? + src_y.encode_to(writer)?
+            }
+            (
+                encoding_type @ (EncodingType::Rre | EncodingType::CoRre),
+                Payload::Pixels(pixels),
+            ) => rre::encode(
+                writer,
+                context.pixel_format.bytes_per_pixel()?,
+                self.width,
+                self.height,
+                &pixels,
+                encoding_type == EncodingType::CoRre,
+            )?,
+            (EncodingType::Hextile, Payload::Pixels(pixels)) => hextile::encode(
+                writer,
+                context.pixel_format.bytes_per_pixel()?,
+                self.width,
+                self.height,
+                &pixels,
+            )?,
+            (EncodingType::Zlib, Payload::Pixels(pixels)) => {
+                zlib::encode(writer, &mut context.zlib, &pixels)?
+            }
+            (EncodingType::Tight, Payload::Pixels(pixels)) => {
+                let (quality, level) = (context.quality_level(), context.compress_level());
+                tight::encode(
+                    writer,
+                    &mut context.tight,
+                    &context.pixel_format,
+                    self.width,
+                    self.height,
+                    &pixels,
+                    quality,
+                    level,
+                )?
+            }
+            (EncodingType::Cursor, Payload::Cursor(cursor)) => {
+                cursor::encode(writer, context.pixel_format.bytes_per_pixel()?, &cursor)?
+            }
+            (EncodingType::XCursor, Payload::Cursor(cursor)) => cursor::encode_x(writer, &cursor)?,
+            (EncodingType::DesktopSize, Payload::DesktopSize)
+            | (EncodingType::QemuExtendedKeyEvent, Payload::QemuExtendedKeyEvent)
+            | (EncodingType::QemuAudio, Payload::QemuAudio) => 0,
+            (EncodingType::ExtendedDesktopSize, Payload::ExtendedDesktopSize(screens)) => {
+                screens.encode_to(writer)?
+            }
+            (EncodingType::Trle, Payload::Pixels(pixels)) => trle::encode(
+                writer,
+                &context.pixel_format,
+                self.width,
+                self.height,
+                &pixels,
+            )?,
+            (EncodingType::Zrle, Payload::Pixels(pixels)) => zrle::encode(
+                writer,
+                &mut context.zrle,
+                &context.pixel_format,
+                self.width,
+                self.height,
+                &pixels,
+            )?,
+            _ => return Err(crate::Error::UnsupportedEncoding),
+        };
+        Ok(len + 12)
     }
 }
 
-impl<R: Read> DecodeFrom<R> for FramebufferUpdate {
+impl<R: Read> DecodeWith<R, SessionContext> for FramebufferUpdate {
     type Error = crate::Error;
-    fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
+    fn decode_with(reader: &mut R, context: &mut SessionContext) -> Result<Self, Self::Error> {
         <[u8; 2]>::decode_from(reader)?;
 
-        let rectangles = Vec::<Rectangle>::decode_from(reader)?;
-        let data = Self { rectangles };
-        println!("Received: {:?}", data);
-        Ok(data)
+        let rectangles = Vec::<Rectangle>::decode_with(reader, context)?;
+        Ok(Self { rectangles })
     }
 }
 
-impl<W: Write> EncodeTo<W> for FramebufferUpdate {
+impl<W: Write> EncodeWith<W, SessionContext> for FramebufferUpdate {
     type Error = crate::Error;
-    fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
-        println!("Sent: {self:?}");
+    fn encode_with(
+        self,
+        writer: &mut W,
+        context: &mut SessionContext,
+    ) -> Result<usize, Self::Error> {
         writer.write_all(&[0, 1])?;
 
-        Ok(self.rectangles.encode_to(writer)? + 2)
+        Ok(self.rectangles.encode_with(writer, context)? + 2)
     }
 }
 
-impl<R: Read> DecodeFrom<R> for Vec<Rectangle> {
+impl<R: Read> DecodeWith<R, SessionContext> for Vec<Rectangle> {
     type Error = crate::Error;
-    fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
+    fn decode_with(reader: &mut R, context: &mut SessionContext) -> Result<Self, Self::Error> {
         let len = u16::decode_from(reader)? as usize;
         let mut collection = Vec::with_capacity(len);
         for _ in 0..len {
-            collection.push(Rectangle::decode_from(reader)?)
+            collection.push(Rectangle::decode_with(reader, context)?)
         }
-        println!("Received: {:?}", collection);
         Ok(collection)
     }
 }
 
-impl<W: Write> EncodeTo<W> for Vec<Rectangle> {
+impl<W: Write> EncodeWith<W, SessionContext> for Vec<Rectangle> {
     type Error = crate::Error;
-    fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
+    fn encode_with(
+        self,
+        writer: &mut W,
+        context: &mut SessionContext,
+    ) -> Result<usize, Self::Error> {
         let len: u16 = self.len().try_into()?;
         len.encode_to(writer)?;
         let mut len = 2;
         for elem in self {
-            len += elem.encode_to(writer)?;
+            len += elem.encode_with(writer, context)?;
         }
         Ok(len)
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn pixel_format(bits_per_pixel: u8) -> PixelFormat {
+        PixelFormat {
+            bits_per_pixel,
+            depth: bits_per_pixel.min(24),
+            big_endian_flag: false,
+            true_colour_flag: true,
+            red_max: 7,
+            green_max: 7,
+            blue_max: 3,
+            red_shift: 0,
+            green_shift: 3,
+            blue_shift: 6,
+        }
+    }
+
+    #[test]
+    fn raw_pixel_sizes() {
+        for bits_per_pixel in [8, 16, 32] {
+            let mut context = SessionContext::new(pixel_format(bits_per_pixel));
+            let size = 3 * 2 * bits_per_pixel as usize / 8;
+            let update = FramebufferUpdate {
+                rectangles: vec![
+                    Rectangle {
+                        x: 1,
+                        y: 2,
+                        width: 3,
+                        height: 2,
+                        encoding_type: EncodingType::Raw,
+                        payload: Payload::Pixels((0..size as u8).collect()),
+                    },
+                    Rectangle {
+                        x: 0,
+                        y: 0,
+                        width: 1,
+                        height: 1,
+                        encoding_type: EncodingType::Raw,
+                        payload: Payload::Pixels(vec![0xff; bits_per_pixel as usize / 8]),
+                    },
+                ],
+            };
+            let mut buf = Vec::new();
+            let len = FramebufferUpdate {
+                rectangles: update.rectangles.clone(),
+            }
+            .encode_with(&mut buf, &mut context)
+            .unwrap();
+            assert_eq!(len, buf.len());
+            assert_eq!(buf.len(), 4 + 2 * 12 + size + bits_per_pixel as usize / 8);
+            let decoded = FramebufferUpdate::decode_with(&mut &buf[..], &mut context).unwrap();
+            assert_eq!(decoded, update);
+        }
+    }
+
+    #[test]
+    fn unsupported_rectangles() {
+        let mut context = SessionContext::new(pixel_format(16));
+        let rectangle = [0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 5, 0, 0];
+        assert!(matches!(
+            Rectangle::decode_with(&mut &rectangle[..], &mut context),
+            Err(crate::Error::UnsupportedEncoding)
+        ));
+
+        let mut context = SessionContext::new(pixel_format(24));
+        let rectangle = [0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(
+            Rectangle::decode_with(&mut &rectangle[..], &mut context),
+            Err(crate::Error::UnsupportedPixelFormat)
+        ));
+    }
+}
@@ -1,5 +1,5 @@
 use crate::io::*;
-use crate::messages::PixelFormat;
+use crate::messages::{Capability, PixelFormat};
 use std::io::{Read, Write};
 
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
@@ -36,6 +36,9 @@ pub struct ServerInit {
     pub framebuffer_height: u16,
     pub pixel_format: PixelFormat,
     pub name: String,
+    /// Sent after the name when the Tight security type was negotiated, it
+    /// is never read by [`DecodeFrom`] as that depends on the security type.
+    pub interaction_capabilities: Option<InteractionCapabilities>,
 }
 
 impl<R: Read> DecodeFrom<R> for ServerInit {
@@ -54,6 +57,7 @@ impl<R: Read> DecodeFrom<R> for ServerInit {
             framebuffer_height,
             pixel_format,
             name,
+            interaction_capabilities: None,
         };
         println!("Received: {data:?}");
         Ok(data)
@@ -66,8 +70,55 @@ impl<W: Write> EncodeTo<W> for ServerInit {
         self.framebuffer_width.encode_to(writer)?;
         self.framebuffer_height.encode_to(writer)?;
         self.pixel_format.encode_to(writer)?;
-        let len = self.name.encode_to(writer)?;
+        let mut len = self.name.encode_to(writer)?;
+        if let Some(capabilities) = self.interaction_capabilities {
+            len += capabilities.encode_to(writer)?;
+        }
 
         Ok(20 + len)
     }
 }
+
+/// Server messages, client messages and encodings a TightVNC server supports.
+#[derive(Clone, PartialEq, PartialOrd, Debug, Default)]
+pub struct InteractionCapabilities {
+    pub server_messages: Vec<Capability>,
+    pub client_messages: Vec<Capability>,
+    pub encodings: Vec<Capability>,
+}
+
+impl<R: Read> DecodeFrom<R> for InteractionCapabilities {
+    type Error = crate::error::Error;
+    fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
+        let server_messages = u16::decode_from(reader)?;
+        let client_messages = u16::decode_from(reader)?;
+        let encodings = u16::decode_from(reader)?;
+        u16::decode_from(reader)?;
+        let mut list = |len| {
+            (0..len)
+                .map(|_| Capability::decode_from(reader))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Self {
+            server_messages: list(server_messages)?,
+            client_messages: list(client_messages)?,
+            encodings: list(encodings)?,
+        })
+    }
+}
+
+impl<W: Write> EncodeTo<W> for InteractionCapabilities {
+    type Error = crate::Error;
+    fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
+        let lists = [self.server_messages, self.client_messages, self.encodings];
+        for list in &lists {
+            u16::try_from(list.len())?.encode_to(writer)?;
+        }
+        0u16.encode_to(writer)?;
+        let mut len = 8;
+        for capability in lists.into_iter().flatten() {
+            len += capability.encode_to(writer)?;
+        }
+        Ok(len)
+    }
+}
@@ -1,8 +1,9 @@
 use crate::io::{Decode, Encode, Length};
 
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
 pub struct KeyEvent {
-    down: bool,
-    key: u32,
+    pub down: bool,
+    pub key: u32,
 }
 
 impl Length for KeyEvent {
@@ -27,6 +28,66 @@ impl Encode for KeyEvent {
     }
 }
 
+/// Key event of the QEMU Extended Key Event pseudo-encoding, carrying the XT
+/// scancode of the physical key along with its keysym so that the server
+/// does not depend on the client keyboard layout.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct QemuExtendedKeyEvent {
+    pub down: bool,
+    pub keysym: u32,
+    /// XT scancode, extended ones with the high bit of the low byte set in
+    /// place of the 0xE0 prefix.
+    pub keycode: u32,
+}
+
+impl QemuExtendedKeyEvent {
+    /// Event for `keysym` typed on a US keyboard, see [`us_scancode`].
+    pub fn us(down: bool, keysym: u32) -> Option<Self> {
+        Some(Self {
+            down,
+            keysym,
+            keycode: us_scancode(keysym)?,
+        })
+    }
+}
+
+impl Length for QemuExtendedKeyEvent {
+    const LENGTH: usize = 12;
+}
+
+impl Decode for QemuExtendedKeyEvent {
+    type Error = crate::Error;
+    fn decode(data: [u8; <Self as Length>::LENGTH]) -> Result<Self, Self::Error> {
+        Ok(Self {
+            down: u16::from_be_bytes([data[2], data[3]]) != 0,
+            keysym: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            keycode: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+        })
+    }
+}
+
+impl Encode for QemuExtendedKeyEvent {
+    type Error = crate::Error;
+    fn encode(self) -> Result<[u8; <Self as Length>::LENGTH], Self::Error> {
+        let keysym = self.keysym.to_be_bytes();
+        let keycode = self.keycode.to_be_bytes();
+        Ok([
+            255,
+            0,
+            0,
+            self.down as u8,
+            keysym[0],
+            keysym[1],
+            keysym[2],
+            keysym[3],
+            keycode[0],
+            keycode[1],
+            keycode[2],
+            keycode[3],
+        ])
+    }
+}
+
 #[repr(u32)]
 #[derive(Debug, PartialEq, Default, PartialOrd)]
 pub enum Key {
@@ -271,3 +332,116 @@ impl Decode for Key {
         })
     }
 }
+
+/// XT scancode of the key typing `keysym` on a US keyboard, in the form of
+/// [`QemuExtendedKeyEvent::keycode`]. Shifted characters give the scancode
+/// of their key, Shift has to be pressed separately.
+pub fn us_scancode(keysym: u32) -> Option<u32> {
+    const ROWS: [(&[u8], &[u8], u32); 4] = [
+        (b"1234567890-=", b"!@#$%^&*()_+", 0x02),
+        (b"qwertyuiop[]", b"QWERTYUIOP{}", 0x10),
+        (b"asdfghjkl;'`", b"ASDFGHJKL:\"~", 0x1e),
+        (b"\\zxcvbnm,./", b"|ZXCVBNM<>?", 0x2b),
+    ];
+    if let Ok(c) = u8::try_from(keysym) {
+        return match c {
+            b' ' => Some(0x39),
+            _ => ROWS.iter().find_map(|(keys, shifted, first)| {
+                let i = keys.iter().chain(shifted.iter()).position(|&k| k == c)?;
+                Some(first + (i % keys.len()) as u32)
+            }),
+        };
+    }
+    use Key::*;
+    Some(match Key::decode(keysym.to_be_bytes()).ok()? {
+        Escape => 0x01,
+        BackSpace => 0x0e,
+        Tab => 0x0f,
+        Return => 0x1c,
+        ControlL => 0x1d,
+        ShiftL => 0x2a,
+        ShiftR => 0x36,
+        KeypadMultiply => 0x37,
+        AltL => 0x38,
+        CapsLock => 0x3a,
+        F1 => 0x3b,
+        F2 => 0x3c,
+        F3 => 0x3d,
+        F4 => 0x3e,
+        F5 => 0x3f,
+        F6 => 0x40,
+        F7 => 0x41,
+        F8 => 0x42,
+        F9 => 0x43,
+        F10 => 0x44,
+        NumLock => 0x45,
+        ScrollLock => 0x46,
+        Keypad7 | KeypadHome => 0x47,
+        Keypad8 | KeypadUp => 0x48,
+        Keypad9 | KeypadPrior => 0x49,
+        KeypadSubtract => 0x4a,
+        Keypad4 | KeypadLeft => 0x4b,
+        Keypad5 | KeypadBegin => 0x4c,
+        Keypad6 | KeypadRight => 0x4d,
+        KeypadAdd => 0x4e,
+        Keypad1 | KeypadEnd => 0x4f,
+        Keypad2 | KeypadDown => 0x50,
+        Keypad3 | KeypadNext => 0x51,
+        Keypad0 | KeypadInsert => 0x52,
+        KeypadDecimal | KeypadDelete => 0x53,
+        F11 => 0x57,
+        F12 => 0x58,
+        // Extended keys, prefixed with 0xE0.
+        KeypadEnter => 0x9c,
+        ControlR => 0x9d,
+        KeypadDivide => 0xb5,
+        Print | SysReq => 0xb7,
+        AltR => 0xb8,
+        Home => 0xc7,
+        Up => 0xc8,
+        PageUp => 0xc9,
+        Left => 0xcb,
+        Right => 0xcd,
+        End => 0xcf,
+        Down => 0xd0,
+        PageDown => 0xd1,
+        Insert => 0xd2,
+        Delete => 0xd3,
+        MetaL => 0xdb,
+        MetaR => 0xdc,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn us_scancodes() {
+        for (keysym, keycode) in [
+            (b'a' as u32, 0x1e),
+            (b'A' as u32, 0x1e),
+            (b'0' as u32, 0x0b),
+            (b')' as u32, 0x0b),
+            (b'\\' as u32, 0x2b),
+            (b'|' as u32, 0x2b),
+            (b'/' as u32, 0x35),
+            (b'~' as u32, 0x29),
+            (b' ' as u32, 0x39),
+            (Key::Return as u32, 0x1c),
+            (Key::F12 as u32, 0x58),
+            (Key::KeypadEnter as u32, 0x9c),
+            (Key::Delete as u32, 0xd3),
+        ] {
+            assert_eq!(us_scancode(keysym), Some(keycode), "{keysym:#x}");
+        }
+        assert_eq!(us_scancode(0xe9), None);
+        assert_eq!(us_scancode(Key::VoidSymbol as u32), None);
+
+        let event = QemuExtendedKeyEvent::us(true, Key::Up as u32).unwrap();
+        let data = event.clone().encode().unwrap();
+        assert_eq!(data, [255, 0, 0, 1, 0, 0, 0xff, 0x52, 0, 0, 0, 0xc8]);
+        assert_eq!(QemuExtendedKeyEvent::decode(data).unwrap(), event);
+    }
+}
@@ -15,12 +15,42 @@ pub use framebuffer_update::*;
 pub mod framebuffer_update_request;
 pub use framebuffer_update_request::*;
 
+pub mod tight;
+pub use tight::*;
+
 pub mod key_event;
 pub use key_event::*;
 
 pub mod set_encodings;
 pub use set_encodings::*;
 
+pub mod pointer_event;
+pub use pointer_event::*;
+
+pub mod client_message;
+pub use client_message::*;
+
+pub mod desktop_size;
+pub use desktop_size::*;
+
+pub mod fence;
+pub use fence::*;
+
+pub mod colour_map;
+pub use colour_map::*;
+
+pub mod continuous_updates;
+pub use continuous_updates::*;
+
+pub mod qemu_audio;
+pub use qemu_audio::*;
+
+pub mod clipboard;
+pub use clipboard::*;
+
+pub mod server_message;
+pub use server_message::*;
+
 use crate::io::*;
 
 pub trait Message<R: Read, W: Write>: EncodeTo<W> + DecodeFrom<R> {
@@ -41,6 +71,9 @@ pub enum Version {
 impl Length for Version {
     const LENGTH: usize = 12;
 }
+
+/// Decodes any version string as the protocol it stands for, see
+/// [`ProtocolVersion`].
 impl Decode for Version {
     type Error = crate::error::Error;
     fn decode(data: [u8; 12]) -> Result<Self, Self::Error> {
@@ -48,11 +81,81 @@ impl Decode for Version {
             RFB33 => Ok(Self::Rfb33),
             RFB37 => Ok(Self::Rfb37),
             RFB38 => Ok(Self::Rfb38),
+            _ => ProtocolVersion::decode(data)?.try_into(),
+        }
+    }
+}
+
+/// Version exactly as announced in a `RFB xxx.yyy\n` string, e.g. 3.889 for
+/// Apple or 4.1 for RealVNC.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct ProtocolVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl Length for ProtocolVersion {
+    const LENGTH: usize = 12;
+}
+
+impl Decode for ProtocolVersion {
+    type Error = crate::error::Error;
+    fn decode(data: [u8; 12]) -> Result<Self, Self::Error> {
+        let number = |digits: &[u8]| {
+            digits.iter().try_fold(0, |n, &d| {
+                d.is_ascii_digit().then(|| n * 10 + (d - b'0') as u16)
+            })
+        };
+        match (
+            &data[..4],
+            number(&data[4..7]),
+            data[7],
+            number(&data[8..11]),
+            data[11],
+        ) {
+            (b"RFB ", Some(major), b'.', Some(minor), b'\n') => Ok(Self { major, minor }),
             _ => Err(Self::Error::UnsupportedVersion),
         }
     }
 }
 
+impl Encode for ProtocolVersion {
+    type Error = crate::error::Error;
+    fn encode(self) -> Result<[u8; 12], Self::Error> {
+        if self.major > 999 || self.minor > 999 {
+            return Err(Self::Error::LengthTooBig);
+        }
+        let mut data = [0; 12];
+        data.copy_from_slice(format!("RFB {:03}.{:03}\n", self.major, self.minor).as_bytes());
+        Ok(data)
+    }
+}
+
+impl From<Version> for ProtocolVersion {
+    fn from(value: Version) -> Self {
+        let minor = match value {
+            Version::Rfb33 => 3,
+            Version::Rfb37 => 7,
+            Version::Rfb38 => 8,
+        };
+        Self { major: 3, minor }
+    }
+}
+
+/// The protocol to speak with a peer announcing this version: the highest one
+/// supported below it. 3.5, reported by some old clients, is 3.3.
+impl TryFrom<ProtocolVersion> for Version {
+    type Error = crate::error::Error;
+    fn try_from(value: ProtocolVersion) -> Result<Self, Self::Error> {
+        match (value.major, value.minor) {
+            (0..=2, _) | (3, 0..=2) => Err(Self::Error::UnsupportedVersion),
+            (3, 3..=6) => Ok(Self::Rfb33),
+            (3, 7) => Ok(Self::Rfb37),
+            _ => Ok(Self::Rfb38),
+        }
+    }
+}
+
 impl Encode for Version {
     type Error = crate::error::Error;
     fn encode(self) -> Result<[u8; 12], Self::Error> {
@@ -117,3 +220,35 @@ impl From<u8> for MessageType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        for (announced, major, minor, version) in [
+            (b"RFB 003.003\n", 3, 3, Version::Rfb33),
+            (b"RFB 003.005\n", 3, 5, Version::Rfb33),
+            (b"RFB 003.007\n", 3, 7, Version::Rfb37),
+            (b"RFB 003.889\n", 3, 889, Version::Rfb38),
+            (b"RFB 004.001\n", 4, 1, Version::Rfb38),
+        ] {
+            let protocol = ProtocolVersion::decode(*announced).unwrap();
+            assert_eq!(protocol, ProtocolVersion { major, minor });
+            assert_eq!(protocol.encode().unwrap(), *announced);
+            assert_eq!(Version::try_from(protocol).unwrap(), version);
+            assert_eq!(Version::decode(*announced).unwrap(), version);
+        }
+        for invalid in [b"RFB 003.002\n", b"RFB 03.0008\n", b"RFB 003.008 "] {
+            assert!(matches!(
+                Version::decode(*invalid),
+                Err(crate::Error::UnsupportedVersion)
+            ));
+        }
+        assert_eq!(
+            ProtocolVersion::from(Version::Rfb37).encode().unwrap(),
+            RFB37
+        );
+    }
+}
@@ -1,5 +1,4 @@
 use crate::io::*;
-use std::convert::Infallible;
 #[derive(Clone, PartialEq, PartialOrd, Debug)]
 
 pub struct PixelFormat {
@@ -63,3 +62,130 @@ impl Encode for PixelFormat {
         ])
     }
 }
+
+impl PixelFormat {
+    /// Size of a pixel on the wire, the protocol allows 8, 16 and 32 bits.
+    pub fn bytes_per_pixel(&self) -> Result<usize, crate::Error> {
+        match self.bits_per_pixel {
+            8 | 16 | 32 => Ok(self.bits_per_pixel as usize / 8),
+            _ => Err(crate::Error::UnsupportedPixelFormat),
+        }
+    }
+
+    /// Bytes of a pixel sent as a CPIXEL by ZRLE and TRLE: the three bytes
+    /// holding the colour of 32 bits true colour formats with a depth of at
+    /// most 24, the whole pixel otherwise.
+    pub fn compressed_pixel_bytes(&self) -> Result<std::ops::Range<usize>, crate::Error> {
+        let bytes_per_pixel = self.bytes_per_pixel()?;
+        if bytes_per_pixel != 4 || !self.true_colour_flag || self.depth > 24 {
+            return Ok(0..bytes_per_pixel);
+        }
+        let mask = [
+            (self.red_max, self.red_shift),
+            (self.green_max, self.green_shift),
+            (self.blue_max, self.blue_shift),
+        ]
+        .iter()
+        .fold(0u64, |mask, &(max, shift)| {
+            mask | (max as u64).checked_shl(shift as u32).unwrap_or(u64::MAX)
+        });
+        // The bytes are the first or last ones depending on the byte order.
+        match (mask & !0xff_ffff == 0, mask & !0xffff_ff00 == 0) {
+            (true, _) if self.big_endian_flag => Ok(1..4),
+            (true, _) => Ok(0..3),
+            (_, true) if self.big_endian_flag => Ok(0..3),
+            (_, true) => Ok(1..4),
+            _ => Ok(0..4),
+        }
+    }
+
+    /// Value of a pixel of this format.
+    pub fn value(&self, pixel: &[u8]) -> u32 {
+        let fold = |value: u32, byte: &u8| value << 8 | *byte as u32;
+        match self.big_endian_flag {
+            true => pixel.iter().fold(0, fold),
+            false => pixel.iter().rev().fold(0, fold),
+        }
+    }
+
+    /// Pixel of this format with the given value.
+    pub fn pixel(&self, value: u32) -> Vec<u8> {
+        let bytes = &value.to_be_bytes()[4 - self.bits_per_pixel as usize / 8..];
+        match self.big_endian_flag {
+            true => bytes.to_vec(),
+            false => bytes.iter().rev().copied().collect(),
+        }
+    }
+
+    fn channels(&self) -> [(u16, u8); 3] {
+        [
+            (self.red_max, self.red_shift),
+            (self.green_max, self.green_shift),
+            (self.blue_max, self.blue_shift),
+        ]
+    }
+
+    /// Red, green and blue components of a true colour pixel value.
+    pub fn components(&self, value: u32) -> [u16; 3] {
+        self.channels()
+            .map(|(max, shift)| (value.checked_shr(shift as u32).unwrap_or(0) & max as u32) as u16)
+    }
+
+    /// True colour pixel value with the given components.
+    pub fn from_components(&self, components: [u16; 3]) -> u32 {
+        self.channels()
+            .iter()
+            .zip(components)
+            .fold(0, |value, (&(max, shift), component)| {
+                let component = component.min(max) as u32;
+                value | component.checked_shl(shift as u32).unwrap_or(0)
+            })
+    }
+
+    /// Colour of a true colour pixel with 8 bits components.
+    pub fn rgb(&self, pixel: &[u8]) -> [u8; 3] {
+        let components = self.components(self.value(pixel));
+        let channels = self.channels();
+        std::array::from_fn(|i| match channels[i].0 {
+            0 => 0,
+            max => ((components[i] as u32 * 255 + max as u32 / 2) / max as u32) as u8,
+        })
+    }
+
+    /// True colour pixel of the closest colour to 8 bits components.
+    pub fn from_rgb(&self, rgb: [u8; 3]) -> Vec<u8> {
+        let channels = self.channels();
+        let components =
+            std::array::from_fn(|i| ((rgb[i] as u32 * channels[i].0 as u32 + 127) / 255) as u16);
+        self.pixel(self.from_components(components))
+    }
+}
+
+#[derive(Clone, PartialEq, PartialOrd, Debug)]
+pub struct SetPixelFormat {
+    pub pixel_format: PixelFormat,
+}
+
+impl Length for SetPixelFormat {
+    const LENGTH: usize = 20;
+}
+
+impl Decode for SetPixelFormat {
+    type Error = crate::Error;
+    fn decode(data: [u8; 20]) -> Result<Self, Self::Error> {
+        let mut pixel_format = [0; 16];
+        pixel_format.copy_from_slice(&data[4..]);
+        Ok(Self {
+            pixel_format: PixelFormat::decode(pixel_format)?,
+        })
+    }
+}
+
+impl Encode for SetPixelFormat {
+    type Error = crate::Error;
+    fn encode(self) -> Result<[u8; 20], Self::Error> {
+        let mut data = [0; 20];
+        data[4..].copy_from_slice(&self.pixel_format.encode()?);
+        Ok(data)
+    }
+}
@@ -0,0 +1,33 @@
+use crate::io::{Decode, Encode, Length};
+
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct PointerEvent {
+    /// Buttons 1 to 8 as bits 0 to 7, set while pressed.
+    pub button_mask: u8,
+    pub x: u16,
+    pub y: u16,
+}
+
+impl Length for PointerEvent {
+    const LENGTH: usize = 6;
+}
+
+impl Decode for PointerEvent {
+    type Error = crate::Error;
+    fn decode(data: [u8; <Self as Length>::LENGTH]) -> Result<Self, Self::Error> {
+        Ok(Self {
+            button_mask: data[1],
+            x: u16::from_be_bytes([data[2], data[3]]),
+            y: u16::from_be_bytes([data[4], data[5]]),
+        })
+    }
+}
+
+impl Encode for PointerEvent {
+    type Error = crate::Error;
+    fn encode(self) -> Result<[u8; <Self as Length>::LENGTH], Self::Error> {
+        let x = self.x.to_be_bytes();
+        let y = self.y.to_be_bytes();
+        Ok([5, self.button_mask, x[0], x[1], y[0], y[1]])
+    }
+}
@@ -0,0 +1,342 @@
+use std::io::{Read, Write};
+
+use crate::io::*;
+use crate::messages::SessionContext;
+
+/// Largest buffer of samples accepted, a second of 8 channels of 32-bit
+/// samples at 192 kHz.
+pub const MAX_AUDIO_DATA: usize = 8 * 4 * 192000;
+
+/// Type of the samples of an audio stream, little-endian when wider than a
+/// byte.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Copy)]
+pub enum SampleFormat {
+    U8,
+    S8,
+    U16,
+    S16,
+    U32,
+    S32,
+}
+
+impl SampleFormat {
+    pub fn bytes(&self) -> usize {
+        match self {
+            Self::U8 | Self::S8 => 1,
+            Self::U16 | Self::S16 => 2,
+            Self::U32 | Self::S32 => 4,
+        }
+    }
+}
+
+impl TryFrom<u8> for SampleFormat {
+    type Error = crate::Error;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::U8,
+            1 => Self::S8,
+            2 => Self::U16,
+            3 => Self::S16,
+            4 => Self::U32,
+            5 => Self::S32,
+            _ => return Err(crate::Error::BadResponse),
+        })
+    }
+}
+
+impl From<SampleFormat> for u8 {
+    fn from(value: SampleFormat) -> Self {
+        value as u8
+    }
+}
+
+/// Format of the audio stream, set by the client.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Copy)]
+pub struct AudioFormat {
+    pub sample_format: SampleFormat,
+    pub channels: u8,
+    /// Frames per second.
+    pub frequency: u32,
+}
+
+/// The format QEMU uses until the client sets one.
+impl Default for AudioFormat {
+    fn default() -> Self {
+        Self {
+            sample_format: SampleFormat::S16,
+            channels: 2,
+            frequency: 44100,
+        }
+    }
+}
+
+impl Length for AudioFormat {
+    const LENGTH: usize = 6;
+}
+
+impl Decode for AudioFormat {
+    type Error = crate::Error;
+    fn decode(data: [u8; <Self as Length>::LENGTH]) -> Result<Self, Self::Error> {
+        Ok(Self {
+            sample_format: data[0].try_into()?,
+            channels: data[1],
+            frequency: u32::from_be_bytes([data[2], data[3], data[4], data[5]]),
+        })
+    }
+}
+
+impl Encode for AudioFormat {
+    type Error = crate::Error;
+    fn encode(self) -> Result<[u8; <Self as Length>::LENGTH], Self::Error> {
+        let frequency = self.frequency.to_be_bytes();
+        Ok([
+            self.sample_format.into(),
+            self.channels,
+            frequency[0],
+            frequency[1],
+            frequency[2],
+            frequency[3],
+        ])
+    }
+}
+
+/// PCM samples, the channels of a frame one after the other.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum Samples {
+    U8(Vec<u8>),
+    S8(Vec<i8>),
+    U16(Vec<u16>),
+    S16(Vec<i16>),
+    U32(Vec<u32>),
+    S32(Vec<i32>),
+}
+
+impl Samples {
+    pub fn sample_format(&self) -> SampleFormat {
+        match self {
+            Self::U8(_) => SampleFormat::U8,
+            Self::S8(_) => SampleFormat::S8,
+            Self::U16(_) => SampleFormat::U16,
+            Self::S16(_) => SampleFormat::S16,
+            Self::U32(_) => SampleFormat::U32,
+            Self::S32(_) => SampleFormat::S32,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::U8(samples) => samples.len(),
+            Self::S8(samples) => samples.len(),
+            Self::U16(samples) => samples.len(),
+            Self::S16(samples) => samples.len(),
+            Self::U32(samples) => samples.len(),
+            Self::S32(samples) => samples.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Samples of `sample_format` in `data`, whose length must be a multiple
+    /// of the sample size.
+    pub fn decode(sample_format: SampleFormat, data: Vec<u8>) -> Result<Self, crate::Error> {
+        if !data.len().is_multiple_of(sample_format.bytes()) {
+            return Err(crate::Error::BadResponse);
+        }
+        let words = || data.chunks_exact(2).map(|c| [c[0], c[1]]);
+        let dwords = || data.chunks_exact(4).map(|c| [c[0], c[1], c[2], c[3]]);
+        Ok(match sample_format {
+            SampleFormat::U8 => Self::U8(data),
+            SampleFormat::S8 => Self::S8(data.iter().map(|&b| b as i8).collect()),
+            SampleFormat::U16 => Self::U16(words().map(u16::from_le_bytes).collect()),
+            SampleFormat::S16 => Self::S16(words().map(i16::from_le_bytes).collect()),
+            SampleFormat::U32 => Self::U32(dwords().map(u32::from_le_bytes).collect()),
+            SampleFormat::S32 => Self::S32(dwords().map(i32::from_le_bytes).collect()),
+        })
+    }
+
+    pub fn encode(self) -> Vec<u8> {
+        match self {
+            Self::U8(samples) => samples,
+            Self::S8(samples) => samples.into_iter().map(|s| s as u8).collect(),
+            Self::U16(samples) => samples.into_iter().flat_map(u16::to_le_bytes).collect(),
+            Self::S16(samples) => samples.into_iter().flat_map(i16::to_le_bytes).collect(),
+            Self::U32(samples) => samples.into_iter().flat_map(u32::to_le_bytes).collect(),
+            Self::S32(samples) => samples.into_iter().flat_map(i32::to_le_bytes).collect(),
+        }
+    }
+}
+
+/// Samples received from the server, in the format of the stream.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct AudioBuffer {
+    pub format: AudioFormat,
+    pub samples: Samples,
+}
+
+/// QEMU audio message of the client, controlling the stream.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum QemuAudioClientMessage {
+    Enable,
+    Disable,
+    SetFormat(AudioFormat),
+}
+
+impl<R: Read> DecodeFrom<R> for QemuAudioClientMessage {
+    type Error = crate::Error;
+    fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
+        let [_, _, operation @ ..] = <[u8; 4]>::decode_from(reader)?;
+        Ok(match u16::from_be_bytes(operation) {
+            0 => Self::Enable,
+            1 => Self::Disable,
+            2 => Self::SetFormat(AudioFormat::decode_from(reader)?),
+            _ => return Err(crate::Error::UnsupportedMessage(255)),
+        })
+    }
+}
+
+impl<W: Write> EncodeTo<W> for QemuAudioClientMessage {
+    type Error = crate::Error;
+    fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
+        let operation: u8 = match self {
+            Self::Enable => 0,
+            Self::Disable => 1,
+            Self::SetFormat(_) => 2,
+        };
+        [255, 1, 0, operation].encode_to(writer)?;
+        match self {
+            Self::SetFormat(format) => Ok(4 + format.encode_to(writer)?),
+            _ => Ok(4),
+        }
+    }
+}
+
+/// QEMU audio message of the server: the stream begins or ends, or samples
+/// of it in the format set by the client.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum QemuAudioServerMessage {
+    End,
+    Begin,
+    Data(AudioBuffer),
+}
+
+impl<R: Read> DecodeWith<R, SessionContext> for QemuAudioServerMessage {
+    type Error = crate::Error;
+    fn decode_with(reader: &mut R, context: &mut SessionContext) -> Result<Self, Self::Error> {
+        let [_, _, operation @ ..] = <[u8; 4]>::decode_from(reader)?;
+        Ok(match u16::from_be_bytes(operation) {
+            0 => Self::End,
+            1 => Self::Begin,
+            2 => {
+                let len = u32::decode_from(reader)? as usize;
+                if len > MAX_AUDIO_DATA {
+                    return Err(crate::Error::BadResponse);
+                }
+                let mut data = vec![0; len];
+                reader.read_exact(&mut data)?;
+                let format = context.audio_format;
+                Self::Data(AudioBuffer {
+                    format,
+                    samples: Samples::decode(format.sample_format, data)?,
+                })
+            }
+            _ => return Err(crate::Error::UnsupportedMessage(255)),
+        })
+    }
+}
+
+impl<W: Write> EncodeWith<W, SessionContext> for QemuAudioServerMessage {
+    type Error = crate::Error;
+    fn encode_with(
+        self,
+        writer: &mut W,
+        context: &mut SessionContext,
+    ) -> Result<usize, Self::Error> {
+        let (operation, data): (u8, _) = match self {
+            Self::End => (0, None),
+            Self::Begin => (1, None),
+            Self::Data(buffer) => {
+                if buffer.format != context.audio_format
+                    || buffer.samples.sample_format() != buffer.format.sample_format
+                {
+                    return Err(crate::Error::UnsupportedEncoding);
+                }
+                (2, Some(buffer.samples.encode()))
+            }
+        };
+        [255, 1, 0, operation].encode_to(writer)?;
+        let Some(data) = data else {
+            return Ok(4);
+        };
+        if data.len() > MAX_AUDIO_DATA {
+            return Err(crate::Error::LengthTooBig);
+        }
+        let len = data.len() as u32;
+        len.encode_to(writer)?;
+        writer.write_all(&data)?;
+        Ok(8 + data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::framebuffer_update::tests::pixel_format;
+
+    #[test]
+    fn audio_data() {
+        let mut context = SessionContext::new(pixel_format(32));
+        let message = QemuAudioClientMessage::SetFormat(AudioFormat {
+            sample_format: SampleFormat::S16,
+            channels: 1,
+            frequency: 8000,
+        });
+        let mut buf = Vec::new();
+        assert_eq!(message.clone().encode_to(&mut buf).unwrap(), 10);
+        assert_eq!(buf, [255, 1, 0, 2, 3, 1, 0, 0, 0x1f, 0x40]);
+        assert_eq!(
+            QemuAudioClientMessage::decode_from(&mut buf.as_slice()).unwrap(),
+            message
+        );
+
+        context.audio_format = AudioFormat {
+            channels: 1,
+            frequency: 8000,
+            ..Default::default()
+        };
+        let message = QemuAudioServerMessage::Data(AudioBuffer {
+            format: context.audio_format,
+            samples: Samples::S16(vec![1, -2]),
+        });
+        let mut buf = Vec::new();
+        assert_eq!(
+            message.clone().encode_with(&mut buf, &mut context).unwrap(),
+            12
+        );
+        assert_eq!(buf, [255, 1, 0, 2, 0, 0, 0, 4, 1, 0, 0xfe, 0xff]);
+        let decoded = QemuAudioServerMessage::decode_with(&mut buf.as_slice(), &mut context);
+        assert_eq!(decoded.unwrap(), message);
+
+        // Oversized and partial samples and buffers in another format are
+        // refused.
+        let data = [255, 1, 0, 2, 0xff, 0xff, 0xff, 0xfe];
+        assert!(matches!(
+            QemuAudioServerMessage::decode_with(&mut data.as_slice(), &mut context),
+            Err(crate::Error::BadResponse)
+        ));
+        let data = [255, 1, 0, 2, 0, 0, 0, 3, 1, 0, 0xfe];
+        assert!(matches!(
+            QemuAudioServerMessage::decode_with(&mut data.as_slice(), &mut context),
+            Err(crate::Error::BadResponse)
+        ));
+        let message = QemuAudioServerMessage::Data(AudioBuffer {
+            format: context.audio_format,
+            samples: Samples::U8(vec![1]),
+        });
+        assert!(matches!(
+            message.encode_with(&mut Vec::new(), &mut context),
+            Err(crate::Error::UnsupportedEncoding)
+        ));
+    }
+}
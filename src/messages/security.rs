@@ -1,14 +1,20 @@
 use std::io::{Read, Write};
 
 use crate::{error::Error, io::*};
-#[repr(u8)]
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+/// A security type byte. Types registered as ranges and unknown types keep
+/// their value, so that every byte decodes and encodes back unchanged.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub enum SecurityType {
     Invalid,
     None,
     VncAuthentication,
-    RealVnc,
-    Tight = 16,
+    /// 3, 4, 9 to 15, 128, 131 to 133 and 192.
+    RealVnc(u8),
+    Ra2,
+    Ra2ne,
+    Sspi,
+    SspiNe,
+    Tight,
     Ultra,
     Tls,
     VeNCrypt,
@@ -17,22 +23,27 @@ pub enum SecurityType {
     ColinDeanXvp,
     SecureTunnel,
     IntegratedSsh,
-    #[default]
-    Unassigned,
-    AppleInc = 30,
-    TightUnixLoginAuthentication,
+    /// 30 to 35, 30 being Apple Remote Desktop.
+    AppleInc(u8),
+    Ra256,
+    RaNe256,
+    Unassigned(u8),
 }
 
-impl Decode for SecurityType {
-    type Error = crate::error::Error;
-    fn decode(data: [u8; 1]) -> Result<Self, Self::Error> {
+impl From<u8> for SecurityType {
+    fn from(value: u8) -> Self {
         use SecurityType::*;
-        let value = data[0];
-        Ok(match value {
+        match value {
             0 => Invalid,
             1 => None,
             2 => VncAuthentication,
-            3..15 | 128 | 130..134 | 192 => RealVnc,
+            5 => Ra2,
+            6 => Ra2ne,
+            7 => Sspi,
+            8 => SspiNe,
+            129 => Ra256,
+            130 => RaNe256,
+            3 | 4 | 9..=15 | 128 | 131..=133 | 192 => RealVnc(value),
             16 => Tight,
             17 => Ultra,
             18 => Tls,
@@ -42,10 +53,50 @@ impl Decode for SecurityType {
             22 => ColinDeanXvp,
             23 => SecureTunnel,
             24 => IntegratedSsh,
-            30..36 => AppleInc,
-            129 => TightUnixLoginAuthentication,
-            _ => Unassigned,
-        })
+            30..=35 => AppleInc(value),
+            _ => Unassigned(value),
+        }
+    }
+}
+
+/// Fails with `IncompatibleSecurity` if `value` holds a number of another
+/// type, which would not decode back to it.
+impl TryFrom<SecurityType> for u8 {
+    type Error = Error;
+    fn try_from(value: SecurityType) -> Result<Self, Self::Error> {
+        use SecurityType::*;
+        let number = match value {
+            Invalid => 0,
+            None => 1,
+            VncAuthentication => 2,
+            Ra2 => 5,
+            Ra2ne => 6,
+            Sspi => 7,
+            SspiNe => 8,
+            Tight => 16,
+            Ultra => 17,
+            Tls => 18,
+            VeNCrypt => 19,
+            GtkVncSasl => 20,
+            Md5HashAuthentication => 21,
+            ColinDeanXvp => 22,
+            SecureTunnel => 23,
+            IntegratedSsh => 24,
+            Ra256 => 129,
+            RaNe256 => 130,
+            RealVnc(value) | AppleInc(value) | Unassigned(value) => value,
+        };
+        match SecurityType::from(number) == value {
+            true => Ok(number),
+            false => Err(Error::IncompatibleSecurity),
+        }
+    }
+}
+
+impl Decode for SecurityType {
+    type Error = crate::error::Error;
+    fn decode(data: [u8; 1]) -> Result<Self, Self::Error> {
+        Ok(data[0].into())
     }
 }
 impl Length for SecurityType {
@@ -55,7 +106,7 @@ impl Length for SecurityType {
 impl Encode for SecurityType {
     type Error = crate::Error;
     fn encode(self) -> Result<[u8; <Self as Length>::LENGTH], Self::Error> {
-        Ok([self as u8])
+        Ok([u8::try_from(self)?])
     }
 }
 
@@ -78,11 +129,13 @@ impl<W: Write> EncodeTo<W> for Vec<SecurityType> {
     fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
         println!("Sent: {self:?}");
         let len: u8 = self.len().try_into()?;
+        // Nothing is written if one of the types cannot be encoded.
+        let security_types = self
+            .into_iter()
+            .map(u8::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
         len.encode_to(writer)?;
-
-        for security_type in self {
-            security_type.encode_to(writer)?;
-        }
+        writer.write_all(&security_types)?;
         Ok(1 + len as usize)
     }
 }
@@ -101,7 +154,7 @@ impl Length for SecurityResult {
 impl Decode for SecurityResult {
     type Error = Error;
     fn decode(data: [u8; 4]) -> Result<Self, Self::Error> {
-        match data[0] {
+        match u32::from_be_bytes(data) {
             0 => Ok(Self::Ok),
             1 => Ok(Self::Failed),
             2 => Ok(Self::TooManyAttempts),
@@ -115,3 +168,52 @@ impl Encode for SecurityResult {
         Ok((self as u32).to_be_bytes())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn security_types_round_trip() {
+        for value in 0..=255u8 {
+            let security_type = SecurityType::decode([value]).unwrap();
+            assert_eq!(security_type.encode().unwrap(), [value]);
+        }
+        let mut buf = Vec::new();
+        vec![3, 31, 129, 200]
+            .into_iter()
+            .map(SecurityType::from)
+            .collect::<Vec<_>>()
+            .encode_to(&mut buf)
+            .unwrap();
+        assert_eq!(buf, [4, 3, 31, 129, 200]);
+        assert_eq!(
+            Vec::<SecurityType>::decode_from(&mut &buf[..]).unwrap(),
+            [
+                SecurityType::RealVnc(3),
+                SecurityType::AppleInc(31),
+                SecurityType::Ra256,
+                SecurityType::Unassigned(200)
+            ]
+        );
+        for security_type in [
+            SecurityType::RealVnc(1),
+            SecurityType::RealVnc(5),
+            SecurityType::AppleInc(2),
+            SecurityType::Unassigned(16),
+            SecurityType::Unassigned(129),
+        ] {
+            assert!(
+                matches!(
+                    u8::try_from(security_type),
+                    Err(Error::IncompatibleSecurity)
+                ),
+                "{security_type:?}"
+            );
+        }
+        let mut buf = Vec::new();
+        let security_types = vec![SecurityType::None, SecurityType::AppleInc(2)];
+        assert!(security_types.encode_to(&mut buf).is_err());
+        assert!(buf.is_empty());
+    }
+}
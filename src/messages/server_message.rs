@@ -0,0 +1,68 @@
+use std::io::{Read, Write};
+
+use crate::io::*;
+use crate::messages::*;
+
+/// A message sent by the server once the session is initialised.
+#[derive(Debug, PartialEq, PartialOrd)]
+pub enum ServerMessage {
+    FramebufferUpdate(FramebufferUpdate),
+    SetColourMapEntries(SetColourMapEntries),
+    Bell(Bell),
+    ServerCutText(CutText),
+    EndOfContinuousUpdates(EndOfContinuousUpdates),
+    ServerFence(Fence),
+    QemuAudio(QemuAudioServerMessage),
+}
+
+impl<R: Read> DecodeWith<R, SessionContext> for ServerMessage {
+    type Error = crate::Error;
+    fn decode_with(reader: &mut R, context: &mut SessionContext) -> Result<Self, Self::Error> {
+        let message_type = [u8::decode_from(reader)?];
+        // Message decoders expect their type, which has been read already.
+        let reader = &mut message_type.as_slice().chain(reader);
+        Ok(match message_type[0] {
+            0 => Self::FramebufferUpdate(DecodeWith::decode_with(reader, context)?),
+            1 => Self::SetColourMapEntries(DecodeFrom::decode_from(reader)?),
+            2 => Self::Bell(DecodeFrom::decode_from(reader)?),
+            3 => {
+                <[u8; 4]>::decode_from(reader)?;
+                Self::ServerCutText(DecodeFrom::decode_from(reader)?)
+            }
+            150 => Self::EndOfContinuousUpdates(DecodeFrom::decode_from(reader)?),
+            248 => Self::ServerFence(DecodeFrom::decode_from(reader)?),
+            255 => {
+                // QEMU messages are told apart by a submessage type.
+                let types = <[u8; 2]>::decode_from(reader)?;
+                let reader = &mut types.as_slice().chain(reader);
+                match types[1] {
+                    1 => Self::QemuAudio(DecodeWith::decode_with(reader, context)?),
+                    _ => return Err(crate::Error::UnsupportedMessage(255)),
+                }
+            }
+            message_type => return Err(crate::Error::UnsupportedMessage(message_type)),
+        })
+    }
+}
+
+impl<W: Write> EncodeWith<W, SessionContext> for ServerMessage {
+    type Error = crate::Error;
+    fn encode_with(
+        self,
+        writer: &mut W,
+        context: &mut SessionContext,
+    ) -> Result<usize, Self::Error> {
+        match self {
+            Self::FramebufferUpdate(message) => message.encode_with(writer, context),
+            Self::SetColourMapEntries(message) => message.encode_to(writer),
+            Self::Bell(message) => message.encode_to(writer),
+            Self::ServerCutText(message) => {
+                writer.write_all(&[3, 0, 0, 0])?;
+                Ok(4 + message.encode_to(writer)?)
+            }
+            Self::EndOfContinuousUpdates(message) => message.encode_to(writer),
+            Self::ServerFence(message) => message.encode_to(writer),
+            Self::QemuAudio(message) => message.encode_with(writer, context),
+        }
+    }
+}
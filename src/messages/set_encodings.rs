@@ -1,7 +1,7 @@
-use crate::io::{BigEndian, Decode, DecodeFrom, Encode, EncodeTo, Length};
+use crate::io::{Decode, DecodeFrom, Encode, EncodeTo, Length};
 use std::io::{Read, Write};
 
-#[derive(Debug, PartialEq, PartialOrd)]
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
 pub struct SetEncodings {
     pub encodings: Vec<EncodingType>,
 }
@@ -9,36 +9,172 @@ pub struct SetEncodings {
 impl<W: Write> EncodeTo<W> for SetEncodings {
     type Error = crate::Error;
     fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
-        println!("Sent: {self:?}");
-        [2, 0].encode_to(writer)?;
-
-        let len: u16 = self.encodings.len().try_into()?;
-        len.encode_to(writer)?;
-
-        self.encodings.encode_to(writer)
+        [2u8, 0].encode_to(writer)?;
+        Ok(2 + self.encodings.encode_to(writer)?)
     }
 }
 impl<R: Read> DecodeFrom<R> for SetEncodings {
     type Error = crate::Error;
     fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
         <[u8; 2]>::decode_from(reader)?;
-        let data = Self {
+        Ok(Self {
             encodings: Vec::<EncodingType>::decode_from(reader)?,
-        };
-        println!("Received: {data:?}");
-        Ok(data)
+        })
     }
 }
 
-#[derive(Debug, PartialEq, PartialOrd)]
-#[repr(i32)]
+/// An encoding or pseudo-encoding number. Numbers registered as ranges and
+/// unknown numbers keep their value, so that every number decodes and encodes
+/// back unchanged.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub enum EncodingType {
     Raw,
     CopyRect,
     Rre,
-    CoRre = 4,
+    CoRre,
     Hextile,
+    Zlib,
+    Tight,
+    ZlibHex,
+    Trle,
+    Zrle,
+    HitachiZywrle,
+    H264,
+    Jpeg,
+    Jrle,
+    OpenH264,
+    /// 1000 to 1002, 1011 and 1100 to 1105.
+    AppleInc(i32),
+    /// JPEG quality of Tight, from 0 (lowest) to 9.
+    QualityLevel(u8),
+    DesktopSize,
+    LastRect,
+    PointerPos,
+    Cursor,
+    XCursor,
+    /// Compression level of Tight, from 0 (fastest) to 9.
+    CompressLevel(u8),
+    QemuPointerMotionChange,
+    QemuExtendedKeyEvent,
+    QemuAudio,
+    QemuLedState,
+    Gii,
+    DesktopName,
+    ExtendedDesktopSize,
+    Xvp,
+    Fence,
+    ContinuousUpdates,
+    CursorWithAlpha,
+    /// JPEG quality from 0 (lowest) to 100.
+    FineQualityLevel(u8),
+    /// JPEG chrominance subsampling: none, 4X, 2X, grayscale, 8X or 16X.
+    SubsamplingLevel(u8),
+    /// 0x574d5600 to 0x574d56ff.
+    VMware(i32),
+    ExtendedClipboard,
+    Unassigned(i32),
 }
+
+impl From<i32> for EncodingType {
+    fn from(value: i32) -> Self {
+        use EncodingType::*;
+        match value {
+            0 => Raw,
+            1 => CopyRect,
+            2 => Rre,
+            4 => CoRre,
+            5 => Hextile,
+            6 => Zlib,
+            7 => Tight,
+            8 => ZlibHex,
+            15 => Trle,
+            16 => Zrle,
+            17 => HitachiZywrle,
+            20 => H264,
+            21 => Jpeg,
+            22 => Jrle,
+            50 => OpenH264,
+            1000..=1002 | 1011 | 1100..=1105 => AppleInc(value),
+            -32..=-23 => QualityLevel((value + 32) as u8),
+            -223 => DesktopSize,
+            -224 => LastRect,
+            -225 => PointerPos,
+            -239 => Cursor,
+            -240 => XCursor,
+            -256..=-247 => CompressLevel((value + 256) as u8),
+            -257 => QemuPointerMotionChange,
+            -258 => QemuExtendedKeyEvent,
+            -259 => QemuAudio,
+            -261 => QemuLedState,
+            -305 => Gii,
+            -307 => DesktopName,
+            -308 => ExtendedDesktopSize,
+            -309 => Xvp,
+            -312 => Fence,
+            -313 => ContinuousUpdates,
+            -314 => CursorWithAlpha,
+            -512..=-412 => FineQualityLevel((value + 512) as u8),
+            -768..=-763 => SubsamplingLevel((value + 768) as u8),
+            0x574d5600..=0x574d56ff => VMware(value),
+            // 0xc0a1e5ce.
+            -1063131698 => ExtendedClipboard,
+            _ => Unassigned(value),
+        }
+    }
+}
+
+/// Fails with `UnsupportedEncoding` if `value` holds a level out of its range
+/// or a number of another encoding, which would not decode back to it.
+impl TryFrom<EncodingType> for i32 {
+    type Error = crate::Error;
+    fn try_from(value: EncodingType) -> Result<Self, Self::Error> {
+        use EncodingType::*;
+        let number = match value {
+            Raw => 0,
+            CopyRect => 1,
+            Rre => 2,
+            CoRre => 4,
+            Hextile => 5,
+            Zlib => 6,
+            Tight => 7,
+            ZlibHex => 8,
+            Trle => 15,
+            Zrle => 16,
+            HitachiZywrle => 17,
+            H264 => 20,
+            Jpeg => 21,
+            Jrle => 22,
+            OpenH264 => 50,
+            QualityLevel(level) => -32 + level as i32,
+            DesktopSize => -223,
+            LastRect => -224,
+            PointerPos => -225,
+            Cursor => -239,
+            XCursor => -240,
+            CompressLevel(level) => -256 + level as i32,
+            QemuPointerMotionChange => -257,
+            QemuExtendedKeyEvent => -258,
+            QemuAudio => -259,
+            QemuLedState => -261,
+            Gii => -305,
+            DesktopName => -307,
+            ExtendedDesktopSize => -308,
+            Xvp => -309,
+            Fence => -312,
+            ContinuousUpdates => -313,
+            CursorWithAlpha => -314,
+            FineQualityLevel(level) => -512 + level as i32,
+            SubsamplingLevel(level) => -768 + level as i32,
+            ExtendedClipboard => -1063131698,
+            AppleInc(value) | VMware(value) | Unassigned(value) => value,
+        };
+        match EncodingType::from(number) == value {
+            true => Ok(number),
+            false => Err(crate::Error::UnsupportedEncoding),
+        }
+    }
+}
+
 impl Length for EncodingType {
     const LENGTH: usize = 4;
 }
@@ -46,24 +182,14 @@ impl Length for EncodingType {
 impl Encode for EncodingType {
     type Error = crate::Error;
     fn encode(self) -> Result<[u8; <Self as Length>::LENGTH], Self::Error> {
-        Ok((self as i32).to_be_bytes())
+        Ok(i32::try_from(self)?.to_be_bytes())
     }
 }
 
 impl Decode for EncodingType {
     type Error = crate::Error;
     fn decode(data: [u8; <Self as Length>::LENGTH]) -> Result<Self, Self::Error> {
-        let n = i32::from_be_bytes(data);
-        use EncodingType::*;
-        match n {
-            0 => Ok(Raw),
-            1 => Ok(CopyRect),
-            2 => Ok(Rre),
-            4 => Ok(CoRre),
-            5 => Ok(Hextile),
-            //TODO: add all encodings!!!
-            _ => Ok(Raw),
-        }
+        Ok(i32::from_be_bytes(data).into())
     }
 }
 impl<R: std::io::Read> DecodeFrom<R> for Vec<EncodingType> {
@@ -83,13 +209,74 @@ impl<W: std::io::Write> EncodeTo<W> for Vec<EncodingType> {
     type Error = crate::Error;
     fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
         let len: u16 = self.len().try_into()?;
-
-        println!("Sent: {self:?}");
         len.encode_to(writer)?;
 
         for encoding_type in self {
             encoding_type.encode_to(writer)?;
         }
-        Ok(2 + len as usize)
+        Ok(2 + len as usize * EncodingType::LENGTH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_number() {
+        let numbers = (-2000..2000).chain(0x574d5500..0x574d5700).chain([
+            i32::MIN,
+            i32::MAX,
+            0xc0a1e5ceu32 as i32,
+        ]);
+        for number in numbers {
+            let encoding_type = EncodingType::from(number);
+            let encoded = i32::try_from(encoding_type).unwrap();
+            assert_eq!(encoded, number, "{encoding_type:?}");
+        }
+        for encoding_type in [
+            EncodingType::QualityLevel(10),
+            EncodingType::CompressLevel(255),
+            EncodingType::FineQualityLevel(101),
+            EncodingType::SubsamplingLevel(6),
+            EncodingType::AppleInc(5),
+            EncodingType::VMware(-1),
+            EncodingType::Unassigned(0),
+        ] {
+            assert!(
+                matches!(
+                    i32::try_from(encoding_type),
+                    Err(crate::Error::UnsupportedEncoding)
+                ),
+                "{encoding_type:?}"
+            );
+        }
+        assert_eq!(
+            EncodingType::from(0xc0a1e5ceu32 as i32),
+            EncodingType::ExtendedClipboard
+        );
+        assert_eq!(EncodingType::from(-247), EncodingType::CompressLevel(9));
+        assert_eq!(
+            EncodingType::from(-412),
+            EncodingType::FineQualityLevel(100)
+        );
+    }
+
+    #[test]
+    fn lossless_set_encodings() {
+        let encodings = SetEncodings {
+            encodings: [
+                16, 7, -23, -247, -224, -239, -308, -312, -313, -258, 1234, -1,
+            ]
+            .map(EncodingType::from)
+            .to_vec(),
+        };
+        let mut buf = Vec::new();
+        encodings.clone().encode_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), 4 + 4 * encodings.encodings.len());
+        assert_eq!(&buf[..4], [2, 0, 0, 12]);
+        assert_eq!(&buf[buf.len() - 8..], [0, 0, 4, 210, 255, 255, 255, 255]);
+        let decoded = SetEncodings::decode_from(&mut &buf[..]).unwrap();
+        assert_eq!(decoded, encodings);
     }
 }
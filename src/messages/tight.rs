@@ -0,0 +1,81 @@
+use std::io::{Read, Write};
+
+use crate::{error::Error, io::*};
+
+/// A TightVNC capability: the code used on the wire along with the vendor and
+/// signature naming what it stands for.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct Capability {
+    pub code: u32,
+    pub vendor: [u8; 4],
+    pub signature: [u8; 8],
+}
+
+pub const STANDARD_VENDOR: [u8; 4] = *b"STDV";
+pub const TIGHT_VENDOR: [u8; 4] = *b"TGHT";
+
+impl Capability {
+    pub const NO_TUNNEL: Self = Self::new(0, TIGHT_VENDOR, *b"NOTUNNEL");
+    pub const AUTH_NONE: Self = Self::new(1, STANDARD_VENDOR, *b"NOAUTH__");
+    pub const AUTH_VNC: Self = Self::new(2, STANDARD_VENDOR, *b"VNCAUTH_");
+    pub const AUTH_UNIX_LOGIN: Self = Self::new(129, TIGHT_VENDOR, *b"ULGNAUTH");
+
+    pub const fn new(code: u32, vendor: [u8; 4], signature: [u8; 8]) -> Self {
+        Self {
+            code,
+            vendor,
+            signature,
+        }
+    }
+}
+
+impl Length for Capability {
+    const LENGTH: usize = 16;
+}
+
+impl Decode for Capability {
+    type Error = Error;
+    fn decode(data: [u8; 16]) -> Result<Self, Self::Error> {
+        let mut vendor = [0; 4];
+        let mut signature = [0; 8];
+        vendor.copy_from_slice(&data[4..8]);
+        signature.copy_from_slice(&data[8..]);
+        Ok(Self {
+            code: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+            vendor,
+            signature,
+        })
+    }
+}
+
+impl Encode for Capability {
+    type Error = Error;
+    fn encode(self) -> Result<[u8; 16], Self::Error> {
+        let mut data = [0; 16];
+        data[..4].copy_from_slice(&self.code.to_be_bytes());
+        data[4..8].copy_from_slice(&self.vendor);
+        data[8..].copy_from_slice(&self.signature);
+        Ok(data)
+    }
+}
+
+/// Tunnel and authentication capability lists, preceded by a u32 count.
+impl<R: Read> DecodeFrom<R> for Vec<Capability> {
+    type Error = Error;
+    fn decode_from(reader: &mut R) -> Result<Self, Self::Error> {
+        let len = u32::decode_from(reader)?;
+        (0..len).map(|_| Capability::decode_from(reader)).collect()
+    }
+}
+
+impl<W: Write> EncodeTo<W> for Vec<Capability> {
+    type Error = Error;
+    fn encode_to(self, writer: &mut W) -> Result<usize, Self::Error> {
+        let len: u32 = self.len().try_into()?;
+        len.encode_to(writer)?;
+        for capability in self {
+            capability.encode_to(writer)?;
+        }
+        Ok(4 + len as usize * Capability::LENGTH)
+    }
+}
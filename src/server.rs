@@ -1,74 +1,1383 @@
+use crate::auth::{self, ServerAuthenticator};
+use crate::encodings::cursor::{Cursor, CursorImage};
+use crate::encodings::{rre, tight};
+use crate::framebuffer::Framebuffer;
+use crate::io::*;
+use crate::messages::*;
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Server side of a session whose handshake is done.
+pub struct Server {
+    stream: Box<dyn Stream>,
+    pub version: Version,
+    pub security_type: SecurityType,
+    /// Whether the client lets other clients stay connected. When false,
+    /// the other sessions of the handshake's [`Sessions`] were disconnected.
+    pub shared: bool,
+    session: Session,
+    context: SessionContext,
+    pub framebuffer_width: u16,
+    pub framebuffer_height: u16,
+    pub name: String,
+    /// Screen layout, a single screen covering the framebuffer at first.
+    pub screens: Vec<Screen>,
+    continuous_updates: Option<EnableContinuousUpdates>,
+    audio: bool,
+    clipboard: ClipboardState,
+    /// Messages read while waiting for an update request, already applied to
+    /// the session and not yet returned.
+    pending: VecDeque<ClientMessage>,
+    /// Fences sent after updates and not answered yet: their number, when
+    /// they were sent and the size of the update before them.
+    fences: VecDeque<(u32, Instant, usize)>,
+    fence_number: u32,
+    /// Round trip time measured with the last fence answered.
+    pub rtt: Option<Duration>,
+    /// Bytes of updates not acknowledged by the client above which the
+    /// session is congested, 1 MiB by default.
+    pub max_in_flight: usize,
+}
+
+/// Open sessions of a server, shared by the handshakes of its clients. A
+/// client asking for exclusive access disconnects the other sessions, whose
+/// next read or write fails, or is refused if other sessions are open and
+/// exclusive clients are refused.
+#[derive(Clone, Default)]
+pub struct Sessions {
+    open: Arc<Mutex<OpenSessions>>,
+    refuse_exclusive: bool,
+}
+
+#[derive(Default)]
+struct OpenSessions {
+    next_id: u64,
+    disconnected: HashMap<u64, Arc<AtomicBool>>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuses clients asking for exclusive access while other sessions are
+    /// open instead of disconnecting them.
+    pub fn refusing_exclusive(mut self) -> Self {
+        self.refuse_exclusive = true;
+        self
+    }
+
+    /// Number of sessions open and not disconnected.
+    pub fn len(&self) -> usize {
+        self.open.lock().unwrap().disconnected.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn open(&self, shared: bool) -> Result<Session, crate::error::Error> {
+        let mut open = self.open.lock().unwrap();
+        if !shared && !open.disconnected.is_empty() {
+            if self.refuse_exclusive {
+                return Err(crate::Error::ConnectionFailed(
+                    "other clients are connected".into(),
+                ));
+            }
+            for (_, disconnected) in open.disconnected.drain() {
+                disconnected.store(true, Ordering::Relaxed);
+            }
+        }
+        let id = open.next_id;
+        open.next_id += 1;
+        let disconnected = Arc::new(AtomicBool::new(false));
+        open.disconnected.insert(id, disconnected.clone());
+        Ok(Session {
+            sessions: self.open.clone(),
+            id,
+            disconnected,
+        })
+    }
+}
+
+/// Entry of a session in its [`Sessions`], removed once the server is dropped.
+struct Session {
+    sessions: Arc<Mutex<OpenSessions>>,
+    id: u64,
+    disconnected: Arc<AtomicBool>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Ok(mut open) = self.sessions.lock() {
+            open.disconnected.remove(&self.id);
+        }
+    }
+}
+
+impl Server {
+    /// Announces `version`, authenticates the client with the first of
+    /// `authenticators` it supports (all of them are offered, in order of
+    /// preference) and sends `server_init` once the client is initialised
+    /// and opened among `sessions`.
+    pub fn handshake(
+        stream: impl Stream + 'static,
+        version: Version,
+        mut authenticators: Vec<Box<dyn ServerAuthenticator>>,
+        mut server_init: ServerInit,
+        sessions: &Sessions,
+    ) -> Result<Self, crate::error::Error> {
+        let mut stream: Box<dyn Stream> = Box::new(stream);
+        version.clone().encode_to(&mut stream)?;
+        stream.flush()?;
+        // Clients answer with the highest version they support up to ours,
+        // some announce non-standard ones such as 3.5.
+        let version = match Version::decode_from(&mut stream)? {
+            client if client < version => client,
+            _ => version,
+        };
+
+        let (stream, security_type) =
+            auth::negotiate_server(stream, &version, &mut authenticators)?;
+        let mut stream: Box<dyn Stream> = Box::new(BufStream::new(stream));
+
+        let client_init = ClientInit::decode_from(&mut stream)?;
+        let session = sessions.open(client_init.shared)?;
+        if security_type != SecurityType::Tight {
+            server_init.interaction_capabilities = None;
+        } else if server_init.interaction_capabilities.is_none() {
+            server_init.interaction_capabilities = Some(InteractionCapabilities::default());
+        }
+        server_init.clone().encode_to(&mut stream)?;
+        stream.flush()?;
+
+        Ok(Self {
+            stream,
+            version,
+            security_type,
+            shared: client_init.shared,
+            session,
+            context: SessionContext::new(server_init.pixel_format),
+            framebuffer_width: server_init.framebuffer_width,
+            framebuffer_height: server_init.framebuffer_height,
+            name: server_init.name,
+            screens: vec![Screen {
+                id: 0,
+                x: 0,
+                y: 0,
+                width: server_init.framebuffer_width,
+                height: server_init.framebuffer_height,
+                flags: 0,
+            }],
+            continuous_updates: None,
+            audio: false,
+            clipboard: ClipboardState::default(),
+            pending: VecDeque::new(),
+            fences: VecDeque::new(),
+            fence_number: 0,
+            rtt: None,
+            max_in_flight: 1 << 20,
+        })
+    }
+
+    /// Whether a client asking for exclusive access disconnected the session.
+    pub fn disconnected(&self) -> bool {
+        self.session.disconnected.load(Ordering::Relaxed)
+    }
+
+    fn check_connected(&self) -> Result<(), crate::error::Error> {
+        if self.disconnected() {
+            return Err(crate::Error::ConnectionFailed(
+                "disconnected by an exclusive client".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Pixel format updates are sent in, the one of the ServerInit until the
+    /// client sets another.
+    pub fn pixel_format(&self) -> &PixelFormat {
+        &self.context.pixel_format
+    }
+
+    /// Encodings accepted by the client, in order of preference.
+    pub fn encodings(&self) -> &[EncodingType] {
+        &self.context.encodings
+    }
+
+    /// Reads the next client message, applying it to the session. Newly
+    /// accepted ContinuousUpdates, Fence, QemuExtendedKeyEvent, QemuAudio and
+    /// ExtendedClipboard encodings are announced, fences and clipboard peeks
+    /// and requests answered, continuous updates and audio enabled or
+    /// stopped. Messages skipped by `read_update_request` come first.
+    pub fn read_message(&mut self) -> Result<ClientMessage, crate::error::Error> {
+        self.check_connected()?;
+        match self.pending.pop_front() {
+            Some(message) => Ok(message),
+            None => self.receive_message(),
+        }
+    }
+
+    fn receive_message(&mut self) -> Result<ClientMessage, crate::error::Error> {
+        let message = ClientMessage::decode_from(&mut self.stream)?;
+        match &message {
+            ClientMessage::SetPixelFormat(SetPixelFormat { pixel_format }) => {
+                pixel_format.bytes_per_pixel()?;
+                self.context.pixel_format = pixel_format.clone();
+            }
+            ClientMessage::SetEncodings(SetEncodings { encodings }) => {
+                let added = |encoding_type| {
+                    encodings.contains(&encoding_type)
+                        && !self.context.encodings.contains(&encoding_type)
+                };
+                let continuous_updates = added(EncodingType::ContinuousUpdates);
+                let fence = added(EncodingType::Fence);
+                let extended_key_events = added(EncodingType::QemuExtendedKeyEvent);
+                let audio = added(EncodingType::QemuAudio);
+                let clipboard = added(EncodingType::ExtendedClipboard);
+                if !encodings.contains(&EncodingType::ExtendedClipboard) {
+                    self.clipboard.caps = None;
+                }
+                self.context.encodings = encodings.clone();
+                if clipboard {
+                    let caps = ExtendedClipboard::Caps(ClipboardCaps::default());
+                    self.send_cut_text(CutText::Extended(caps))?;
+                }
+                if extended_key_events {
+                    self.acknowledge(
+                        EncodingType::QemuExtendedKeyEvent,
+                        Payload::QemuExtendedKeyEvent,
+                    )?;
+                }
+                if audio {
+                    self.acknowledge(EncodingType::QemuAudio, Payload::QemuAudio)?;
+                }
+                if continuous_updates {
+                    self.send_message(ServerMessage::EndOfContinuousUpdates(
+                        EndOfContinuousUpdates,
+                    ))?;
+                }
+                if fence {
+                    self.send_fence(0)?;
+                }
+            }
+            ClientMessage::EnableContinuousUpdates(request) if request.enable => {
+                self.continuous_updates = Some(request.clone());
+            }
+            ClientMessage::EnableContinuousUpdates(_) => {
+                self.continuous_updates = None;
+                self.send_message(ServerMessage::EndOfContinuousUpdates(
+                    EndOfContinuousUpdates,
+                ))?;
+            }
+            ClientMessage::QemuAudio(QemuAudioClientMessage::SetFormat(format)) => {
+                self.context.audio_format = *format;
+            }
+            ClientMessage::QemuAudio(QemuAudioClientMessage::Enable) => self.audio = true,
+            ClientMessage::QemuAudio(QemuAudioClientMessage::Disable) => self.audio = false,
+            ClientMessage::ClientCutText(cut_text) => {
+                if let Some(answer) = self.clipboard.receive(cut_text, false) {
+                    self.send_cut_text(answer)?;
+                }
+            }
+            ClientMessage::ClientFence(fence) if fence.flags.request => {
+                self.send_message(ServerMessage::ServerFence(fence.response()))?;
+            }
+            ClientMessage::ClientFence(fence) => {
+                if let Some(&(number, sent, _)) = self.fences.front() {
+                    if fence.payload == number.to_be_bytes() {
+                        self.rtt = Some(sent.elapsed());
+                        self.fences.pop_front();
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(message)
+    }
+
+    /// Area the client asked to get updates of as it changes, without
+    /// requesting them, until it disables continuous updates.
+    pub fn continuous_updates(&self) -> Option<&EnableContinuousUpdates> {
+        self.continuous_updates.as_ref()
+    }
+
+    /// Bytes of updates sent and not yet acknowledged by the client. Clients
+    /// accepting fences acknowledge each update by answering the fence sent
+    /// after it, for other clients this is always 0.
+    pub fn in_flight(&self) -> usize {
+        self.fences.iter().map(|&(_, _, len)| len).sum()
+    }
+
+    /// Whether more than `max_in_flight` bytes are in flight, in which case
+    /// `send_changes` waits for the client to catch up. Updates sent with
+    /// `send_update` are not held back.
+    pub fn congested(&self) -> bool {
+        self.in_flight() > self.max_in_flight
+    }
+
+    /// Acknowledges a pseudo-encoding with an empty rectangle of it.
+    fn acknowledge(
+        &mut self,
+        encoding_type: EncodingType,
+        payload: Payload,
+    ) -> Result<(), crate::error::Error> {
+        self.send_message(ServerMessage::FramebufferUpdate(FramebufferUpdate {
+            rectangles: vec![Rectangle {
+                x: 0,
+                y: 0,
+                width: 0,
+                height: 0,
+                encoding_type,
+                payload,
+            }],
+        }))?;
+        Ok(())
+    }
+
+    /// Sets the clipboard the client can paste. With Extended Clipboard the
+    /// client is notified and requests the content, which is sent while
+    /// reading messages. Otherwise the text is sent, in Latin-1.
+    pub fn set_clipboard(&mut self, clipboard: Clipboard) -> Result<(), crate::error::Error> {
+        let cut_text = self.clipboard.set(clipboard);
+        self.send_cut_text(cut_text)
+    }
+
+    /// Requests the content of the client clipboard after a
+    /// `ClipboardEvent::Changed`, which comes as a `ClipboardEvent::Content`.
+    pub fn request_clipboard(&mut self) -> Result<(), crate::error::Error> {
+        match self.clipboard.request() {
+            Some(request) => self.send_cut_text(request),
+            None => Ok(()),
+        }
+    }
+
+    fn send_cut_text(&mut self, cut_text: CutText) -> Result<(), crate::error::Error> {
+        self.send_message(ServerMessage::ServerCutText(cut_text))?;
+        Ok(())
+    }
+
+    /// Whether the client enabled QEMU audio, in the format it set.
+    pub fn audio(&self) -> Option<&AudioFormat> {
+        self.audio.then_some(&self.context.audio_format)
+    }
+
+    /// Tells the client audio starts, once it enabled it.
+    pub fn begin_audio(&mut self) -> Result<(), crate::error::Error> {
+        self.send_audio_message(QemuAudioServerMessage::Begin)
+    }
+
+    pub fn end_audio(&mut self) -> Result<(), crate::error::Error> {
+        self.send_audio_message(QemuAudioServerMessage::End)
+    }
+
+    /// Sends `samples`, which must be in the format set by the client.
+    pub fn send_audio(&mut self, samples: Samples) -> Result<(), crate::error::Error> {
+        let format = self.context.audio_format;
+        self.send_audio_message(QemuAudioServerMessage::Data(AudioBuffer {
+            format,
+            samples,
+        }))
+    }
+
+    fn send_audio_message(
+        &mut self,
+        message: QemuAudioServerMessage,
+    ) -> Result<(), crate::error::Error> {
+        if !self.audio {
+            return Err(crate::Error::UnsupportedMessage(255));
+        }
+        self.send_message(ServerMessage::QemuAudio(message))?;
+        Ok(())
+    }
+
+    fn send_message(&mut self, message: ServerMessage) -> Result<usize, crate::error::Error> {
+        self.check_connected()?;
+        // Nothing is sent if a message cannot be encoded.
+        let mut buf = Vec::new();
+        message.encode_with(&mut buf, &mut self.context)?;
+        self.stream.write_all(&buf)?;
+        self.stream.flush()?;
+        Ok(buf.len())
+    }
+
+    /// Sends a fence the client answers once `len` bytes sent before it are
+    /// processed.
+    fn send_fence(&mut self, len: usize) -> Result<(), crate::error::Error> {
+        let number = self.fence_number;
+        self.fence_number = number.wrapping_add(1);
+        self.send_message(ServerMessage::ServerFence(Fence {
+            flags: FenceFlags {
+                block_before: true,
+                request: true,
+                ..Default::default()
+            },
+            payload: number.to_be_bytes().to_vec(),
+        }))?;
+        self.fences.push_back((number, Instant::now(), len));
+        Ok(())
+    }
+
+    /// Reads client messages until an update request. The others are kept
+    /// for `read_message` to return, in the order they came.
+    pub fn read_update_request(&mut self) -> Result<FramebufferUpdateRequest, crate::error::Error> {
+        self.check_connected()?;
+        let position = self
+            .pending
+            .iter()
+            .position(|message| matches!(message, ClientMessage::FramebufferUpdateRequest(_)));
+        if let Some(ClientMessage::FramebufferUpdateRequest(request)) =
+            position.and_then(|position| self.pending.remove(position))
+        {
+            return Ok(request);
+        }
+        loop {
+            match self.receive_message()? {
+                ClientMessage::FramebufferUpdateRequest(request) => return Ok(request),
+                message => self.pending.push_back(message),
+            }
+        }
+    }
+
+    /// Sends `update`, followed by a fence measuring it if the client accepts
+    /// fences.
+    pub fn send_update(&mut self, update: FramebufferUpdate) -> Result<(), crate::error::Error> {
+        let len = self.send_message(ServerMessage::FramebufferUpdate(update))?;
+        if self.context.encodings.contains(&EncodingType::Fence) {
+            self.send_fence(len)?;
+        }
+        Ok(())
+    }
+
+    /// Rings the bell of the client.
+    pub fn bell(&mut self) -> Result<(), crate::error::Error> {
+        self.send_message(ServerMessage::Bell(Bell))?;
+        Ok(())
+    }
+
+    /// Sets entries of the colour map, for clients whose pixel format is not
+    /// true colour.
+    pub fn set_colour_map_entries(
+        &mut self,
+        first_colour: u16,
+        colours: Vec<Colour>,
+    ) -> Result<(), crate::error::Error> {
+        self.send_message(ServerMessage::SetColourMapEntries(SetColourMapEntries {
+            first_colour,
+            colours,
+        }))?;
+        Ok(())
+    }
+
+    /// Sends a new pointer shape: bitmaps as XCursor if the client accepts
+    /// it, anything else as Cursor. Fails with `UnsupportedEncoding` if the
+    /// client accepts neither.
+    pub fn send_cursor(&mut self, cursor: Cursor) -> Result<(), crate::error::Error> {
+        let encodings = &self.context.encodings;
+        let (encoding_type, cursor) = match cursor.image {
+            CursorImage::Bitmap { .. } if encodings.contains(&EncodingType::XCursor) => {
+                (EncodingType::XCursor, cursor)
+            }
+            CursorImage::Bitmap { .. } => {
+                let pixels = cursor.pixels(&self.context.pixel_format)?;
+                let image = CursorImage::Pixels(pixels);
+                (EncodingType::Cursor, Cursor { image, ..cursor })
+            }
+            CursorImage::Pixels(_) => (EncodingType::Cursor, cursor),
+        };
+        self.send_update(FramebufferUpdate {
+            rectangles: vec![Rectangle {
+                x: cursor.hotspot_x,
+                y: cursor.hotspot_y,
+                width: cursor.width,
+                height: cursor.height,
+                encoding_type,
+                payload: Payload::Cursor(cursor),
+            }],
+        })
+    }
+
+    /// Sends the framebuffer size and layout: as ExtendedDesktopSize if the
+    /// client accepts it, as DesktopSize for successful changes otherwise.
+    fn send_desktop_size(
+        &mut self,
+        reason: DesktopSizeReason,
+        status: DesktopSizeStatus,
+    ) -> Result<(), crate::error::Error> {
+        let encodings = &self.context.encodings;
+        let (encoding_type, x, y, payload) =
+            if encodings.contains(&EncodingType::ExtendedDesktopSize) {
+                let payload = Payload::ExtendedDesktopSize(self.screens.clone());
+                let (x, y) = (reason.into(), status.into());
+                (EncodingType::ExtendedDesktopSize, x, y, payload)
+            } else if status == DesktopSizeStatus::Success {
+                (EncodingType::DesktopSize, 0, 0, Payload::DesktopSize)
+            } else {
+                return Ok(());
+            };
+        self.send_update(FramebufferUpdate {
+            rectangles: vec![Rectangle {
+                x,
+                y,
+                width: self.framebuffer_width,
+                height: self.framebuffer_height,
+                encoding_type,
+                payload,
+            }],
+        })
+    }
+
+    /// Changes the framebuffer size and layout and tells the client, which
+    /// must accept DesktopSize or ExtendedDesktopSize. Frames passed to
+    /// `send_changes` afterwards must be of the new size.
+    pub fn resize(
+        &mut self,
+        width: u16,
+        height: u16,
+        screens: Vec<Screen>,
+    ) -> Result<(), crate::error::Error> {
+        self.framebuffer_width = width;
+        self.framebuffer_height = height;
+        self.screens = screens;
+        self.send_desktop_size(DesktopSizeReason::Server, DesktopSizeStatus::Success)
+    }
+
+    /// Applies a size change requested by the client, unless its layout is
+    /// invalid, and answers it. Returns the status sent.
+    pub fn accept_desktop_size(
+        &mut self,
+        request: &SetDesktopSize,
+    ) -> Result<DesktopSizeStatus, crate::error::Error> {
+        if !Screen::valid_layout(&request.screens, request.width, request.height) {
+            self.reject_desktop_size(DesktopSizeStatus::InvalidLayout)?;
+            return Ok(DesktopSizeStatus::InvalidLayout);
+        }
+        self.framebuffer_width = request.width;
+        self.framebuffer_height = request.height;
+        self.screens = request.screens.clone();
+        let status = DesktopSizeStatus::Success;
+        self.send_desktop_size(DesktopSizeReason::Client, status)?;
+        Ok(status)
+    }
+
+    /// Answers a size change requested by the client with an error `status`,
+    /// the framebuffer keeping its size.
+    pub fn reject_desktop_size(
+        &mut self,
+        status: DesktopSizeStatus,
+    ) -> Result<(), crate::error::Error> {
+        self.send_desktop_size(DesktopSizeReason::Client, status)
+    }
+
+    /// Sends the changes from the content of `framebuffer` to `frame` and
+    /// stores `frame` in it. Moved areas are copied when the client accepts
+    /// CopyRect, pixels use the client's preferred encoding. While the
+    /// session is congested nothing is sent and `framebuffer` is left as is,
+    /// so that the changes are sent with the next frame: returns whether the
+    /// update was sent.
+    pub fn send_changes(
+        &mut self,
+        framebuffer: &mut Framebuffer,
+        frame: &[u8],
+    ) -> Result<bool, crate::error::Error> {
+        if self.congested() {
+            return Ok(false);
+        }
+        let copy_rect = self.context.encodings.contains(&EncodingType::CopyRect);
+        let encoding_type = self.context.pixel_encoding();
+        let bytes_per_pixel = self.context.pixel_format.bytes_per_pixel()?;
+        let size = match encoding_type {
+            EncodingType::CoRre => rre::CORRE_MAX_SIZE,
+            EncodingType::Tight => tight::MAX_WIDTH,
+            _ => u16::MAX,
+        };
+        let rectangles = framebuffer
+            .update(frame, copy_rect)?
+            .into_iter()
+            .flat_map(|rectangle| match rectangle.payload {
+                Payload::Pixels(_) => Rectangle {
+                    encoding_type,
+                    ..rectangle
+                }
+                .tiles(size, bytes_per_pixel),
+                _ => vec![rectangle],
+            })
+            .collect();
+        self.send_update(FramebufferUpdate { rectangles })?;
+        Ok(true)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{
-        io::{BufRead, BufReader, BufWriter, Write},
-        net::TcpListener,
-    };
-
-    use crate::{
-        io::{DecodeFrom, Encode, EncodeTo},
-        messages::{
-            ClientInit, EncodingType, FramebufferUpdate, FramebufferUpdateRequest, PixelFormat,
-            Rectangle, SecurityResult, SecurityType, ServerInit, SetEncodings, Version,
-        },
-    };
-
-    #[test]
-    fn server() -> Result<(), crate::Error> {
-        let (stream, addr) = TcpListener::bind("0.0.0.0:5900")?.accept()?;
-
-        let writer = &mut BufWriter::new(&stream);
-        let reader = &mut BufReader::new(&stream);
-
-        Version::Rfb38.encode_to(writer)?;
-        writer.flush()?;
-        assert_eq!(Version::Rfb38, Version::decode_from(reader)?);
-        vec![SecurityType::None].encode_to(writer)?;
-        writer.flush()?;
-        assert_eq!(SecurityType::None, SecurityType::decode_from(reader)?);
-        SecurityResult::Ok.encode_to(writer)?;
-        writer.flush();
-        ClientInit::decode_from(reader)?;
-        ServerInit {
-            pixel_format: PixelFormat {
-                bits_per_pixel: 32,
-                depth: 24,
-                big_endian_flag: false,
-                true_colour_flag: true,
-                red_max: 255,
-                green_max: 255,
-                blue_max: 255,
-                red_shift: 16,
-                green_shift: 8,
-                blue_shift: 0,
-            },
-            framebuffer_width: 500,
-            framebuffer_height: 500,
-            name: "BONOJOUR".to_string(),
-        }
-        .encode_to(writer)?;
-        writer.flush();
-        SetEncodings::decode_from(reader)?;
-
-        FramebufferUpdateRequest::decode_from(reader)?;
-        println!("{:?}", writer.buffer());
-        for i in (0..1000).step_by(10) {
-            std::thread::sleep_ms(1);
-            FramebufferUpdate {
+    use super::*;
+    use crate::auth::tests::server_init;
+    use crate::auth::{ClientAuthenticator, NoAuthentication, VncAuthVerifier};
+    use crate::client::Client;
+    use crate::Error;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    fn accept(
+        version: Version,
+        authenticators: Vec<Box<dyn ServerAuthenticator>>,
+    ) -> (UnixStream, std::thread::JoinHandle<Result<Server, Error>>) {
+        accept_among(version, authenticators, Sessions::new())
+    }
+
+    fn accept_among(
+        version: Version,
+        authenticators: Vec<Box<dyn ServerAuthenticator>>,
+        sessions: Sessions,
+    ) -> (UnixStream, std::thread::JoinHandle<Result<Server, Error>>) {
+        let (server, client) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            Server::handshake(server, version, authenticators, server_init(), &sessions)
+        });
+        (client, handle)
+    }
+
+    /// Client and server of a session without authentication.
+    fn connect() -> (Client, Server) {
+        connect_among(&Sessions::new())
+    }
+
+    /// Like `connect`, the client asking for exclusive access among `sessions`.
+    fn connect_among(sessions: &Sessions) -> (Client, Server) {
+        let (client, handle) = accept_among(
+            Version::Rfb38,
+            vec![Box::new(NoAuthentication)],
+            sessions.clone(),
+        );
+        let authenticators: Vec<Box<dyn ClientAuthenticator>> = vec![Box::new(NoAuthentication)];
+        let client = Client::handshake(client, authenticators).unwrap();
+        (client, handle.join().unwrap().unwrap())
+    }
+
+    #[test]
+    fn session() {
+        let (mut client, mut server) = connect();
+        assert_eq!(server.version, Version::Rfb38);
+        assert_eq!(server.security_type, SecurityType::None);
+        assert!(!server.shared);
+        assert_eq!(client.name, server.name);
+
+        client.request_update(true, 0, 0, 1, 1).unwrap();
+        let request = server.read_update_request().unwrap();
+        assert!(request.incremental);
+        server
+            .send_update(FramebufferUpdate {
                 rectangles: vec![Rectangle {
                     x: 0,
                     y: 0,
-                    width: 500,
-                    height: 500,
+                    width: 1,
+                    height: 1,
                     encoding_type: EncodingType::Raw,
-                    pixels: (0..500 * 500 * 4).map(|x| (x + i % 256) as u8).collect(),
+                    payload: Payload::Pixels(vec![1, 2, 3, 4]),
                 }],
+            })
+            .unwrap();
+        assert_eq!(
+            client.read_update().unwrap().rectangles[0].payload,
+            Payload::Pixels(vec![1, 2, 3, 4])
+        );
+    }
+
+    #[test]
+    fn messages_before_update_request() {
+        let (mut client, mut server) = connect();
+
+        client.set_desktop_size(2, 1, Vec::new()).unwrap();
+        client.request_update(false, 0, 0, 1, 1).unwrap();
+        client.request_update(true, 0, 0, 1, 1).unwrap();
+        client.set_desktop_size(3, 1, Vec::new()).unwrap();
+        assert!(!server.read_update_request().unwrap().incremental);
+        assert!(server.read_update_request().unwrap().incremental);
+        // Messages skipped on the way are returned afterwards, in order.
+        for width in [2, 3] {
+            assert!(matches!(
+                server.read_message().unwrap(),
+                ClientMessage::SetDesktopSize(SetDesktopSize { width: w, .. }) if w == width
+            ));
+        }
+    }
+
+    #[test]
+    fn client_pixel_format() {
+        let (mut client, mut server) = connect();
+
+        let pixel_format = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+            ..client.pixel_format().clone()
+        };
+        client.set_pixel_format(pixel_format.clone()).unwrap();
+        client.set_encodings(vec![EncodingType::Raw]).unwrap();
+        client.request_update(false, 0, 0, 2, 1).unwrap();
+        server.read_update_request().unwrap();
+        assert_eq!(server.pixel_format(), &pixel_format);
+        assert_eq!(server.encodings(), [EncodingType::Raw]);
+
+        let rectangle = Rectangle {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+            encoding_type: EncodingType::Raw,
+            payload: Payload::Pixels(vec![1, 2, 3, 4]),
+        };
+        server
+            .send_update(FramebufferUpdate {
+                rectangles: vec![rectangle.clone()],
+            })
+            .unwrap();
+        assert_eq!(client.read_update().unwrap().rectangles, [rectangle]);
+    }
+
+    #[test]
+    fn rejected_encodings() {
+        let (mut client, mut server) = connect();
+        client.set_encodings(vec![EncodingType::Raw]).unwrap();
+        let encodings = vec![EncodingType::CopyRect, EncodingType::QualityLevel(10)];
+        assert!(matches!(
+            client.set_encodings(encodings),
+            Err(Error::UnsupportedEncoding)
+        ));
+        // Nothing of the rejected message reaches the server.
+        client.request_update(false, 0, 0, 1, 1).unwrap();
+        server.read_update_request().unwrap();
+        assert_eq!(server.encodings(), [EncodingType::Raw]);
+    }
+
+    #[test]
+    fn bell_and_colour_map() {
+        let (mut client, mut server) = connect();
+        let colours = vec![Colour {
+            red: 0xffff,
+            green: 0x8000,
+            blue: 0,
+        }];
+        server.bell().unwrap();
+        server.set_colour_map_entries(7, colours.clone()).unwrap();
+        server
+            .send_update(FramebufferUpdate {
+                rectangles: Vec::new(),
+            })
+            .unwrap();
+        assert!(client.read_update().unwrap().rectangles.is_empty());
+        assert!(matches!(
+            client.read_message().unwrap(),
+            ServerMessage::Bell(_)
+        ));
+        assert_eq!(
+            client.read_message().unwrap(),
+            ServerMessage::SetColourMapEntries(SetColourMapEntries {
+                first_colour: 7,
+                colours
+            })
+        );
+    }
+
+    #[test]
+    fn copy_rect() {
+        let (mut client, mut server) = connect();
+        let mut local = Framebuffer::new(16, 16, client.pixel_format()).unwrap();
+        let mut remote = Framebuffer::new(16, 16, server.pixel_format()).unwrap();
+
+        client
+            .set_encodings(vec![EncodingType::CopyRect, EncodingType::Raw])
+            .unwrap();
+        // The second frame scrolls the first one by 3 rows.
+        let frames = [0, 3].map(|scroll| -> Vec<u8> {
+            (scroll..16 + scroll)
+                .flat_map(|y| (0..16u8).flat_map(move |x| [x, y, x ^ y, 0]))
+                .collect()
+        });
+        for (frame, copies) in frames.iter().zip([0, 1]) {
+            client.request_update(true, 0, 0, 16, 16).unwrap();
+            server.read_update_request().unwrap();
+            assert!(server.send_changes(&mut remote, frame).unwrap());
+            let rectangles = client.read_update().unwrap().rectangles;
+            let copy_rect = |r: &&Rectangle| r.encoding_type == EncodingType::CopyRect;
+            assert_eq!(rectangles.iter().filter(copy_rect).count(), copies);
+            for rectangle in rectangles {
+                local.apply(&rectangle).unwrap();
             }
-            .encode_to(writer)?;
-            writer.flush()?;
+            assert_eq!(local.data(), frame);
         }
-        panic!("BONOJOIUJOPIUOPIHJIOUHOIUH");
+    }
 
-        Ok(())
+    #[test]
+    fn pixel_encodings() {
+        for encoding_type in [
+            EncodingType::Rre,
+            EncodingType::CoRre,
+            EncodingType::Hextile,
+            EncodingType::Zlib,
+            EncodingType::Tight,
+            EncodingType::Trle,
+            EncodingType::Zrle,
+        ] {
+            let (mut client, mut server) = connect();
+            let mut local = Framebuffer::new(300, 20, client.pixel_format()).unwrap();
+            let mut remote = Framebuffer::new(300, 20, server.pixel_format()).unwrap();
+
+            client
+                .set_encodings(vec![encoding_type, EncodingType::Raw])
+                .unwrap();
+            client.request_update(false, 0, 0, 300, 20).unwrap();
+            server.read_update_request().unwrap();
+            let frame: Vec<u8> = (0..20usize)
+                .flat_map(|y| {
+                    (0..300usize).flat_map(move |x| [(x / 50) as u8, (y / 5) as u8, 0, 0])
+                })
+                .collect();
+            assert!(server.send_changes(&mut remote, &frame).unwrap());
+            let rectangles = client.read_update().unwrap().rectangles;
+            assert!(rectangles.iter().all(|r| r.encoding_type == encoding_type));
+            let tiles = match encoding_type {
+                EncodingType::CoRre => 2,
+                _ => 1,
+            };
+            assert_eq!(rectangles.len(), tiles);
+            for rectangle in rectangles {
+                local.apply(&rectangle).unwrap();
+            }
+            assert_eq!(local.data(), frame);
+        }
+    }
+
+    #[test]
+    fn zlib_updates() {
+        for encoding_type in [EncodingType::Zlib, EncodingType::Tight, EncodingType::Zrle] {
+            let (mut client, mut server) = connect();
+            let mut local = Framebuffer::new(64, 32, client.pixel_format()).unwrap();
+            let mut remote = Framebuffer::new(64, 32, server.pixel_format()).unwrap();
+
+            client.set_encodings(vec![encoding_type]).unwrap();
+            // Every update continues the stream of the previous ones.
+            for step in 0..4u8 {
+                let frame: Vec<u8> = (0..32u8)
+                    .flat_map(|y| {
+                        (0..64u8).flat_map(move |x| [x ^ step, y, (x + y) / (step + 1), 0])
+                    })
+                    .collect();
+                client.request_update(true, 0, 0, 64, 32).unwrap();
+                server.read_update_request().unwrap();
+                assert!(server.send_changes(&mut remote, &frame).unwrap());
+                let rectangles = client.read_update().unwrap().rectangles;
+                assert!(!rectangles.is_empty());
+                for rectangle in rectangles {
+                    assert_eq!(rectangle.encoding_type, encoding_type);
+                    local.apply(&rectangle).unwrap();
+                }
+                assert_eq!(local.data(), frame);
+            }
+        }
+    }
+
+    #[test]
+    fn cursor_shapes() {
+        let (mut client, mut server) = connect();
+        let cursor = Cursor {
+            hotspot_x: 1,
+            hotspot_y: 2,
+            width: 3,
+            height: 3,
+            image: CursorImage::Bitmap {
+                foreground: [255, 0, 0],
+                background: [0, 0, 255],
+                bitmap: vec![0x40, 0xe0, 0x40],
+            },
+            mask: vec![0x40, 0xe0, 0xe0],
+        };
+        assert!(matches!(
+            server.send_cursor(cursor.clone()),
+            Err(Error::UnsupportedEncoding)
+        ));
+
+        client
+            .set_encodings(vec![EncodingType::Cursor, EncodingType::XCursor])
+            .unwrap();
+        server.read_message().unwrap();
+        server.send_cursor(cursor.clone()).unwrap();
+        let rectangles = client.read_update().unwrap().rectangles;
+        assert_eq!(rectangles[0].encoding_type, EncodingType::XCursor);
+        assert_eq!(client.cursor, Some(cursor.clone()));
+
+        // Without XCursor, bitmaps are sent as pixels.
+        client.set_encodings(vec![EncodingType::Cursor]).unwrap();
+        server.read_message().unwrap();
+        server.send_cursor(cursor.clone()).unwrap();
+        let rectangles = client.read_update().unwrap().rectangles;
+        assert_eq!(rectangles[0].encoding_type, EncodingType::Cursor);
+        let received = client.cursor.clone().unwrap();
+        let pixels = cursor.pixels(client.pixel_format()).unwrap();
+        assert_eq!(received.image, CursorImage::Pixels(pixels));
+        assert_eq!((received.hotspot_x, received.hotspot_y), (1, 2));
+        assert!(received.visible(1, 0) && !received.visible(0, 0));
+    }
+
+    #[test]
+    fn desktop_size() {
+        let (mut client, mut server) = connect();
+        let mut local = Framebuffer::new(1, 1, client.pixel_format()).unwrap();
+        let screen = |id, x, width| Screen {
+            id,
+            x,
+            y: 0,
+            width,
+            height: 10,
+            flags: 0,
+        };
+        assert!(matches!(
+            server.resize(4, 10, vec![screen(0, 0, 4)]),
+            Err(Error::UnsupportedEncoding)
+        ));
+
+        // Clients only accepting DesktopSize get the size.
+        client
+            .set_encodings(vec![EncodingType::DesktopSize])
+            .unwrap();
+        server.read_message().unwrap();
+        server.resize(8, 10, vec![screen(0, 0, 8)]).unwrap();
+        for rectangle in client.read_update().unwrap().rectangles {
+            assert_eq!(rectangle.payload, Payload::DesktopSize);
+            local.apply(&rectangle).unwrap();
+        }
+        assert_eq!(
+            (client.framebuffer_width, client.framebuffer_height),
+            (8, 10)
+        );
+        assert_eq!((local.width(), local.height()), (8, 10));
+
+        client
+            .set_encodings(vec![EncodingType::ExtendedDesktopSize])
+            .unwrap();
+        server.read_message().unwrap();
+        let layout = vec![screen(1, 0, 6), screen(2, 6, 6)];
+        client.set_desktop_size(12, 10, layout.clone()).unwrap();
+        let ClientMessage::SetDesktopSize(request) = server.read_message().unwrap() else {
+            panic!("expected SetDesktopSize");
+        };
+        assert_eq!(
+            server.accept_desktop_size(&request).unwrap(),
+            DesktopSizeStatus::Success
+        );
+        let rectangle = client.read_update().unwrap().rectangles.remove(0);
+        assert_eq!((rectangle.x, rectangle.y), (1, 0));
+        assert_eq!(
+            (client.framebuffer_width, client.screens.clone()),
+            (12, layout)
+        );
+
+        // Rejected and invalid requests leave the size unchanged.
+        client
+            .set_desktop_size(20, 10, vec![screen(1, 0, 20)])
+            .unwrap();
+        let ClientMessage::SetDesktopSize(_) = server.read_message().unwrap() else {
+            panic!("expected SetDesktopSize");
+        };
+        server
+            .reject_desktop_size(DesktopSizeStatus::Prohibited)
+            .unwrap();
+        let rectangle = client.read_update().unwrap().rectangles.remove(0);
+        assert_eq!(
+            DesktopSizeStatus::from(rectangle.y),
+            DesktopSizeStatus::Prohibited
+        );
+        assert_eq!(rectangle.width, 12);
+        client
+            .set_desktop_size(20, 10, vec![screen(1, 10, 20)])
+            .unwrap();
+        let ClientMessage::SetDesktopSize(request) = server.read_message().unwrap() else {
+            panic!("expected SetDesktopSize");
+        };
+        assert_eq!(
+            server.accept_desktop_size(&request).unwrap(),
+            DesktopSizeStatus::InvalidLayout
+        );
+        let rectangle = client.read_update().unwrap().rectangles.remove(0);
+        assert_eq!(rectangle.y, 3);
+        assert_eq!(client.framebuffer_width, 12);
+        assert_eq!(server.framebuffer_width, 12);
+    }
+
+    #[test]
+    fn continuous_updates() {
+        let (mut client, mut server) = connect();
+        let mut local = Framebuffer::new(4, 4, client.pixel_format()).unwrap();
+        let mut remote = Framebuffer::new(4, 4, server.pixel_format()).unwrap();
+        assert!(matches!(
+            client.enable_continuous_updates(0, 0, 4, 4),
+            Err(Error::UnsupportedMessage(150))
+        ));
+
+        // Both extensions are announced, the fence is answered.
+        client
+            .set_encodings(vec![
+                EncodingType::Raw,
+                EncodingType::ContinuousUpdates,
+                EncodingType::Fence,
+            ])
+            .unwrap();
+        server.read_message().unwrap();
+        assert_eq!(server.in_flight(), 0);
+        assert!(matches!(
+            client.read_message().unwrap(),
+            ServerMessage::EndOfContinuousUpdates(_)
+        ));
+        assert!(client.continuous_updates);
+        let ServerMessage::ServerFence(fence) = client.read_message().unwrap() else {
+            panic!("expected ServerFence");
+        };
+        assert!(fence.flags.request && fence.flags.block_before);
+        let ClientMessage::ClientFence(answer) = server.read_message().unwrap() else {
+            panic!("expected ClientFence");
+        };
+        assert_eq!(answer, fence.response());
+        assert!(server.rtt.is_some());
+
+        // Updates come without requests, each acknowledged by a fence.
+        client.enable_continuous_updates(0, 0, 4, 4).unwrap();
+        server.read_message().unwrap();
+        assert!(client.streaming());
+        assert_eq!(server.continuous_updates().unwrap().width, 4);
+        server.max_in_flight = 100;
+        for value in [1, 2] {
+            assert!(server.send_changes(&mut remote, &[value; 64]).unwrap());
+        }
+        assert_eq!(server.in_flight(), 2 * (4 + 12 + 64));
+        assert!(server.congested());
+        // Changes wait for the client to catch up.
+        assert!(!server.send_changes(&mut remote, &[3; 64]).unwrap());
+        assert_eq!(remote.data(), [2; 64]);
+        assert_eq!(server.in_flight(), 2 * (4 + 12 + 64));
+        for _ in 0..2 {
+            for rectangle in client.read_update().unwrap().rectangles {
+                local.apply(&rectangle).unwrap();
+            }
+        }
+        assert_eq!(local.data(), [2; 64]);
+        client.disable_continuous_updates().unwrap();
+        for _ in 0..2 {
+            server.read_message().unwrap();
+        }
+        assert_eq!(server.in_flight(), 4 + 12 + 64);
+        assert!(!server.congested());
+        assert!(server.continuous_updates().is_none());
+        // The fence of the first update, answered while reading the second
+        // one, is returned before the fence of the second update is read.
+        for _ in 0..2 {
+            assert!(matches!(
+                client.read_message().unwrap(),
+                ServerMessage::ServerFence(_)
+            ));
+        }
+        assert!(client.streaming());
+        assert!(matches!(
+            client.read_message().unwrap(),
+            ServerMessage::EndOfContinuousUpdates(_)
+        ));
+        server.read_message().unwrap();
+        assert_eq!(server.in_flight(), 0);
+        assert!(!client.streaming());
+    }
+
+    #[test]
+    fn client_fences() {
+        let (mut client, mut server) = connect();
+        assert!(client.send_fence().is_err());
+        client
+            .set_encodings(vec![EncodingType::Raw, EncodingType::Fence])
+            .unwrap();
+        server.read_message().unwrap();
+        client.read_message().unwrap();
+        assert!(client.fence);
+        server.read_message().unwrap();
+
+        // The server answers right away, the client measures the round trip.
+        client.send_fence().unwrap();
+        assert_eq!(client.fences_in_flight(), 1);
+        let ClientMessage::ClientFence(fence) = server.read_message().unwrap() else {
+            panic!("expected ClientFence");
+        };
+        assert!(fence.flags.request);
+        let ServerMessage::ServerFence(answer) = client.read_message().unwrap() else {
+            panic!("expected ServerFence");
+        };
+        assert_eq!(answer, fence.response());
+        assert_eq!(client.fences_in_flight(), 0);
+        assert!(client.rtt.is_some());
+    }
+
+    #[test]
+    fn qemu_extended_key_events() {
+        let (mut client, mut server) = connect();
+        assert!(matches!(
+            client.extended_key_event(true, b'a' as u32, 0x1e),
+            Err(Error::UnsupportedMessage(255))
+        ));
+
+        client
+            .set_encodings(vec![EncodingType::QemuExtendedKeyEvent])
+            .unwrap();
+        server.read_message().unwrap();
+        let rectangles = client.read_update().unwrap().rectangles;
+        assert_eq!(rectangles[0].payload, Payload::QemuExtendedKeyEvent);
+        assert!(client.qemu_extended_key_events);
+        let event = QemuExtendedKeyEvent::us(false, Key::Left as u32).unwrap();
+        client
+            .extended_key_event(event.down, event.keysym, event.keycode)
+            .unwrap();
+        assert_eq!(
+            server.read_message().unwrap(),
+            ClientMessage::QemuExtendedKeyEvent(event)
+        );
+    }
+
+    #[test]
+    fn qemu_audio() {
+        let (mut client, mut server) = connect();
+        assert!(matches!(
+            client.enable_audio(),
+            Err(Error::UnsupportedMessage(255))
+        ));
+
+        client.set_encodings(vec![EncodingType::QemuAudio]).unwrap();
+        server.read_message().unwrap();
+        client.read_update().unwrap();
+        assert!(client.qemu_audio);
+        let format = AudioFormat {
+            sample_format: SampleFormat::U8,
+            channels: 1,
+            frequency: 22050,
+        };
+        client.set_audio_format(format).unwrap();
+        server.read_message().unwrap();
+        assert!(matches!(
+            server.begin_audio(),
+            Err(Error::UnsupportedMessage(255))
+        ));
+        client.enable_audio().unwrap();
+        server.read_message().unwrap();
+        assert_eq!(server.audio(), Some(&format));
+
+        server.begin_audio().unwrap();
+        server.send_audio(Samples::U8(vec![128, 255, 0])).unwrap();
+        assert!(matches!(
+            server.send_audio(Samples::S16(vec![0])),
+            Err(Error::UnsupportedEncoding)
+        ));
+        server.end_audio().unwrap();
+        let messages: Vec<_> = (0..3).map(|_| client.read_message().unwrap()).collect();
+        assert_eq!(
+            messages,
+            [
+                ServerMessage::QemuAudio(QemuAudioServerMessage::Begin),
+                ServerMessage::QemuAudio(QemuAudioServerMessage::Data(AudioBuffer {
+                    format,
+                    samples: Samples::U8(vec![128, 255, 0]),
+                })),
+                ServerMessage::QemuAudio(QemuAudioServerMessage::End),
+            ]
+        );
+        client.disable_audio().unwrap();
+        server.read_message().unwrap();
+        assert_eq!(server.audio(), None);
+    }
+
+    #[test]
+    fn clipboard() {
+        let (mut client, mut server) = connect();
+        let event = |cut_text: &CutText| cut_text.event();
+
+        // Without Extended Clipboard, text goes as Latin-1.
+        server.set_clipboard(Clipboard::text("née ✓")).unwrap();
+        let ServerMessage::ServerCutText(cut_text) = client.read_message().unwrap() else {
+            panic!("expected ServerCutText");
+        };
+        assert_eq!(
+            event(&cut_text),
+            Some(ClipboardEvent::Content(Clipboard::text("née ?")))
+        );
+
+        // Caps are exchanged.
+        client
+            .set_encodings(vec![EncodingType::Raw, EncodingType::ExtendedClipboard])
+            .unwrap();
+        server.read_message().unwrap();
+        client.read_message().unwrap();
+        server.read_message().unwrap();
+
+        // Cut text sent before an update is kept while reading the update.
+        server.set_clipboard(Clipboard::text("first")).unwrap();
+        server
+            .send_update(FramebufferUpdate {
+                rectangles: Vec::new(),
+            })
+            .unwrap();
+        assert!(client.read_update().unwrap().rectangles.is_empty());
+        let ServerMessage::ServerCutText(cut_text) = client.read_message().unwrap() else {
+            panic!("expected ServerCutText");
+        };
+        assert!(matches!(event(&cut_text), Some(ClipboardEvent::Changed(_))));
+
+        // Changes are notified, the content requested in the formats wanted.
+        let clipboard = Clipboard {
+            text: Some("☕\nready".to_string()),
+            html: Some("<b>☕</b>".to_string()),
+            rtf: None,
+        };
+        server.set_clipboard(clipboard.clone()).unwrap();
+        let ServerMessage::ServerCutText(cut_text) = client.read_message().unwrap() else {
+            panic!("expected ServerCutText");
+        };
+        assert_eq!(
+            event(&cut_text),
+            Some(ClipboardEvent::Changed(clipboard.formats()))
+        );
+        client.request_clipboard().unwrap();
+        server.read_message().unwrap();
+        let ServerMessage::ServerCutText(cut_text) = client.read_message().unwrap() else {
+            panic!("expected ServerCutText");
+        };
+        assert_eq!(event(&cut_text), Some(ClipboardEvent::Content(clipboard)));
+
+        client.set_clipboard(Clipboard::text("ok")).unwrap();
+        let ClientMessage::ClientCutText(cut_text) = server.read_message().unwrap() else {
+            panic!("expected ClientCutText");
+        };
+        assert!(matches!(event(&cut_text), Some(ClipboardEvent::Changed(_))));
+        server.request_clipboard().unwrap();
+        client.read_message().unwrap();
+        let ClientMessage::ClientCutText(cut_text) = server.read_message().unwrap() else {
+            panic!("expected ClientCutText");
+        };
+        assert_eq!(
+            event(&cut_text),
+            Some(ClipboardEvent::Content(Clipboard::text("ok")))
+        );
+    }
+
+    #[test]
+    fn exclusive_client() {
+        let sessions = Sessions::new();
+        let (mut first, mut first_server) = connect_among(&sessions);
+        let (mut second, mut second_server) = connect_among(&sessions);
+        assert!(!second_server.shared);
+        assert_eq!(sessions.len(), 1);
+
+        assert!(first_server.disconnected());
+        first.request_update(false, 0, 0, 1, 1).unwrap();
+        assert!(matches!(
+            first_server.read_update_request(),
+            Err(Error::ConnectionFailed(_))
+        ));
+        assert!(first_server.bell().is_err());
+        drop(first_server);
+        assert_eq!(sessions.len(), 1);
+
+        second.request_update(false, 0, 0, 1, 1).unwrap();
+        assert!(!second_server.read_update_request().unwrap().incremental);
+        drop(second_server);
+        assert!(sessions.is_empty());
+    }
+
+    #[test]
+    fn refused_exclusive_client() {
+        let sessions = Sessions::new().refusing_exclusive();
+        let (mut first, mut first_server) = connect_among(&sessions);
+        let (client, handle) = accept_among(
+            Version::Rfb38,
+            vec![Box::new(NoAuthentication)],
+            sessions.clone(),
+        );
+        let authenticators: Vec<Box<dyn ClientAuthenticator>> = vec![Box::new(NoAuthentication)];
+        assert!(Client::handshake(client, authenticators).is_err());
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(Error::ConnectionFailed(_))
+        ));
+        assert_eq!(sessions.len(), 1);
+
+        assert!(!first_server.disconnected());
+        first.request_update(false, 0, 0, 1, 1).unwrap();
+        assert!(!first_server.read_update_request().unwrap().incremental);
+    }
+
+    #[test]
+    fn shared_client() {
+        let sessions = Sessions::new();
+        let (mut first, mut first_server) = connect_among(&sessions);
+        let (mut client, handle) = accept_among(
+            Version::Rfb38,
+            vec![Box::new(NoAuthentication)],
+            sessions.clone(),
+        );
+        client.write_all(b"RFB 003.003\n\x01").unwrap();
+        let server = handle.join().unwrap().unwrap();
+        assert!(server.shared);
+        assert_eq!(sessions.len(), 2);
+
+        assert!(!first_server.disconnected());
+        first.request_update(false, 0, 0, 1, 1).unwrap();
+        assert!(!first_server.read_update_request().unwrap().incremental);
+
+        let (_third, _third_server) = connect_among(&sessions);
+        assert!(first_server.disconnected() && server.disconnected());
+        assert_eq!(sessions.len(), 1);
+    }
+
+    #[test]
+    fn older_client() {
+        let (mut client, handle) = accept(Version::Rfb38, vec![Box::new(NoAuthentication)]);
+        let mut version = [0; 12];
+        client.read_exact(&mut version).unwrap();
+        assert_eq!(&version, b"RFB 003.008\n");
+        client.write_all(b"RFB 003.003\n").unwrap();
+        let mut security_type = [0; 4];
+        client.read_exact(&mut security_type).unwrap();
+        assert_eq!(security_type, [0, 0, 0, 1]);
+        client.write_all(&[1]).unwrap();
+        let server = handle.join().unwrap().unwrap();
+        assert_eq!(server.version, Version::Rfb33);
+        assert!(server.shared);
+    }
+
+    #[test]
+    fn failure_reasons() {
+        let (mut client, handle) = accept(Version::Rfb38, vec![]);
+        client.write_all(b"RFB 003.008\n").unwrap();
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(Error::ConnectionFailed(_))
+        ));
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).unwrap();
+        assert_eq!(&sent[12..], b"\0\0\0\0\x1ano security type available");
+
+        let (mut client, handle) = accept(
+            Version::Rfb38,
+            vec![Box::new(VncAuthVerifier::new("secret"))],
+        );
+        client.write_all(b"RFB 003.008\n\x01").unwrap();
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(Error::IncompatibleSecurity)
+        ));
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).unwrap();
+        assert_eq!(&sent[12..18], [1, 2, 0, 0, 0, 1]);
+        assert_eq!(&sent[22..], b"unsupported security type");
     }
 }